@@ -0,0 +1,30 @@
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use vap_client_framework::{Client, PlainCapability};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let (mut client, mut notifications) =
+        Client::new("Test client", "com.example.test_client").unwrap();
+    println!("Locales in use: {:?}", client.locales());
+
+    let mut cap_data = HashMap::new();
+    cap_data.insert("text".into(), "What time is it?".into());
+
+    client.start_session(vec![]).unwrap();
+    let answer = client
+        .finish_session(vec![PlainCapability {
+            name: "text".into(),
+            version: 1,
+            cap_data,
+        }])
+        .unwrap();
+    println!("Assistant answer: {:?}", answer);
+
+    while let Some(notification) = notifications.next().await {
+        for cap in notification.capabilities {
+            println!("{} sent us: {} {:?}", cap.from, cap.name, cap.cap_data);
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::mpsc as std_mpsc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use coap::{CoAPClient, Server};
+use coap_lite::{
+    CoapOption, CoapRequest, CoapResponse, MessageClass, RequestType as Method, ResponseType,
+};
+use futures::{channel::mpsc, SinkExt};
+use log::warn;
+use serde::Serialize;
+use thiserror::Error;
+use unic_langid::LanguageIdentifier;
+use vap_common_client::auth::{default_token_path, load_token, save_token};
+use vap_common_client::structures::*;
+
+pub use vap_common_client::capability::CapabilityRange;
+pub use vap_common_client::structures::{
+    msg_client_notification, MsgClientNotification, MsgSessionDataResponse, PlainCapability,
+};
+
+/// VAP version implemented by this crate
+const VAP_VERSION: &str = "Alpha";
+
+/// Port in which the client registry listens by default (5684 is CoAP over
+/// DTLS's)
+const REGISTRY_PORT: u16 = 5690;
+
+/// Multicast address for "All CoAP nodes" in IPv4
+const ALL_COAP_IPV4: &str = "224.0.1.187";
+
+/// How much to wait for a registry to answer to service discovery
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The client itself, use this to communicate with the registry.
+pub struct Client {
+    client: CoAPClient,
+    id: String,
+    locales: Vec<LanguageIdentifier>,
+    /// Given by the registry, it goes in every message
+    token: Option<String>,
+    _server_thrd: thread::JoinHandle<()>,
+}
+
+/// Creates a client with some extra options, get one with [`Client::builder`].
+pub struct ClientBuilder {
+    name: String,
+    id: String,
+    address: Option<String>,
+    token_file: Option<PathBuf>,
+    capabilities: Vec<CapabilityRange>,
+}
+
+impl ClientBuilder {
+    /// Connect to the registry at this address (e.g: 192.168.1.2:5690)
+    /// instead of searching for it.
+    pub fn address<S: Into<String>>(mut self, address: S) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Keep the token given by the registry in this file instead of
+    /// `$HOME/.local/share/vap/{id}.token`.
+    pub fn token_file<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.token_file = Some(path.into());
+        self
+    }
+
+    /// The client can handle versions `min_version` to `max_version` of the
+    /// capability `name`, see [`Client::with_capabilities`].
+    pub fn capability<S: Into<String>>(mut self, name: S, min_version: u16, max_version: u16) -> Self {
+        self.capabilities.push(CapabilityRange::new(name, min_version, max_version));
+        self
+    }
+
+    /// Connects to the client registry, see [`Client::new`] for more info.
+    pub fn build(self) -> Result<(Client, ClientIn)> {
+        let address = self.address.unwrap_or_else(Client::get_address);
+        let token_file = self.token_file.or_else(|| default_token_path(&self.id));
+        Client::connect(self.name, self.id, address, token_file, self.capabilities)
+    }
+}
+
+impl Client {
+    /// Search for the client registry using CoAP's service discovery, if
+    /// nobody answers the registry is assumed to be on this same device.
+    fn get_address() -> String {
+        fn discover() -> std::io::Result<Option<SocketAddr>> {
+            let client = CoAPClient::new((ALL_COAP_IPV4, REGISTRY_PORT))?;
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path(".well-known/core");
+            request
+                .message
+                .add_option(CoapOption::UriQuery, b"rt=vap-client-registry".to_vec());
+
+            client.send_all_coap(&request, 0)?;
+            client.set_receive_timeout(Some(DISCOVERY_TIMEOUT))?;
+            let (resp, address) = client.receive_from()?;
+            let links = String::from_utf8_lossy(&resp.message.payload);
+
+            Ok(links.contains("vap-client-registry").then_some(address))
+        }
+
+        match discover() {
+            Ok(Some(address)) => address.to_string(),
+            _ => format!("127.0.0.1:{}", REGISTRY_PORT),
+        }
+    }
+
+    /// Creates a new client, will also connect to the client registry. Returns
+    /// both itself and a channel that you will use to receive notifications.
+    /// This follows RAII and as as soon as it is dropped will disconnect from
+    /// the client registry. The token given by the registry is kept in
+    /// `$HOME/.local/share/vap/{id}.token`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A human-readable name for this client
+    /// * `id` -  This client id like 'com.my_company.my_client'
+    ///
+    pub fn new<S1, S2>(name: S1, id: S2) -> Result<(Self, ClientIn)>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        Self::with_capabilities(name, id, vec![])
+    }
+
+    /// Get a builder to create a client with extra options (e.g: a fixed
+    /// registry address).
+    pub fn builder<S1, S2>(name: S1, id: S2) -> ClientBuilder
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        ClientBuilder {
+            name: name.into(),
+            id: id.into(),
+            address: None,
+            token_file: None,
+            capabilities: vec![],
+        }
+    }
+
+    /// Same as [`Client::new`] but declares which versions of each capability
+    /// the client can handle, capabilities that aren't declared are not sent
+    /// to it. Without any declaration everything is sent.
+    pub fn with_capabilities<S1, S2>(name: S1, id: S2, capabilities: Vec<CapabilityRange>) -> Result<(Self, ClientIn)>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let mut builder = Self::builder(name, id);
+        builder.capabilities = capabilities;
+        builder.build()
+    }
+
+    fn connect(
+        name: String,
+        id_str: String,
+        address: String,
+        token_file: Option<PathBuf>,
+        capabilities: Vec<CapabilityRange>,
+    ) -> Result<(Self, ClientIn)> {
+        let (sender, receiver) = mpsc::channel(10);
+        let (port, _server_thrd) = start_notification_server(sender)?;
+
+        let token = token_file.as_ref().and_then(load_token);
+        let payload = rmp_serde::to_vec_named(&MsgClientConnect {
+            id: id_str.clone(),
+            name,
+            vap_version: VAP_VERSION.into(),
+            port: Some(port),
+            capabilities,
+            auth_token: token.clone(),
+        })
+        .expect("Failed to make initial payload, report this");
+        let mut client = CoAPClient::new(address)?;
+
+        let resp = client.request_path(
+            "vap/clientRegistry/connect",
+            Method::Post,
+            Some(payload),
+            None,
+        )?;
+
+        match extract_type(resp.message.header.code) {
+            ResponseType::Created => {
+                let payload: MsgClientConnectResponse =
+                    rmp_serde::from_read(Cursor::new(resp.message.payload))
+                        .map_err(|_| Error::BadResponse)?;
+
+                // We only receive a token the first time we connect
+                let token = match (payload.auth_token, token_file) {
+                    (Some(new_token), Some(path)) => {
+                        if let Err(e) = save_token(&path, &new_token) {
+                            warn!("Couldn't save our token, we won't be able to connect again: {}", e);
+                        }
+                        Some(new_token)
+                    }
+                    (new_token, _) => new_token.or(token),
+                };
+
+                Ok((
+                    Self {
+                        client,
+                        id: id_str,
+                        locales: payload.locales.into_iter().map(|l| l.into()).collect(),
+                        token,
+                        _server_thrd,
+                    },
+                    receiver,
+                ))
+            }
+            ResponseType::BadRequest => Err(Error::BadRequest),
+            ResponseType::Unauthorized => Err(Error::ConnectionDenied),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// The locales currently in use by the voice assistant
+    pub fn locales(&self) -> &[LanguageIdentifier] {
+        &self.locales
+    }
+
+    fn send_message<T: Serialize>(
+        &mut self,
+        method: Method,
+        path: &str,
+        data: T,
+    ) -> Result<(ResponseType, Vec<u8>)> {
+        let d = rmp_serde::to_vec_named(&data).expect("Failed to encode message, report this");
+        let resp = self.client.request_path(path, method, Some(d), None)?;
+
+        Ok((
+            extract_type(resp.message.header.code),
+            resp.message.payload,
+        ))
+    }
+
+    /// Signal the start of a session (e.g: the wakeword was heard). The
+    /// capabilities are meant for user authorization and wakeword double
+    /// checking, they can be empty.
+    pub fn start_session(&mut self, capabilities: Vec<PlainCapability>) -> Result<()> {
+        let exact_time_stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_millis() as u64);
+
+        match self.send_message(
+            Method::Post,
+            "vap/clientRegistry/sessionStart",
+            MsgSessionStart {
+                client_id: self.id.clone(),
+                capabilities,
+                exact_time_stamp,
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Created, _) => Ok(()),
+            (ResponseType::BadRequest, _) => Err(Error::BadRequest),
+            _ => Err(Error::SessionRejected),
+        }
+    }
+
+    /// Send a fragment of data (e.g: some audio) for the current session,
+    /// more data is expected to follow.
+    pub fn send_session_data(&mut self, capabilities: Vec<PlainCapability>) -> Result<()> {
+        match self.send_message(
+            Method::Post,
+            "vap/clientRegistry/sessionData",
+            MsgSessionData {
+                client_id: self.id.clone(),
+                capabilities,
+                last_fragment: false,
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Continue, _) => Ok(()),
+            (ResponseType::BadRequest, _) => Err(Error::BadRequest),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Send the last fragment of data for the current session and wait for
+    /// the assistant's answer. Note: It may take a while.
+    pub fn finish_session(
+        &mut self,
+        capabilities: Vec<PlainCapability>,
+    ) -> Result<MsgSessionDataResponse> {
+        match self.send_message(
+            Method::Post,
+            "vap/clientRegistry/sessionData",
+            MsgSessionData {
+                client_id: self.id.clone(),
+                capabilities,
+                last_fragment: true,
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Created, d) => {
+                rmp_serde::from_read(Cursor::new(d)).map_err(|_| Error::BadResponse)
+            }
+            (ResponseType::BadRequest, _) => Err(Error::BadRequest),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.send_message(
+            Method::Post,
+            "vap/clientRegistry/clientClose",
+            MsgClientClose {
+                client_id: self.id.clone(),
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Deleted, _) => Ok(()),
+            _ => Err(Error::Unknown),
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            warn!("Failed to close the connection with the registry: {}", e);
+        }
+    }
+}
+
+/// Starts a CoAP server in its own thread that will receive the notifications
+/// from the registry, returns the port in which it listens.
+fn start_notification_server(
+    sender: mpsc::Sender<MsgClientNotification>,
+) -> Result<(u16, thread::JoinHandle<()>)> {
+    async fn on_request(
+        request: CoapRequest<SocketAddr>,
+        mut sender: mpsc::Sender<MsgClientNotification>,
+    ) -> Option<CoapResponse> {
+        let status = match (*request.get_method(), request.get_path().as_str()) {
+            (Method::Post, "vap/notification") => {
+                match rmp_serde::from_read(Cursor::new(&request.message.payload)) {
+                    Ok(msg) => {
+                        if sender.send(msg).await.is_ok() {
+                            ResponseType::Changed
+                        } else {
+                            ResponseType::ServiceUnavailable
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Received a bad msgpack message, will be ignored: {}", e);
+                        ResponseType::BadRequest
+                    }
+                }
+            }
+            (_, "vap/notification") => ResponseType::MethodNotAllowed,
+            _ => ResponseType::NotFound,
+        };
+
+        request.response.map(|mut r| {
+            r.set_status(status);
+            r
+        })
+    }
+
+    let (port_send, port_recv) = std_mpsc::channel();
+    let handle = thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                let _ = port_send.send(Err(e));
+                return;
+            }
+        };
+
+        rt.block_on(async move {
+            let mut server = match Server::new("0.0.0.0:0") {
+                Ok(server) => server,
+                Err(e) => {
+                    let _ = port_send.send(Err(e));
+                    return;
+                }
+            };
+
+            let _ = port_send.send(server.socket_addr().map(|a| a.port()));
+            if let Err(e) = server
+                .run(|request| on_request(request, sender.clone()))
+                .await
+            {
+                warn!("The notification server stopped: {}", e);
+            }
+        });
+    });
+
+    let port = port_recv.recv().map_err(|_| Error::Unknown)??;
+    Ok((port, handle))
+}
+
+fn extract_type(code: MessageClass) -> ResponseType {
+    if let MessageClass::Response(c) = code {
+        c
+    } else {
+        ResponseType::UnKnown
+    }
+}
+
+type ClientIn = mpsc::Receiver<MsgClientNotification>;
+
+type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("IO")]
+    IO(#[from] std::io::Error),
+
+    #[error("The data sent had a wrong format or didn't meet the VAP rules")]
+    BadRequest,
+
+    #[error("The registry answered with data we couldn't understand")]
+    BadResponse,
+
+    #[error("The connection was denied by the registry")]
+    ConnectionDenied,
+
+    #[error("The registry did not accept the session")]
+    SessionRejected,
+
+    #[error("We got an error, but we don't know why")]
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+
+    use coap_lite::Packet;
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+
+    type Received = Vec<(String, HashMap<String, Value>)>;
+
+    /// A client registry answering with `answer` until the client closes,
+    /// returns its address and everything it received.
+    fn fake_registry(answer: fn(&str, &[u8]) -> (ResponseType, Vec<u8>)) -> (String, thread::JoinHandle<Received>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        let handle = thread::spawn(move || {
+            let mut received = vec![];
+            let mut buf = [0; 1500];
+            while let Ok((len, source)) = socket.recv_from(&mut buf) {
+                let packet = Packet::from_bytes(&buf[..len]).unwrap();
+                let request = CoapRequest::from_packet(packet, source);
+                let path = request.get_path();
+                let fields = rmp_serde::from_slice(&request.message.payload).unwrap();
+                received.push((path.clone(), fields));
+
+                let (status, payload) = answer(&path, &request.message.payload);
+                let mut response = request.response.unwrap();
+                response.set_status(status);
+                response.message.payload = payload;
+                socket.send_to(&response.message.to_bytes().unwrap(), source).unwrap();
+                if path == "vap/clientRegistry/clientClose" {
+                    break;
+                }
+            }
+            received
+        });
+
+        (address, handle)
+    }
+
+    fn registry(path: &str, payload: &[u8]) -> (ResponseType, Vec<u8>) {
+        match path {
+            "vap/clientRegistry/connect" => {
+                let msg = MsgClientConnectResponse {
+                    locales: vec!["en-US".parse::<LanguageIdentifier>().unwrap().into()],
+                    auth_token: Some("0123456789abcdef".into()),
+                };
+                (ResponseType::Created, rmp_serde::to_vec_named(&msg).unwrap())
+            }
+            "vap/clientRegistry/sessionStart" => (ResponseType::Created, vec![]),
+            "vap/clientRegistry/sessionData" => {
+                let msg: MsgSessionData = rmp_serde::from_slice(payload).unwrap();
+                if msg.last_fragment {
+                    let answer = MsgSessionDataResponse { capabilities: vec![], end_session: true };
+                    (ResponseType::Created, rmp_serde::to_vec_named(&answer).unwrap())
+                } else {
+                    (ResponseType::Continue, vec![])
+                }
+            }
+            "vap/clientRegistry/clientClose" => (ResponseType::Deleted, vec![]),
+            _ => (ResponseType::NotFound, vec![]),
+        }
+    }
+
+    fn token_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vap-client-{}-{}.token", name, std::process::id()))
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let (address, registry) = fake_registry(registry);
+        let path = token_file("session");
+        let (mut client, _notifications) = Client::builder("Test", "com.example.test")
+            .address(address)
+            .token_file(&path)
+            .capability("text", 1, 2)
+            .build()
+            .unwrap();
+
+        assert_eq!(client.locales(), &["en-US".parse::<LanguageIdentifier>().unwrap()]);
+        assert_eq!(load_token(&path).as_deref(), Some("0123456789abcdef"));
+
+        client.start_session(vec![]).unwrap();
+        client.send_session_data(vec![]).unwrap();
+        assert!(client.finish_session(vec![]).unwrap().end_session);
+        drop(client);
+
+        let received = registry.join().unwrap();
+        let paths: Vec<_> = received.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "vap/clientRegistry/connect",
+                "vap/clientRegistry/sessionStart",
+                "vap/clientRegistry/sessionData",
+                "vap/clientRegistry/sessionData",
+                "vap/clientRegistry/clientClose",
+            ]
+        );
+
+        let connect = &received[0].1;
+        assert!(connect.contains_key("port"));
+        assert!(connect.contains_key("capabilities"));
+        assert!(!connect.contains_key("uniqueAuthenticationToken"));
+        for (_, fields) in &received[1..] {
+            assert_eq!(fields.get("uniqueAuthenticationToken"), Some(&"0123456789abcdef".into()));
+        }
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn denied_connection() {
+        let (address, registry) = fake_registry(|_, _| (ResponseType::Unauthorized, vec![]));
+        let path = token_file("denied");
+        let result = Client::builder("Test", "com.example.test")
+            .address(address)
+            .token_file(&path)
+            .build();
+
+        assert!(matches!(result, Err(Error::ConnectionDenied)));
+        drop(registry);
+    }
+
+    #[tokio::test]
+    async fn notifications_reach_the_channel() {
+        let (sender, mut receiver) = mpsc::channel(10);
+        let (port, _server) = start_notification_server(sender).unwrap();
+        let mut client = CoAPClient::new(("127.0.0.1", port)).unwrap();
+        let mut request = |method, path: &str, payload: Vec<u8>| {
+            let resp = client.request_path(path, method, Some(payload), None).unwrap();
+            extract_type(resp.message.header.code)
+        };
+
+        let mut cap_data = HashMap::new();
+        cap_data.insert("text".into(), "Hello!".into());
+        let msg = MsgClientNotification {
+            capabilities: vec![msg_client_notification::Capability {
+                name: "text".into(),
+                from: "com.example.skill".into(),
+                version: 1,
+                cap_data,
+            }],
+        };
+        let payload = rmp_serde::to_vec_named(&msg).unwrap();
+
+        assert_eq!(request(Method::Post, "vap/notification", payload.clone()), ResponseType::Changed);
+        assert_eq!(request(Method::Post, "vap/notification", vec![0x82]), ResponseType::BadRequest);
+        assert_eq!(request(Method::Get, "vap/notification", vec![]), ResponseType::MethodNotAllowed);
+        assert_eq!(request(Method::Post, "vap/other", payload), ResponseType::NotFound);
+
+        let received = receiver.next().now_or_never().flatten().unwrap();
+        assert_eq!(received.capabilities[0].from, "com.example.skill");
+        assert!(receiver.next().now_or_never().is_none());
+    }
+}
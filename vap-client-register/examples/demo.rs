@@ -1,3 +1,83 @@
-fn main() {
-    println!("Hello, world!");
+use vap_client_register::{
+    structures::{Language, MsgClientConnectResponse, MsgSessionDataResponse},
+    ClientRegister, ClientRegisterMessage, ClientRegisterStream, Response, ResponseType, DEFAULT_PORT,
+};
+
+async fn on_msg(mut stream: ClientRegisterStream) -> Result<(), vap_client_register::Error> {
+    loop {
+        let (msg, responder) = stream.recv().await?;
+        let resp = match msg {
+            ClientRegisterMessage::Connect(m) => {
+                println!("{} wants to connect", m.id);
+                let data = rmp_serde::to_vec_named(&MsgClientConnectResponse {
+                    locales: vec![Language {
+                        language: "en".to_string(),
+                        country: Some("US".to_string()),
+                        extra: None,
+                    }],
+                    // The register adds the token
+                    auth_token: None,
+                })
+                .unwrap();
+
+                Response {
+                    status: ResponseType::Created,
+                    payload: data,
+                }
+            }
+            ClientRegisterMessage::SessionStart(m) => {
+                println!("{} wants to start a session", m.client_id);
+                Response {
+                    status: ResponseType::Created,
+                    payload: vec![],
+                }
+            }
+            ClientRegisterMessage::SessionData(m) => {
+                println!(
+                    "{} sent {} capabilities",
+                    m.client_id,
+                    m.capabilities.len()
+                );
+
+                if m.last_fragment {
+                    let data = rmp_serde::to_vec_named(&MsgSessionDataResponse {
+                        capabilities: vec![],
+                        end_session: true,
+                    })
+                    .unwrap();
+
+                    Response {
+                        status: ResponseType::Created,
+                        payload: data,
+                    }
+                } else {
+                    Response {
+                        status: ResponseType::Continue,
+                        payload: vec![],
+                    }
+                }
+            }
+            ClientRegisterMessage::Close(m) => {
+                println!("{} wants to close", m.client_id);
+                Response {
+                    status: ResponseType::Deleted,
+                    payload: vec![],
+                }
+            }
+        };
+
+        responder
+            .send(resp)
+            .map_err(|_| vap_client_register::Error::ClosedChannel)?;
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let (reg, stream, _out) = ClientRegister::new(DEFAULT_PORT).unwrap();
+
+    tokio::select!(
+        _= tokio::spawn(reg.run()) => {}
+        _= on_msg(stream) => {}
+    );
 }
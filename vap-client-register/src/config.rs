@@ -0,0 +1,81 @@
+// How the client register should be set up
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::{ClientRegister, ClientRegisterOut, ClientRegisterStream, Error};
+
+/// The port in which VAP client registers listen by default, it can't be the
+/// same as the skill register's (5683) and 5684 is taken by CoAP over DTLS.
+pub const DEFAULT_PORT: u16 = 5690;
+
+/// Everything that can be configured about a client register. Every method
+/// consumes the configuration and returns it, so that they can be chained.
+/// # Examples
+/// ```ignore
+/// let (register, stream, out) = ClientRegisterConfig::new()
+///     .address("0.0.0.0".parse().unwrap())
+///     .build()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientRegisterConfig {
+    address: IpAddr,
+    port: u16,
+    pub(crate) stream_capacity: usize,
+    pub(crate) token_file: Option<PathBuf>,
+}
+
+impl Default for ClientRegisterConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            stream_capacity: 20,
+            token_file: None,
+        }
+    }
+}
+
+impl ClientRegisterConfig {
+    /// A configuration listening on `127.0.0.1` and the default port
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The address to listen on, use `0.0.0.0` or `::` to accept clients
+    /// from other devices (speakers, phones...).
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// The port to listen on.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// How many messages from clients can be waiting on the stream before
+    /// the register stops accepting more.
+    pub fn stream_capacity(mut self, capacity: usize) -> Self {
+        self.stream_capacity = capacity;
+        self
+    }
+
+    /// Where to keep the ids of the clients that ever connected along with
+    /// their tokens, `None` forgets them when the register is dropped (and
+    /// clients will receive new tokens next time).
+    pub fn token_file(mut self, path: Option<PathBuf>) -> Self {
+        self.token_file = path;
+        self
+    }
+
+    pub(crate) fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// Creates the client register, see [`ClientRegister::new`] for more info.
+    pub fn build(self) -> Result<(ClientRegister, ClientRegisterStream, ClientRegisterOut), Error> {
+        ClientRegister::with_config(self)
+    }
+}
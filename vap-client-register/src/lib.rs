@@ -1,3 +1,313 @@
-fn main() {
-    println!("Hello, world!");
+//! The reference implementation of the VAP client register.
+
+mod config;
+mod method_handlers;
+mod vars;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
+
+use coap::{CoAPClient, Server};
+use coap_lite::{CoapRequest, CoapResponse, MessageClass, RequestType as Method};
+use futures::{
+    channel::{mpsc, oneshot},
+    StreamExt,
+};
+use thiserror::Error;
+use vap_common_client::auth::TokenStore;
+use vap_common_client::capability::{CapabilityError, CapabilityRange, Converters};
+use vap_common_client::structures::{msg_client_notification, MsgClientNotification, PlainCapability};
+use vap_skill_register::{Notification, NotificationResponse};
+
+pub use coap_lite::ResponseType;
+pub use config::{ClientRegisterConfig, DEFAULT_PORT};
+pub use vap_common_client::structures;
+pub use vars::{SYSTEM_SELF_ID, VAP_VERSION};
+
+type SharedClients = Arc<SyncMutex<HashMap<String, ClientInfo>>>;
+
+/// Every client that ever connected and its token
+type SharedTokens = Arc<SyncMutex<TokenStore>>;
+
+/// How much to wait for a client to acknowledge a notification
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("A Oneshot channel was closed")]
+    ClosedChannel,
+
+    #[error("IO")]
+    IO(#[from] std::io::Error),
+
+    #[error("A background task could not finish")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+pub struct Response {
+    pub status: ResponseType,
+    pub payload: Vec<u8>,
+}
+
+/// What we know about a connected client
+struct ClientInfo {
+    /// Where the client receives notifications. Note: Addresses might change,
+    /// the client will update them by connecting again.
+    address: SocketAddr,
+    /// Versions of the capabilities the client can handle, empty if it
+    /// didn't say
+    capabilities: Vec<CapabilityRange>,
+}
+
+/// Will handle incoming and outgoing messages to and from the clients, also
+/// keeps account of the clients connected to the system.
+pub struct ClientRegister {
+    address: SocketAddr,
+    in_send: mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+    current_clients: SharedClients,
+    tokens: SharedTokens,
+}
+
+/// A message received from a client
+pub enum ClientRegisterMessage {
+    Connect(structures::MsgClientConnect),
+    SessionStart(structures::MsgSessionStart),
+    SessionData(structures::MsgSessionData),
+    Close(structures::MsgClientClose),
+}
+
+fn respond(resp: Option<CoapResponse>, st: ResponseType, pl: Vec<u8>) -> Option<CoapResponse> {
+    resp.map(|mut c| {
+        c.set_status(st);
+        c.message.payload = pl;
+        c
+    })
+}
+
+/// Whether a response from the application is regarded as "OK"
+fn is_ok(status: ResponseType) -> bool {
+    [
+        ResponseType::Created,
+        ResponseType::Deleted,
+        ResponseType::Valid,
+        ResponseType::Changed,
+        ResponseType::Content,
+        ResponseType::Continue,
+    ]
+    .contains(&status)
+}
+
+/// Transforms a response type into its numeric CoAP code (e.g: 4.04 -> 404)
+fn coap_code(status: ResponseType) -> u16 {
+    let raw = u8::from(MessageClass::Response(status));
+    u16::from(raw >> 5) * 100 + u16::from(raw & 0x1F)
+}
+
+impl ClientRegister {
+    /// Creates a new client register, the client register is divided into three parts:
+    /// 1. The client register task, which will handle everything behind the scenes, you just need to await on run().
+    /// 2. The client stream, which will receive all the messages from the clients.
+    /// 3. The client out, which you can use to send notifications to the clients.
+    /// # Arguments
+    /// * `port` - The port for the client register to listen CoAP messages on.
+    ///
+    /// It will only listen on `127.0.0.1`, use [`ClientRegisterConfig`] for
+    /// anything else. Tokens given to clients are forgotten when dropped, see
+    /// [`ClientRegister::with_token_file`] to keep them.
+    pub fn new(port: u16) -> Result<(Self, ClientRegisterStream, ClientRegisterOut), Error> {
+        ClientRegisterConfig::new().port(port).build()
+    }
+
+    /// Same as [`ClientRegister::new`] but the ids of the clients that ever
+    /// connected and their tokens are kept in `path`.
+    pub fn with_token_file(port: u16, path: PathBuf) -> Result<(Self, ClientRegisterStream, ClientRegisterOut), Error> {
+        ClientRegisterConfig::new().port(port).token_file(Some(path)).build()
+    }
+
+    /// Same as [`ClientRegister::new`] but with a custom configuration.
+    pub fn with_config(config: ClientRegisterConfig) -> Result<(Self, ClientRegisterStream, ClientRegisterOut), Error> {
+        let (in_send, in_recv) = mpsc::channel(config.stream_capacity);
+        let current_clients = Arc::new(SyncMutex::new(HashMap::new()));
+        let tokens = match &config.token_file {
+            Some(path) => TokenStore::open(path)?,
+            None => TokenStore::in_memory(),
+        };
+
+        Ok((
+            ClientRegister {
+                address: config.socket_addr(),
+                in_send,
+                current_clients: current_clients.clone(),
+                tokens: Arc::new(SyncMutex::new(tokens)),
+            },
+            ClientRegisterStream { stream_in: in_recv },
+            ClientRegisterOut {
+                current_clients,
+                converters: Converters::new(),
+            },
+        ))
+    }
+
+    /// Call this function and await it for the rest of the program, this handles
+    /// sending and receiving messages from the clients. Stopping this means no more
+    /// communication, and even dropped channels.
+    pub async fn run(self) -> Result<(), Error> {
+        async fn perform(
+            request: CoapRequest<SocketAddr>,
+            mut in_send: mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+            current_clients: &SharedClients,
+            tokens: &SharedTokens,
+        ) -> Option<CoapResponse> {
+            match *request.get_method() {
+                Method::Get => method_handlers::on_get(request).await,
+                Method::Post => {
+                    method_handlers::on_post(request, &mut in_send, current_clients, tokens).await
+                }
+                _ => {
+                    println!("request by other method");
+                    respond(request.response, ResponseType::MethodNotAllowed, vec![])
+                }
+            }
+        }
+
+        let mut server = Server::new(self.address)?;
+        server.enable_all_coap(0);
+        server
+            .run(|request| perform(request, self.in_send.clone(), &self.current_clients, &self.tokens))
+            .await?;
+        Ok(())
+    }
+}
+
+/// An object for sending messages to clients
+pub struct ClientRegisterOut {
+    current_clients: SharedClients,
+    converters: Converters,
+}
+
+impl ClientRegisterOut {
+    /// How capabilities too new for a client are turned into older versions,
+    /// by default none can be converted.
+    pub fn set_converters(&mut self, converters: Converters) {
+        self.converters = converters;
+    }
+
+    /// Prepares capabilities for a client (e.g: for the answer to its session),
+    /// those it can't handle are left out and their errors returned.
+    /// Capabilities for clients that are not connected are left untouched.
+    pub fn adapt_for_client(
+        &self,
+        client_id: &str,
+        capabilities: Vec<PlainCapability>,
+    ) -> (Vec<PlainCapability>, Vec<CapabilityError>) {
+        let supported = self
+            .current_clients
+            .lock()
+            .unwrap()
+            .get(client_id)
+            .map(|c| c.capabilities.clone())
+            .unwrap_or_default();
+
+        self.converters.adapt_all(capabilities, &supported)
+    }
+
+    /// Sends the data of a skill notification to each of the clients it is
+    /// directed to. Data for clients that are not connected is answered with a
+    /// 404, data directed to the system itself is not handled here.
+    /// Capabilities a client can't handle are not sent, they are returned as
+    /// errors (with a 400 if nothing could be sent).
+    pub async fn send_notification(&mut self, notification: Notification) -> Vec<NotificationResponse> {
+        let skill_id = notification.skill_id;
+        let mut answers = Vec::new();
+        for data in notification.data {
+            if data.client_id == SYSTEM_SELF_ID {
+                continue;
+            }
+
+            let address = self
+                .current_clients
+                .lock()
+                .unwrap()
+                .get(&data.client_id)
+                .map(|c| c.address);
+
+            let (capabilities, capability_errors) = self.adapt_for_client(&data.client_id, data.capabilities);
+            let code = match address {
+                Some(_) if capabilities.is_empty() && !capability_errors.is_empty() => {
+                    coap_code(ResponseType::BadRequest)
+                }
+                Some(address) => {
+                    let msg = MsgClientNotification {
+                        capabilities: capabilities
+                            .into_iter()
+                            .map(|c| msg_client_notification::Capability {
+                                name: c.name,
+                                from: skill_id.clone(),
+                                version: c.version,
+                                cap_data: c.cap_data,
+                            })
+                            .collect(),
+                    };
+
+                    match Self::post_notification(address, msg).await {
+                        Ok(status) => coap_code(status),
+                        Err(e) => {
+                            println!("Error sending notification to {}: {:?}", &data.client_id, e);
+                            coap_code(ResponseType::GatewayTimeout)
+                        }
+                    }
+                }
+                None => coap_code(ResponseType::NotFound),
+            };
+
+            answers.push(NotificationResponse {
+                client_id: data.client_id,
+                code,
+                capability_errors,
+            });
+        }
+
+        answers
+    }
+
+    async fn post_notification(
+        address: SocketAddr,
+        msg: MsgClientNotification,
+    ) -> Result<ResponseType, Error> {
+        let payload = rmp_serde::to_vec_named(&msg).expect("Failed to encode notification, report this");
+        tokio::task::spawn_blocking(move || {
+            let mut client = CoAPClient::new(address)?;
+            let resp = client.request_path(
+                "vap/notification",
+                Method::Post,
+                Some(payload),
+                Some(NOTIFICATION_TIMEOUT),
+            )?;
+            Ok(*resp.get_status())
+        })
+        .await?
+    }
+}
+
+/// An object that will receive messages from clients
+pub struct ClientRegisterStream {
+    stream_in: mpsc::Receiver<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+}
+
+impl ClientRegisterStream {
+    /// Await this on a loop to get messages from clients
+    /// # Examples
+    /// ```ignore
+    /// loop {
+    ///     let (msg, response) = client_register_stream.recv().await.unwrap();
+    /// }
+    ///```
+    pub async fn recv(
+        &mut self,
+    ) -> Result<(ClientRegisterMessage, oneshot::Sender<Response>), Error> {
+        self.stream_in.next().await.ok_or(Error::ClosedChannel)
+    }
 }
@@ -0,0 +1,123 @@
+use std::io::Cursor;
+use std::net::SocketAddr;
+
+use crate::{respond, ClientRegisterMessage, Response};
+
+use coap_lite::{CoapRequest, CoapResponse, MessageClass, ResponseType};
+use futures::{channel::{mpsc, oneshot}, SinkExt};
+use rmp_serde::{from_read, to_vec_named};
+use serde::de::DeserializeOwned;
+use vap_common_client::structures::VapError;
+
+pub async fn wait_response<F>(
+    receiver: oneshot::Receiver<Response>,
+    resp: Option<CoapResponse>,
+    cb: F
+) -> Option<CoapResponse> where
+F: FnOnce(&mut Response) {
+    match receiver.await {
+        Ok(mut resp_data) => {
+            cb(&mut resp_data);
+            respond(resp, resp_data.status, resp_data.payload)
+        }
+        Err(_) => {
+            None
+        }
+    }  
+}
+
+/// Sends a message to the stream and waits for the answer of the application,
+/// if the stream is gone the client receives an internal error. `cb` can look
+/// at (and change) the answer before it is sent.
+pub async fn send_and_wait<F>(
+    in_send: &mut mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+    msg: ClientRegisterMessage,
+    resp: Option<CoapResponse>,
+    cb: F
+) -> Option<CoapResponse> where
+F: FnOnce(&mut Response) {
+    let (sender, receiver) = oneshot::channel();
+    if in_send.send((msg, sender)).await.is_err() {
+        println!("The client register stream was dropped, can't answer the request");
+        return respond(resp, ResponseType::InternalServerError, vec![]);
+    }
+
+    wait_response(receiver, resp, cb).await
+}
+
+/// Answer with an error, the status is taken from the error code. An error
+/// that can't be encoded becomes an empty internal error.
+pub fn respond_error(r: Option<CoapResponse>, error: VapError) -> Option<CoapResponse> {
+    match to_vec_named(&error) {
+        Ok(payload) => respond(r, status_from_code(error.code), payload),
+        Err(e) => {
+            println!("Failed to encode an error: {}", e);
+            respond(r, ResponseType::InternalServerError, vec![])
+        }
+    }
+}
+
+/// Transforms a numeric CoAP code (e.g: 404) into its response type
+fn status_from_code(code: u16) -> ResponseType {
+    let raw = (((code / 100) << 5) | (code % 100)) as u8;
+    match MessageClass::from(raw) {
+        MessageClass::Response(status) => status,
+        _ => ResponseType::InternalServerError,
+    }
+}
+
+pub fn response_not_found(r: Option<CoapResponse>) -> Option<CoapResponse> {
+    respond(r, ResponseType::NotFound, vec![])
+}
+
+pub fn read_payload<T: DeserializeOwned>(payload: &[u8], r: Option<CoapResponse>) -> Result<(T, Option<CoapResponse>), Option<CoapResponse>> {
+    match from_read(Cursor::new(payload)) {
+        Ok::<T,_>(a) => {
+            Ok((a,r))
+        }
+        Err(e) => {
+            Err(r.map(|mut r|{
+                println!("Found an error while reading payload: {}", &e);
+                let status = match e {
+                    rmp_serde::decode::Error::TypeMismatch(_) => {
+                        coap_lite::ResponseType::RequestEntityIncomplete
+                    }
+
+                    _ => {
+                        coap_lite::ResponseType::BadRequest
+                    }
+                };
+
+                r.set_status(status);
+                r
+            }))
+        }
+    }
+}
+
+pub async fn handle_msg<T: DeserializeOwned, F, F2>(
+    request: CoapRequest<SocketAddr>,
+    in_send: &mut mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+    key_check: F2,
+    cb: F,
+) -> Option<CoapResponse> where
+    F: FnOnce(T) -> ClientRegisterMessage,
+    F2: FnOnce(&T) -> Result<(), VapError>{
+
+    match read_payload(&request.message.payload, request.response) {
+        Ok::<(T,_),_>((p, resp)) => {
+            match key_check(&p) {
+                Ok(()) => {
+                    send_and_wait(in_send, cb(p), resp, |_|{}).await
+                }
+                Err(error) => {
+                    println!("Bad request because key_check");
+                    respond_error(resp, error)
+                }
+            }
+        }
+        Err(r) => {
+            r
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,306 @@
+// Handle the incoming CoAP requests
+
+use std::net::SocketAddr;
+
+use crate::vars::VAP_VERSION;
+use crate::{is_ok, respond, ClientInfo, ClientRegisterMessage, Response, SharedClients, SharedTokens};
+use self::io_helpers::*;
+
+use coap_lite::{CoapRequest, CoapResponse, ResponseType};
+use futures::channel::{mpsc, oneshot};
+use rmp_serde::{from_slice, to_vec_named};
+use vap_common_client::structures::*;
+
+mod io_helpers;
+
+pub async fn on_get(request: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+    match request.get_path().as_str() {
+        ".well-known/core" => {
+            respond(request.response, ResponseType::Content, b"</vap>;rt=\"vap-client-registry\"".to_vec())
+        }
+
+        _ => response_not_found(request.response)
+    }
+}
+
+pub async fn on_post(
+    request: CoapRequest<SocketAddr>,
+    in_send: &mut mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+    current_clients: &SharedClients,
+    tokens: &SharedTokens,
+) -> Option<CoapResponse> {
+    match request.get_path().as_str() {
+        "vap/clientRegistry/connect" => {
+            let source = request.source;
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgClientConnect,_),_>((p, resp)) => {
+                    // Clients that connected before need to prove who they are
+                    let (is_known, authorized) = {
+                        let tokens = tokens.lock().unwrap();
+                        let is_known = tokens.is_known(&p.id);
+                        (is_known, !is_known || tokens.verify(&p.id, p.auth_token.as_deref()))
+                    };
+                    match (p.vap_version == VAP_VERSION, source) {
+                        (true, Some(_)) if !authorized => {
+                            println!("{} tried to connect without its token", p.id);
+                            respond_error(resp, VapError::connection_denied())
+                        }
+                        (true, Some(source)) => {
+                            // Notifications are sent to the same address the
+                            // client connected from, unless it told us otherwise
+                            let mut address = source;
+                            if let Some(port) = p.port {
+                                address.set_port(port);
+                            }
+
+                            let client_id = p.id.clone();
+                            let capabilities = p.capabilities.clone();
+                            send_and_wait(in_send, ClientRegisterMessage::Connect(p), resp, |r| {
+                                if is_ok(r.status) {
+                                    // The first time a client connects it receives its token
+                                    let given = if is_known { Ok(()) } else { give_token(r, tokens, &client_id) };
+                                    match given {
+                                        // A client reconnecting (e.g: after a crash)
+                                        // just gets its record updated
+                                        Ok(()) => {
+                                            current_clients.lock().unwrap().insert(
+                                                client_id,
+                                                ClientInfo { address, capabilities }
+                                            );
+                                        }
+                                        Err(()) => {
+                                            r.status = ResponseType::InternalServerError;
+                                            r.payload = vec![];
+                                        }
+                                    }
+                                }
+                            }).await
+                        }
+                        (false, _) => {
+                            println!("Received a non-compatible version, bad request");
+                            respond(resp, ResponseType::BadRequest, vec![])
+                        }
+                        (true, None) => {
+                            respond(resp, ResponseType::BadRequest, vec![])
+                        }
+                    }
+                }
+                Err(r) => {
+                    r
+                }
+            }
+        }
+
+        "vap/clientRegistry/sessionStart" => {
+            handle_msg(
+                request,
+                in_send,
+                |p: &MsgSessionStart|client_authenticated(current_clients, tokens, &p.client_id, p.auth_token.as_deref()),
+                ClientRegisterMessage::SessionStart
+            ).await
+        }
+
+        "vap/clientRegistry/sessionData" => {
+            handle_msg(
+                request,
+                in_send,
+                |p: &MsgSessionData|client_authenticated(current_clients, tokens, &p.client_id, p.auth_token.as_deref()),
+                ClientRegisterMessage::SessionData
+            ).await
+        }
+
+        "vap/clientRegistry/clientClose" => {
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgClientClose,_),_>((p, resp)) => {
+                    match client_authenticated(current_clients, tokens, &p.client_id, p.auth_token.as_deref()) {
+                        Ok(()) => {
+                            let client_id = p.client_id.clone();
+                            send_and_wait(in_send, ClientRegisterMessage::Close(p), resp, |r| {
+                                if is_ok(r.status) {
+                                    current_clients.lock().unwrap().remove(&client_id);
+                                }
+                            }).await
+                        }
+                        Err(error) => {
+                            respond_error(resp, error)
+                        }
+                    }
+                }
+                Err(r) => {
+                    r
+                }
+            }
+        }
+
+        _ => response_not_found(request.response)
+    }
+}
+
+/// Checks that the client is connected and that the message carries its token
+fn client_authenticated(current_clients: &SharedClients, tokens: &SharedTokens, id: &str, token: Option<&str>) -> Result<(), VapError> {
+    if !current_clients.lock().unwrap().contains_key(id) {
+        Err(VapError::wrong_client_id(id))
+    }
+    else if !tokens.lock().unwrap().verify(id, token) {
+        Err(VapError::unauthorized())
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Issues a token for a new client and adds it to the answer of the application
+fn give_token(response: &mut Response, tokens: &SharedTokens, id: &str) -> Result<(), ()> {
+    let mut msg: MsgClientConnectResponse = from_slice(&response.payload).map_err(|e| {
+        println!("The application answered a connect with something unexpected: {}", e);
+    })?;
+    msg.auth_token = Some(tokens.lock().unwrap().issue(id).map_err(|e| {
+        println!("Couldn't issue a token for {}: {}", id, e);
+    })?);
+    response.payload = to_vec_named(&msg).map_err(|_| ())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex as SyncMutex};
+
+    use coap_lite::{MessageClass, MessageType, Packet, RequestType as Method};
+    use futures::future::{select, Either};
+    use futures::StreamExt;
+    use serde::Serialize;
+    use vap_common_client::auth::TokenStore;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "org.company.speaker";
+
+    type Stream = mpsc::Receiver<(ClientRegisterMessage, oneshot::Sender<Response>)>;
+
+    struct Register {
+        in_send: mpsc::Sender<(ClientRegisterMessage, oneshot::Sender<Response>)>,
+        stream: Stream,
+        clients: SharedClients,
+        tokens: SharedTokens,
+    }
+
+    impl Register {
+        fn new() -> Self {
+            let (in_send, stream) = mpsc::channel(1);
+            Self {
+                in_send,
+                stream,
+                clients: Arc::new(SyncMutex::new(HashMap::new())),
+                tokens: Arc::new(SyncMutex::new(TokenStore::in_memory())),
+            }
+        }
+
+        /// Sends a request, the application answers with `status` and `payload`
+        async fn post(&mut self, request: CoapRequest<SocketAddr>, status: ResponseType, payload: Vec<u8>) -> (CoapResponse, Option<ClientRegisterMessage>) {
+            let stream = &mut self.stream;
+            let answer = Box::pin(async move {
+                let (msg, sender) = stream.next().await.unwrap();
+                sender.send(Response { status, payload }).ok().unwrap();
+                msg
+            });
+            let handle = Box::pin(on_post(request, &mut self.in_send, &self.clients, &self.tokens));
+            // Requests refused by the register never reach the application
+            match select(handle, answer).await {
+                Either::Left((resp, _)) => (resp.unwrap(), None),
+                Either::Right((msg, handle)) => (handle.await.unwrap(), Some(msg)),
+            }
+        }
+
+        async fn connect(&mut self, token: Option<String>) -> CoapResponse {
+            let connect = MsgClientConnect {
+                id: CLIENT_ID.into(),
+                name: "Speaker".into(),
+                vap_version: VAP_VERSION.into(),
+                port: Some(7000),
+                capabilities: vec![],
+                auth_token: token,
+            };
+            let answer = MsgClientConnectResponse { locales: vec![], auth_token: None };
+            self.post(request("vap/clientRegistry/connect", &connect), ResponseType::Created, to_vec_named(&answer).unwrap()).await.0
+        }
+    }
+
+    fn request<T: Serialize>(path: &str, msg: &T) -> CoapRequest<SocketAddr> {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(Method::Post);
+        packet.payload = to_vec_named(msg).unwrap();
+        let mut request = CoapRequest::from_packet(packet, "127.0.0.1:6000".parse().unwrap());
+        request.set_path(path);
+        request
+    }
+
+    #[tokio::test]
+    async fn connect() {
+        let mut register = Register::new();
+        let resp = register.connect(None).await;
+        assert_eq!(*resp.get_status(), ResponseType::Created);
+        let msg: MsgClientConnectResponse = from_slice(&resp.message.payload).unwrap();
+        let token = msg.auth_token.expect("The first connection gets a token");
+        assert_eq!(register.clients.lock().unwrap()[CLIENT_ID].address, "127.0.0.1:7000".parse().unwrap());
+
+        // Later connections need the token, and don't get a new one
+        let resp = register.connect(None).await;
+        assert_eq!(*resp.get_status(), ResponseType::Unauthorized);
+        let error: VapError = from_slice(&resp.message.payload).unwrap();
+        assert_eq!(error, VapError::connection_denied());
+        let resp = register.connect(Some(token)).await;
+        assert_eq!(*resp.get_status(), ResponseType::Created);
+        let msg: MsgClientConnectResponse = from_slice(&resp.message.payload).unwrap();
+        assert_eq!(msg.auth_token, None);
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let mut register = Register::new();
+        let resp = register.connect(None).await;
+        let token = from_slice::<MsgClientConnectResponse>(&resp.message.payload).unwrap().auth_token;
+
+        let close = |auth_token| MsgClientClose { client_id: CLIENT_ID.into(), auth_token };
+        let (resp, msg) = register.post(request("vap/clientRegistry/clientClose", &close(None)), ResponseType::Deleted, vec![]).await;
+        assert_eq!(*resp.get_status(), ResponseType::Unauthorized);
+        let error: VapError = from_slice(&resp.message.payload).unwrap();
+        assert_eq!(error, VapError::unauthorized());
+        assert!(msg.is_none());
+        assert!(register.clients.lock().unwrap().contains_key(CLIENT_ID));
+
+        let (resp, msg) = register.post(request("vap/clientRegistry/clientClose", &close(token)), ResponseType::Deleted, vec![]).await;
+        assert_eq!(*resp.get_status(), ResponseType::Deleted);
+        assert!(matches!(msg, Some(ClientRegisterMessage::Close(_))));
+        assert!(register.clients.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_payloads() {
+        let mut register = Register::new();
+        let mut garbage = request("vap/clientRegistry/connect", &());
+        // A map which is cut short
+        garbage.message.payload = vec![0x82];
+        let (resp, msg) = register.post(garbage, ResponseType::Created, vec![]).await;
+        assert_eq!(*resp.get_status(), ResponseType::BadRequest);
+        assert!(msg.is_none());
+
+        // A session for a client that never connected
+        let start = MsgSessionStart {
+            client_id: CLIENT_ID.into(),
+            capabilities: vec![],
+            exact_time_stamp: None,
+            auth_token: None,
+        };
+        let (resp, msg) = register.post(request("vap/clientRegistry/sessionStart", &start), ResponseType::Created, vec![]).await;
+        assert_eq!(*resp.get_status(), ResponseType::BadRequest);
+        let error: VapError = from_slice(&resp.message.payload).unwrap();
+        assert_eq!(error, VapError::wrong_client_id(CLIENT_ID));
+        assert!(msg.is_none());
+
+        let (resp, _) = register.post(request("vap/clientRegistry/unknown", &()), ResponseType::Created, vec![]).await;
+        assert_eq!(*resp.get_status(), ResponseType::NotFound);
+    }
+}
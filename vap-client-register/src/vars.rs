@@ -0,0 +1,6 @@
+// Some vars to be exported (though some are used internally as well)
+
+/// VAP version implemented by this crate
+pub const VAP_VERSION: &str = "Alpha";
+/// The name used to refer to the client register itself
+pub const SYSTEM_SELF_ID: &str = "vap.SYSTEM";
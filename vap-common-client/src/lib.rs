@@ -1,7 +1,53 @@
+pub mod structures;
+
+pub use vap_common::auth;
+pub use vap_common::capability;
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use crate::structures::*;
+    use vap_common::structures::Value;
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn session_data_uses_camel_case() {
+        let msg = MsgSessionData {
+            client_id: "org.company.product".into(),
+            capabilities: vec![],
+            last_fragment: true,
+            auth_token: Some("0123456789abcdef".into()),
+        };
+
+        let encoded = rmp_serde::to_vec_named(&msg).unwrap();
+        let fields: HashMap<String, Value> = rmp_serde::from_slice(&encoded).unwrap();
+        assert!(fields.contains_key("clientId"));
+        assert!(fields.contains_key("lastFragment"));
+        assert!(fields.contains_key("uniqueAuthenticationToken"));
+
+        let decoded: MsgSessionData = rmp_serde::from_slice(&encoded).unwrap();
+        assert!(decoded.last_fragment);
+    }
+
+    #[test]
+    fn notification_keeps_capability_data() {
+        let mut cap_data = HashMap::new();
+        cap_data.insert("text".into(), "Hello!".into());
+        let msg = MsgClientNotification {
+            capabilities: vec![msg_client_notification::Capability {
+                name: "text".into(),
+                from: "com.example.test".into(),
+                version: 2,
+                cap_data,
+            }],
+        };
+
+        let encoded = rmp_serde::to_vec_named(&msg).unwrap();
+        let decoded: MsgClientNotification = rmp_serde::from_slice(&encoded).unwrap();
+        let cap = &decoded.capabilities[0];
+        assert_eq!(cap.from, "com.example.test");
+        assert_eq!(cap.cap_data.get(&"text".into()), Some(&"Hello!".into()));
+        assert_eq!(cap.version, 2);
+        assert!(!cap.cap_data.contains_key(&"version".into()));
     }
 }
@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+pub use vap_common::structures::{error_types, AssociativeMap, Language, PlainCapability, Value, VapError};
+use vap_common::capability::CapabilityRange;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgClientConnect {
+    /// A client id in the form of org.company.product
+    pub id: String,
+
+    /// A human readable name for the client
+    pub name: String,
+
+    #[serde(rename = "vapVersion")]
+    pub vap_version: String,
+
+    /// Port in which the client listens for notifications, if none is given
+    /// the one used for connecting is assumed
+    #[serde(default)]
+    pub port: Option<u16>,
+
+    /// Versions of the capabilities the client can handle, clients that don't
+    /// declare any receive everything
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<CapabilityRange>,
+
+    /// The token we got the first time we connected, if any
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgClientConnectResponse {
+    /// A list of locales currently in use by the voice assistant
+    pub locales: Vec<Language>,
+
+    /// Only sent the first time a client connects, keep it
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgSessionStart {
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+
+    /// Capabilities meant for user authorization and wakeword double checking
+    #[serde(default)]
+    pub capabilities: Vec<PlainCapability>,
+
+    #[serde(rename = "exactTimeStamp", default)]
+    pub exact_time_stamp: Option<u64>,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgSessionData {
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+
+    pub capabilities: Vec<PlainCapability>,
+
+    #[serde(rename = "lastFragment")]
+    pub last_fragment: bool,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgSessionDataResponse {
+    pub capabilities: Vec<PlainCapability>,
+
+    #[serde(rename = "endSession")]
+    pub end_session: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgClientNotification {
+    pub capabilities: Vec<msg_client_notification::Capability>,
+}
+
+pub mod msg_client_notification {
+    use serde::{Deserialize, Serialize};
+
+    use super::AssociativeMap;
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Capability {
+        pub name: String,
+
+        /// Skill that sent this capability, can also be the system itself
+        pub from: String,
+
+        #[serde(default = "vap_common::capability::default_version")]
+        pub version: u16,
+
+        #[serde(flatten)]
+        pub cap_data: AssociativeMap,
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgClientClose {
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+}
@@ -1,5 +1,11 @@
 pub mod structures;
 
+pub use vap_common::auth;
+pub use vap_common::capability;
+#[cfg(feature = "oscore")]
+pub use vap_common::oscore;
+pub use vap_common::version;
+
 #[cfg(test)]
 mod tests {
     #[test]
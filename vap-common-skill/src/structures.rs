@@ -1,11 +1,8 @@
-use std::{
-    collections::HashMap,
-    fmt::{Display, Write},
-    hash::Hash,
-};
-
 use serde::{Deserialize, Serialize};
-use unic_langid::LanguageIdentifier;
+
+pub use vap_common::structures::{error_types, AssociativeMap, Language, PlainCapability, Value, VapError};
+use vap_common::capability::CapabilityRange;
+use vap_common::version::{Version, VersionRange};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgConnect {
@@ -15,14 +12,45 @@ pub struct MsgConnect {
     /// A human readable name for the skill
     pub name: String,
 
+    /// Registries that don't negotiate only accept the exact version they
+    /// know, so this should be the oldest one the skill supports
     #[serde(rename = "vapVersion")]
-    pub vap_version: String
+    pub vap_version: String,
+
+    /// Every version the skill supports, skills without it only support
+    /// `vap_version`
+    #[serde(rename = "vapVersions", default, skip_serializing_if = "Option::is_none")]
+    pub vap_versions: Option<VersionRange>,
+
+    /// Versions of the capabilities the skill can handle, skills that don't
+    /// declare any receive everything
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<CapabilityRange>,
+
+    /// The token we got the first time we connected, if any
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgConnectResponse {
     /// A list of languages currently in use by the voice assistant
-    pub langs: Vec<Language>
+    pub langs: Vec<Language>,
+
+    /// The version agreed with the skill, filled by the registry. Registries
+    /// that don't negotiate don't send it, they talk the `vap_version` of
+    /// the connect message.
+    #[serde(rename = "vapVersion", default, skip_serializing_if = "Option::is_none")]
+    pub vap_version: Option<Version>,
+
+    /// Milliseconds without hearing from the skill after which the registry
+    /// considers it gone, filled by the registry. Missing if there's no lease.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lease: Option<u64>,
+
+    /// Only sent the first time a skill connects, keep it
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -32,12 +60,29 @@ pub struct MsgRegisterIntents {
 
     #[serde(rename = "nluData")]
     pub nlu_data: Vec<msg_register_intents::NluData>,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 pub mod msg_register_intents {
     use super::Language;
     use serde::{Deserialize, Serialize};
 
+    /// The scope of intents that don't say theirs, always enabled at first
+    pub const MAIN_SCOPE: &str = "main";
+
+    fn main_scope() -> String {
+        MAIN_SCOPE.to_string()
+    }
+
+    /// Built-in entities, slots can use them without registering them
+    pub const DATE_ENTITY: &str = "vap.date";
+    pub const TIME_ENTITY: &str = "vap.time";
+    pub const NUMBER_ENTITY: &str = "vap.number";
+    pub const BUILTIN_ENTITIES: &[&str] = &[DATE_ENTITY, TIME_ENTITY, NUMBER_ENTITY];
+
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct NluData {
         pub language: Language,
@@ -50,6 +95,10 @@ pub mod msg_register_intents {
         pub name: String,
         pub utterances: Vec<NluDataIntentUtterance>,
         pub slots: Vec<NluDataSlot>,
+
+        /// Intents are only recognized while their scope is enabled
+        #[serde(default = "main_scope")]
+        pub scope: String,
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -61,13 +110,57 @@ pub mod msg_register_intents {
     pub struct NluDataSlot {
         pub name: String,
         pub entity: String,
+
+        /// The assistant must ask for the slot before sending the request
+        #[serde(default)]
+        pub required: bool,
+
+        /// How to ask for a required slot that is missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub prompt: Option<String>,
+
+        /// How to ask again if the answer to the prompt didn't fill the slot
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub reprompt: Option<String>,
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
     pub struct NluDataEntity {
         pub name: String,
+
+        /// Only the values in the list are accepted (lists and references)
+        #[serde(default)]
         pub strict: bool,
+
+        #[serde(default)]
         pub data: Vec<NluDataEntityData>,
+
+        /// How values are recognized, just `data` if missing
+        #[serde(default)]
+        pub kind: NluDataEntityKind,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(tag = "type", rename_all = "camelCase")]
+    pub enum NluDataEntityKind {
+        /// The values in `data`
+        #[default]
+        List,
+
+        /// Any text matching the regular expression
+        Regex { pattern: String },
+
+        /// A number between `min` and `max` (both included)
+        Range {
+            min: f64,
+            max: f64,
+
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            step: Option<f64>,
+        },
+
+        /// The values of another entity of the same language, plus `data`
+        Reference { entity: String },
     }
 
     #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -78,42 +171,25 @@ pub mod msg_register_intents {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Language {
-    // Better this or a single string?
-    /// The country code of the language
-    pub country: Option<String>,
+pub struct MsgRegisterIntentsResponse {}
 
-    /// The language code
-    pub language: String,
+/// Enables and disables scopes of intents, scopes in both lists are enabled
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MsgSetScopes {
+    #[serde(rename = "skillId")]
+    pub skill_id: String,
 
-    /// The extra code for the language
-    pub extra: Option<String>, // is this necessary?
-}
+    #[serde(default)]
+    pub enable: Vec<String>,
 
-impl From<LanguageIdentifier> for Language {
-    fn from(l: LanguageIdentifier) -> Self {
-        Language {
-            country: l.region.map(|r| r.to_string()),
-            language: l.language.to_string(),
-            extra: l.script.map(|s| s.to_string()),
-        }
-    }
-}
+    #[serde(default)]
+    pub disable: Vec<String>,
 
-impl From<Language> for LanguageIdentifier {
-    fn from(lang: Language) -> Self {
-        LanguageIdentifier::from_parts(
-            lang.language.parse().unwrap(),
-            lang.extra.and_then(|e| e.parse().ok()),
-            lang.country.and_then(|c| c.parse().ok()),
-            &[],
-        )
-    }
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct MsgRegisterIntentsResponse {}
-
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MsgSkillRequest {
     pub request_id: u64,
@@ -171,6 +247,10 @@ pub struct MsgNotification {
     pub skill_id: String,
 
     pub data: Vec<msg_notification::Data>,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 pub mod msg_notification {
@@ -213,6 +293,8 @@ pub struct MsgNotificationResponse {
 pub mod msg_notification_response {
     use serde::{Deserialize, Serialize};
 
+    use vap_common::capability::CapabilityError;
+
     #[derive(Clone, Debug, Deserialize, Serialize)]
     #[serde(tag = "type")]
     pub enum Data {
@@ -221,6 +303,10 @@ pub mod msg_notification_response {
             #[serde(rename = "requestId")]
             request_id: u64,
             code: u16,
+
+            /// Capabilities the client couldn't receive
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            errors: Vec<CapabilityError>,
         },
 
         #[serde(rename = "standalone")]
@@ -228,6 +314,10 @@ pub mod msg_notification_response {
             #[serde(rename = "clientId")]
             client_id: String,
             code: u16,
+
+            /// Capabilities the client couldn't receive
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            errors: Vec<CapabilityError>,
         },
 
         #[serde(rename = "canYouAnswer")]
@@ -244,6 +334,10 @@ pub struct MsgQuery {
     #[serde(rename = "skillId")]
     pub skill_id: String,
     pub data: Vec<msg_query::QueryData>,
+
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
 
 pub mod msg_query {
@@ -279,6 +373,10 @@ pub mod msg_query_response {
         pub name: String,
         pub code: u16,
 
+        /// Version of the data, see [`crate::capability`]
+        #[serde(default = "crate::capability::default_version")]
+        pub version: u16,
+
         #[serde(flatten)]
         pub data: AssociativeMap,
     }
@@ -288,143 +386,8 @@ pub mod msg_query_response {
 pub struct MsgSkillClose {
     #[serde(rename = "skillId")]
     pub skill_id: String,
-}
-
-/// A structure describing Capability data
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct PlainCapability {
-    pub name: String,
-
-    #[serde(flatten)]
-    pub cap_data: AssociativeMap,
-}
-
-pub type AssociativeMap = HashMap<Value, Value>;
 
-/// Used as variant for the capabilities data. Represents all types in MsgPack
-#[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum Value {
-    Nil,
-    Bool(bool),
-    I8(i8),
-    U8(u8),
-    I16(i16),
-    U16(u16),
-    I32(i32),
-    U32(u32),
-    I64(i64),
-    U64(u64),
-    F32(f32),
-    F64(f64),
-    String(String),
-    Binary(Vec<u8>),
-    Array(Vec<Value>),
-    Map(HashMap<Value, Value>),
-    // Timestamp // TODO! Finish this type
-}
-
-/// PartialEq implementation. For floating point instead of direct equality, we
-/// we make sure they are close enough (because of how computers treat decimals).
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
-            (Self::I8(l0), Self::I8(r0)) => l0 == r0,
-            (Self::U8(l0), Self::U8(r0)) => l0 == r0,
-            (Self::I16(l0), Self::I16(r0)) => l0 == r0,
-            (Self::U16(l0), Self::U16(r0)) => l0 == r0,
-            (Self::I32(l0), Self::I32(r0)) => l0 == r0,
-            (Self::U32(l0), Self::U32(r0)) => l0 == r0,
-            (Self::I64(l0), Self::I64(r0)) => l0 == r0,
-            (Self::U64(l0), Self::U64(r0)) => l0 == r0,
-            (Self::F32(l0), Self::F32(r0)) => (l0 - r0) < std::f32::EPSILON,
-            (Self::F64(l0), Self::F64(r0)) => (l0 - r0) < std::f64::EPSILON,
-            (Self::String(l0), Self::String(r0)) => l0 == r0,
-            (Self::Binary(l0), Self::Binary(r0)) => l0 == r0,
-            (Self::Array(l0), Self::Array(r0)) => l0 == r0,
-            (Self::Map(l0), Self::Map(r0)) => l0 == r0,
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
-        }
-    }
-}
-
-impl Hash for Value {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        core::mem::discriminant(self).hash(state);
-    }
-}
-
-impl Eq for Value {}
-
-impl Display for Value {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fn write_vec<D: Display>(
-            v: &[D],
-            fmt: &mut std::fmt::Formatter,
-        ) -> Result<(), std::fmt::Error> {
-            fmt.write_char('[')?;
-            let mut it = v.iter().peekable();
-            while let Some(val) = it.next() {
-                if it.peek().is_some() {
-                    fmt.write_fmt(format_args!("{}, ", &val.to_string()))?;
-                } else {
-                    fmt.write_str(&val.to_string())?;
-                }
-            }
-
-            fmt.write_char(']')?;
-
-            Ok(())
-        }
-
-        fn write_map<D1: Display, D2: Display>(
-            m: &HashMap<D1, D2>,
-            fmt: &mut std::fmt::Formatter,
-        ) -> Result<(), std::fmt::Error> {
-            fmt.write_char('{')?;
-            let mut it = m.iter().peekable();
-            while let Some((k, v)) = it.next() {
-                if it.peek().is_some() {
-                    fmt.write_fmt(format_args!("{}: {}, ", k, v))?;
-                } else {
-                    fmt.write_fmt(format_args!("{}: {}", k, v))?;
-                }
-            }
-            fmt.write_char('}')?;
-
-            Ok(())
-        }
-
-        match self {
-            Value::Nil => fmt.write_str("Nil"),
-            Value::Bool(b) => fmt.write_str(&b.to_string()),
-            Value::I8(i) => fmt.write_str(&i.to_string()),
-            Value::U8(u) => fmt.write_str(&u.to_string()),
-            Value::I16(i) => fmt.write_str(&i.to_string()),
-            Value::U16(u) => fmt.write_str(&u.to_string()),
-            Value::I32(i) => fmt.write_str(&i.to_string()),
-            Value::U32(u) => fmt.write_str(&u.to_string()),
-            Value::I64(i) => fmt.write_str(&i.to_string()),
-            Value::U64(u) => fmt.write_str(&u.to_string()),
-            Value::F32(f) => fmt.write_str(&f.to_string()),
-            Value::F64(f) => fmt.write_str(&f.to_string()),
-            Value::String(str) => fmt.write_str(str),
-            Value::Binary(b) => write_vec(b, fmt),
-            Value::Array(a) => write_vec(a, fmt),
-            Value::Map(m) => write_map(m, fmt),
-        }
-    }
-}
-
-impl From<String> for Value {
-    fn from(s: String) -> Self {
-        Value::String(s)
-    }
-}
-
-impl From<&str> for Value {
-    fn from(s: &str) -> Self {
-        Value::String(s.to_string())
-    }
+    /// Given by the registry the first time we connect, must be sent afterwards
+    #[serde(rename = "uniqueAuthenticationToken", default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
 }
@@ -0,0 +1,190 @@
+//! Unique authentication tokens. Registries give one to every skill or client
+//! the first time it connects, afterwards it must be included in every
+//! message (and in later connections).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Requests without a payload (e.g: observing) carry the token in a query
+/// with this name: `?uniqueAuthenticationToken=0123456789abcdef`
+pub const TOKEN_QUERY: &str = "uniqueAuthenticationToken";
+
+/// Creates a new random token
+pub fn new_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::from)?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Reads a token saved with [`save_token`], `None` if there's none
+pub fn load_token<P: AsRef<Path>>(path: P) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+/// Saves the token given by a registry so that it can be used later
+pub fn save_token<P: AsRef<Path>>(path: P, token: &str) -> io::Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_private(path.as_ref(), token)
+}
+
+/// Where to keep the token of a skill or client by default:
+/// `$HOME/.local/share/vap/{id}.token`. `None` if there's no home or the id
+/// could escape that directory (it's empty or has `/`, `\\` or `..`).
+pub fn default_token_path(id: &str) -> Option<PathBuf> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+        return None;
+    }
+
+    std::env::var_os("HOME").map(|home| {
+        PathBuf::from(home)
+            .join(".local/share/vap")
+            .join(format!("{}.token", id))
+    })
+}
+
+/// The ids known by a registry along with their tokens, optionally kept in a
+/// file (one `id token` pair per line) so that they survive restarts.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    path: Option<PathBuf>,
+    tokens: HashMap<String, String>,
+}
+
+impl TokenStore {
+    /// A store which is forgotten when dropped
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// A store kept in `path`, it is created if it doesn't exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let tokens = match fs::read_to_string(&path) {
+            Ok(data) => data
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .map(|(id, token)| (id.to_string(), token.trim().to_string()))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            tokens,
+        })
+    }
+
+    /// Whether this id has connected before
+    pub fn is_known(&self, id: &str) -> bool {
+        self.tokens.contains_key(id)
+    }
+
+    /// Whether `token` is the one given to `id`
+    pub fn verify(&self, id: &str, token: Option<&str>) -> bool {
+        matches!((self.tokens.get(id), token), (Some(known), Some(token)) if constant_time_eq(known, token))
+    }
+
+    /// Gives a new token to `id` and saves it
+    pub fn issue(&mut self, id: &str) -> io::Result<String> {
+        let token = new_token()?;
+        self.tokens.insert(id.to_string(), token.clone());
+        self.save()?;
+
+        Ok(token)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let data: String = self
+                .tokens
+                .iter()
+                .map(|(id, token)| format!("{} {}\n", id, token))
+                .collect();
+            write_private(path, &data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a file only its owner can read, tokens are as good as passwords
+fn write_private(path: &Path, data: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    // The mode is only used for new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(data.as_bytes())
+}
+
+/// Compares two tokens taking the same time no matter where they differ, so
+/// that the time taken doesn't tell how much of a guess is right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_store_roundtrip() {
+        let path = std::env::temp_dir().join(format!("vap-tokens-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = TokenStore::open(&path).unwrap();
+        assert!(!store.is_known("com.example.test"));
+        let token = store.issue("com.example.test").unwrap();
+        assert!(store.verify("com.example.test", Some(&token)));
+        assert!(!store.verify("com.example.test", None));
+        assert!(!store.verify("com.example.other", Some(&token)));
+        let wrong: String = token.chars().rev().collect();
+        assert!(!store.verify("com.example.test", Some(&wrong)));
+
+        // Only we can read the tokens
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let store = TokenStore::open(&path).unwrap();
+        assert!(store.verify("com.example.test", Some(&token)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn token_paths_stay_in_their_directory() {
+        if std::env::var_os("HOME").is_some() {
+            let path = default_token_path("com.example.test").unwrap();
+            assert!(path.ends_with(".local/share/vap/com.example.test.token"));
+        }
+        assert_eq!(default_token_path(""), None);
+        assert_eq!(default_token_path("../../.bashrc"), None);
+        assert_eq!(default_token_path("com/example"), None);
+        assert_eq!(default_token_path("com\\example"), None);
+        assert_eq!(default_token_path(".."), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! Capability versions. Skills and clients declare which versions of each
+//! capability they can handle, capabilities meant for them are converted to
+//! an older version when they are too new (if a conversion is known) or
+//! refused with a [`CapabilityError`].
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::structures::PlainCapability;
+
+/// The version of capabilities that don't say theirs
+pub const DEFAULT_CAPABILITY_VERSION: u16 = 1;
+
+/// [`DEFAULT_CAPABILITY_VERSION`], for `#[serde(default = ...)]`
+pub fn default_version() -> u16 {
+    DEFAULT_CAPABILITY_VERSION
+}
+
+/// The versions of a capability something can handle, both included
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CapabilityRange {
+    pub name: String,
+
+    #[serde(rename = "minVersion")]
+    pub min_version: u16,
+
+    #[serde(rename = "maxVersion")]
+    pub max_version: u16,
+}
+
+impl CapabilityRange {
+    pub fn new<S: Into<String>>(name: S, min_version: u16, max_version: u16) -> Self {
+        Self {
+            name: name.into(),
+            min_version,
+            max_version,
+        }
+    }
+
+    pub fn contains(&self, version: u16) -> bool {
+        self.min_version <= version && version <= self.max_version
+    }
+}
+
+/// Why a capability couldn't be delivered
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum CapabilityError {
+    /// The target doesn't handle this capability at all
+    #[serde(rename = "unsupportedCapability")]
+    Unsupported { name: String },
+
+    /// The target handles other versions, and no conversion was possible
+    #[serde(rename = "unsupportedCapabilityVersion")]
+    UnsupportedVersion {
+        name: String,
+        version: u16,
+
+        #[serde(rename = "minVersion")]
+        min_version: u16,
+
+        #[serde(rename = "maxVersion")]
+        max_version: u16,
+    },
+}
+
+impl fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CapabilityError::Unsupported { name } => write!(f, "the capability {} is not supported", name),
+            CapabilityError::UnsupportedVersion {
+                name,
+                version,
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "version {} of {} is not supported, only {} to {}",
+                version, name, min_version, max_version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CapabilityError {}
+
+type Conversion = Box<dyn Fn(PlainCapability) -> PlainCapability + Send + Sync>;
+
+/// Known ways to turn a capability into its previous version
+#[derive(Default)]
+pub struct Converters {
+    /// (Name, version it converts from) -> Conversion
+    conversions: HashMap<(String, u16), Conversion>,
+}
+
+impl Converters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `convert` receives version `from` of `name` and returns the same data
+    /// as version `from - 1` (the version is set afterwards).
+    pub fn add<S, F>(&mut self, name: S, from: u16, convert: F)
+    where
+        S: Into<String>,
+        F: Fn(PlainCapability) -> PlainCapability + Send + Sync + 'static,
+    {
+        self.conversions.insert((name.into(), from), Box::new(convert));
+    }
+
+    /// Prepares a capability for a target that declared `supported`. Targets
+    /// that declared nothing receive everything untouched, otherwise the
+    /// capability must be declared and too new versions are converted down
+    /// one version at a time.
+    pub fn adapt(&self, capability: PlainCapability, supported: &[CapabilityRange]) -> Result<PlainCapability, CapabilityError> {
+        if supported.is_empty() {
+            return Ok(capability);
+        }
+
+        let range = supported
+            .iter()
+            .find(|r| r.name == capability.name)
+            .ok_or_else(|| CapabilityError::Unsupported {
+                name: capability.name.clone(),
+            })?;
+        let error = |version| CapabilityError::UnsupportedVersion {
+            name: range.name.clone(),
+            version,
+            min_version: range.min_version,
+            max_version: range.max_version,
+        };
+
+        let original = capability.version;
+        let mut capability = capability;
+        while capability.version > range.max_version {
+            let convert = self
+                .conversions
+                .get(&(capability.name.clone(), capability.version))
+                .ok_or_else(|| error(original))?;
+            let version = capability.version - 1;
+            capability = convert(capability);
+            capability.version = version;
+        }
+
+        if range.contains(capability.version) {
+            Ok(capability)
+        } else {
+            Err(error(original))
+        }
+    }
+
+    /// Adapts every capability, those that can't be are left out and their
+    /// errors returned.
+    pub fn adapt_all(
+        &self,
+        capabilities: Vec<PlainCapability>,
+        supported: &[CapabilityRange],
+    ) -> (Vec<PlainCapability>, Vec<CapabilityError>) {
+        let mut adapted = Vec::new();
+        let mut errors = Vec::new();
+        for capability in capabilities {
+            match self.adapt(capability, supported) {
+                Ok(c) => adapted.push(c),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        (adapted, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::structures::Value;
+
+    #[test]
+    fn capability_adaptation() {
+        let text = |version| PlainCapability {
+            name: "text".into(),
+            version,
+            cap_data: HashMap::new(),
+        };
+        let supported = [CapabilityRange::new("text", 1, 2)];
+        let mut converters = Converters::new();
+        converters.add("text", 3, |mut c| {
+            c.cap_data.insert("converted".into(), Value::Bool(true));
+            c
+        });
+
+        assert_eq!(converters.adapt(text(2), &supported).unwrap().version, 2);
+        let converted = converters.adapt(text(3), &supported).unwrap();
+        assert_eq!(converted.version, 2);
+        assert!(converted.cap_data.contains_key(&"converted".into()));
+
+        let too_new = CapabilityError::UnsupportedVersion {
+            name: "text".into(),
+            version: 4,
+            min_version: 1,
+            max_version: 2,
+        };
+        assert_eq!(converters.adapt(text(4), &supported).unwrap_err(), too_new);
+        assert!(converters.adapt(text(0), &supported).is_err());
+        let sound = PlainCapability { name: "sound".into(), ..text(1) };
+        assert_eq!(
+            converters.adapt(sound.clone(), &supported).unwrap_err(),
+            CapabilityError::Unsupported { name: "sound".into() }
+        );
+        // Nothing declared, nothing checked
+        assert!(converters.adapt(sound, &[]).is_ok());
+    }
+}
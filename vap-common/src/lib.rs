@@ -1,8 +1,6 @@
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
-    }
-}
+pub mod auth;
+pub mod capability;
+#[cfg(feature = "oscore")]
+pub mod oscore;
+pub mod structures;
+pub mod version;
@@ -0,0 +1,805 @@
+//! Object security for VAP messages, modelled after OSCORE (RFC 8613) with a
+//! master secret shared beforehand by each skill and the register.
+//!
+//! The code, path, query and payload of every message are encrypted and
+//! authenticated with AES-CCM-16-64-128. Other options (e.g: Observe) stay in
+//! the clear, so protected messages go through the same paths as plain ones.
+//! Every message carries its own sequence number, messages seen before are
+//! rejected.
+//!
+//! Sequence numbers are not kept across restarts, so that a nonce is never
+//! used twice with the same key each side derives its sender key with a
+//! random ID context chosen when it starts (RFC 8613 Appendix B.2). The ID
+//! context goes with every message, receivers derive the matching key the
+//! first time they see it and refuse the last ones used before it.
+//!
+//! Nothing is kept across restarts either, so a request with an ID context
+//! we haven't accepted yet could have been recorded before we started. Those
+//! are answered with a 4.01 carrying an Echo option (RFC 8613 Appendix
+//! B.1.2), and only taken once the peer sends the request again echoing it.
+//! Each Echo value is used once, older requests of that ID context are
+//! refused from then on.
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+
+use aes::Aes128;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::{Aead, KeyInit, Payload};
+use ccm::consts::{U13, U8};
+use ccm::Ccm;
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType, ResponseType};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+/// AES-CCM-16-64-128 in COSE terms
+const ALGORITHM: u8 = 10;
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+
+/// Longest sender id that fits in a nonce
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+
+/// Sequence numbers are at most 40 bits long
+const MAX_SEQUENCE: u64 = (1 << 40) - 1;
+
+/// Length of the random ID context of each side
+const ID_CONTEXT_LEN: usize = 8;
+
+/// Length of the Echo values we ask peers for
+const ECHO_LEN: usize = 8;
+
+/// How many ID contexts of the peer are remembered to refuse them
+const MAX_OLD_CONTEXTS: usize = 8;
+
+/// Salt used for every VAP context
+const MASTER_SALT: &[u8] = b"vap";
+
+/// Sender id of the register
+pub const REGISTER_ID: &[u8] = b"";
+
+const URI_PATH: u16 = 11;
+const URI_QUERY: u16 = 15;
+
+/// The Echo option (RFC 9175), encrypted like the path and the query
+pub const ECHO: u16 = 252;
+
+type AesCcm = Ccm<Aes128, U8, U13>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The message has no OSCORE option
+    NotProtected,
+    /// Nobody with this id shares a secret with us
+    UnknownSender,
+    /// The message was received before
+    Replay,
+    /// The message was tampered with or uses another key
+    Decrypt,
+    /// The protected message couldn't be decoded
+    Malformed,
+    /// Every sequence number was used, a new secret is needed
+    SequenceExhausted,
+    /// The request is authentic but might be an old one, it must be sent
+    /// again with the Echo option of [`SecurityContext::challenge`]
+    Unverified(RequestBinding),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Error::NotProtected => "the message is not protected",
+            Error::UnknownSender => "the sender is not known",
+            Error::Replay => "the message was already received",
+            Error::Decrypt => "the message couldn't be decrypted",
+            Error::Malformed => "the protected message is malformed",
+            Error::SequenceExhausted => "no sequence numbers are left",
+            Error::Unverified(_) => "the request might be an old one",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The sender id of a skill: the first bytes of the SHA-256 of its id
+pub fn sender_id(skill_id: &str) -> Vec<u8> {
+    Sha256::digest(skill_id.as_bytes())[..MAX_ID_LEN].to_vec()
+}
+
+/// The id of the sender of a protected request, `None` if it has none
+pub fn kid(packet: &Packet) -> Result<Option<Vec<u8>>, Error> {
+    Ok(OscoreOption::from_packet(packet)?.kid)
+}
+
+/// Identifies a request: the id of its sender and its sequence number.
+/// Responses are bound to their request so that they can't be swapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestBinding {
+    pub kid: Vec<u8>,
+    pub partial_iv: Vec<u8>,
+}
+
+/// What we know about the messages of the peer since it last started
+struct Recipient {
+    id_context: Vec<u8>,
+    key: [u8; KEY_LEN],
+    replay: ReplayWindow,
+}
+
+/// Everything needed to talk securely with one peer
+pub struct SecurityContext {
+    hkdf: Hkdf<Sha256>,
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    id_context: [u8; ID_CONTEXT_LEN],
+    sender_key: [u8; KEY_LEN],
+    common_iv: [u8; NONCE_LEN],
+    sequence: u64,
+    /// `None` until the peer sends something
+    recipient: Option<Recipient>,
+    /// The last ID contexts the peer used before the current one
+    old_contexts: VecDeque<Vec<u8>>,
+    /// What requests with a new ID context must echo
+    echo: [u8; ECHO_LEN],
+}
+
+impl SecurityContext {
+    /// Derives the keys for talking with `recipient_id`, both sides must use
+    /// the same secret and salt with the ids swapped. Our sender key is new
+    /// every time, see the module docs.
+    ///
+    /// # Panics
+    /// If the system can't give us random numbers.
+    pub fn new(master_secret: &[u8], master_salt: &[u8], sender_id: &[u8], recipient_id: &[u8]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
+        let mut id_context = [0; ID_CONTEXT_LEN];
+        getrandom::getrandom(&mut id_context).expect("Can't choose an OSCORE ID context without random numbers");
+
+        let mut sender_key = [0; KEY_LEN];
+        let mut common_iv = [0; NONCE_LEN];
+        derive(&hkdf, sender_id, Some(&id_context), "Key", &mut sender_key);
+        derive(&hkdf, &[], None, "IV", &mut common_iv);
+
+        Self {
+            hkdf,
+            sender_id: sender_id.to_vec(),
+            recipient_id: recipient_id.to_vec(),
+            id_context,
+            sender_key,
+            common_iv,
+            sequence: 0,
+            recipient: None,
+            old_contexts: VecDeque::new(),
+            echo: new_echo(),
+        }
+    }
+
+    /// The context a skill uses to talk to the register
+    pub fn for_skill(skill_id: &str, master_secret: &[u8]) -> Self {
+        Self::new(master_secret, MASTER_SALT, &sender_id(skill_id), REGISTER_ID)
+    }
+
+    /// The context the register uses to talk to a skill
+    pub fn for_register(skill_id: &str, master_secret: &[u8]) -> Self {
+        Self::new(master_secret, MASTER_SALT, REGISTER_ID, &sender_id(skill_id))
+    }
+
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.recipient_id
+    }
+
+    /// Encrypts the packet in place. Requests are sent with `request` set to
+    /// `None`, responses and notifications with the request they answer.
+    /// Returns what the response to this message must be bound to.
+    pub fn protect(&mut self, packet: &mut Packet, request: Option<&RequestBinding>) -> Result<RequestBinding, Error> {
+        let is_observe = packet.get_option(CoapOption::Observe).is_some();
+        let mut options = vec![];
+        for (number, option) in [
+            (URI_PATH, CoapOption::UriPath),
+            (URI_QUERY, CoapOption::UriQuery),
+            (ECHO, CoapOption::Unknown(ECHO)),
+        ] {
+            if let Some(values) = packet.get_option(option) {
+                options.extend(values.iter().map(|v| (number, v.clone())));
+            }
+            packet.clear_option(option);
+        }
+
+        let plaintext = encode_plaintext(u8::from(packet.header.code), &options, &packet.payload);
+        let (option, ciphertext, own) = self.seal(&plaintext, request)?;
+
+        packet.add_option(CoapOption::Oscore, option);
+        packet.header.code = match (request, is_observe) {
+            (None, false) => MessageClass::Request(RequestType::Post),
+            (None, true) => MessageClass::Request(RequestType::Fetch),
+            (Some(_), false) => MessageClass::Response(ResponseType::Changed),
+            (Some(_), true) => MessageClass::Response(ResponseType::Content),
+        };
+        packet.payload = ciphertext;
+
+        Ok(own)
+    }
+
+    /// Decrypts the packet in place. Requests are received with `request` set
+    /// to `None`, responses and notifications with the request they answer.
+    /// Returns what the response to this message must be bound to.
+    pub fn unprotect(&mut self, packet: &mut Packet, request: Option<&RequestBinding>) -> Result<RequestBinding, Error> {
+        let option = OscoreOption::from_packet(packet)?;
+        let plaintext = self.open(&option, &packet.payload, request)?;
+        let (code, options, payload) = decode_plaintext(&plaintext)?;
+
+        packet.clear_option(CoapOption::Oscore);
+        packet.header.code = MessageClass::from(code);
+        for (number, value) in options {
+            let option = match number {
+                URI_PATH => CoapOption::UriPath,
+                URI_QUERY => CoapOption::UriQuery,
+                ECHO => CoapOption::Unknown(ECHO),
+                _ => return Err(Error::Malformed),
+            };
+            packet.add_option(option, value);
+        }
+        packet.payload = payload;
+
+        Ok(RequestBinding {
+            kid: self.recipient_id.clone(),
+            partial_iv: option.partial_iv,
+        })
+    }
+
+    /// Turns `packet` into the answer to a request that failed with
+    /// [`Error::Unverified`]: a protected 4.01 with the Echo the peer must
+    /// send back.
+    pub fn challenge(&mut self, packet: &mut Packet, request: &RequestBinding) -> Result<(), Error> {
+        packet.header.code = MessageClass::Response(ResponseType::Unauthorized);
+        packet.add_option(CoapOption::Unknown(ECHO), self.echo.to_vec());
+        self.protect(packet, Some(request))?;
+        Ok(())
+    }
+
+    /// Encrypts `plaintext`, returns the value of the OSCORE option, the
+    /// ciphertext and the binding of this message.
+    fn seal(&mut self, plaintext: &[u8], request: Option<&RequestBinding>) -> Result<(Vec<u8>, Vec<u8>, RequestBinding), Error> {
+        if self.sequence > MAX_SEQUENCE {
+            return Err(Error::SequenceExhausted);
+        }
+        let partial_iv = encode_partial_iv(self.sequence);
+        self.sequence += 1;
+
+        let own = RequestBinding {
+            kid: self.sender_id.clone(),
+            partial_iv,
+        };
+        // Requests are bound to themselves
+        let aad = aad(request.unwrap_or(&own));
+        let nonce = nonce(&self.sender_id, &own.partial_iv, &self.common_iv);
+        let ciphertext = AesCcm::new(GenericArray::from_slice(&self.sender_key))
+            .encrypt(GenericArray::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+            .map_err(|_| Error::Malformed)?;
+
+        let option = OscoreOption {
+            partial_iv: own.partial_iv.clone(),
+            kid_context: Some(self.id_context.to_vec()),
+            // Only requests say who sent them
+            kid: request.is_none().then(|| self.sender_id.clone()),
+        };
+
+        Ok((option.encode(), ciphertext, own))
+    }
+
+    fn open(&mut self, option: &OscoreOption, ciphertext: &[u8], request: Option<&RequestBinding>) -> Result<Vec<u8>, Error> {
+        if request.is_none() && option.kid.as_deref() != Some(&self.recipient_id[..]) {
+            return Err(Error::UnknownSender);
+        }
+        let id_context = match &option.kid_context {
+            Some(id_context) if !option.partial_iv.is_empty() => id_context,
+            _ => return Err(Error::Malformed),
+        };
+        let sequence = decode_partial_iv(&option.partial_iv)?;
+
+        // A new ID context means that the peer started again
+        let restarted = match &self.recipient {
+            Some(recipient) if recipient.id_context == *id_context => None,
+            _ if self.old_contexts.contains(id_context) => return Err(Error::Replay),
+            _ => {
+                let mut key = [0; KEY_LEN];
+                derive(&self.hkdf, &self.recipient_id, Some(id_context), "Key", &mut key);
+                Some(Recipient {
+                    id_context: id_context.clone(),
+                    key,
+                    replay: ReplayWindow::default(),
+                })
+            }
+        };
+        let recipient = match &restarted {
+            Some(recipient) => recipient,
+            None => self.recipient.as_ref().expect("Checked above, report this"),
+        };
+        if !recipient.replay.is_fresh(sequence) {
+            return Err(Error::Replay);
+        }
+
+        let own = RequestBinding {
+            kid: self.recipient_id.clone(),
+            partial_iv: option.partial_iv.clone(),
+        };
+        let aad = aad(request.unwrap_or(&own));
+        let nonce = nonce(&self.recipient_id, &option.partial_iv, &self.common_iv);
+        let plaintext = AesCcm::new(GenericArray::from_slice(&recipient.key))
+            .decrypt(GenericArray::from_slice(&nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| Error::Decrypt)?;
+
+        // Only authentic messages move the window (or change the keys)
+        if let Some(mut restarted) = restarted {
+            // Requests must prove they were sent after our last challenge,
+            // anything before them could be a recording
+            if request.is_none() {
+                let (_, options, _) = decode_plaintext(&plaintext)?;
+                if !options.iter().any(|(n, v)| *n == ECHO && v[..] == self.echo) {
+                    return Err(Error::Unverified(own));
+                }
+                self.echo = new_echo();
+                restarted.replay = ReplayWindow::starting_at(sequence);
+            }
+            if let Some(old) = self.recipient.replace(restarted) {
+                if self.old_contexts.len() == MAX_OLD_CONTEXTS {
+                    self.old_contexts.pop_front();
+                }
+                self.old_contexts.push_back(old.id_context);
+            }
+        }
+        if let Some(recipient) = &mut self.recipient {
+            recipient.replay.mark(sequence);
+        }
+        Ok(plaintext)
+    }
+}
+
+/// The value of the OSCORE option
+#[derive(Debug, Default, PartialEq)]
+struct OscoreOption {
+    partial_iv: Vec<u8>,
+    kid_context: Option<Vec<u8>>,
+    kid: Option<Vec<u8>>,
+}
+
+impl OscoreOption {
+    fn from_packet(packet: &Packet) -> Result<Self, Error> {
+        let value = packet
+            .get_option(CoapOption::Oscore)
+            .and_then(|values| values.front())
+            .ok_or(Error::NotProtected)?;
+
+        Self::decode(value)
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, Error> {
+        let (flags, mut rest) = match value.split_first() {
+            Some((flags, rest)) => (*flags, rest),
+            None => return Ok(Self::default()),
+        };
+
+        let piv_len = (flags & 0x07) as usize;
+        if rest.len() < piv_len {
+            return Err(Error::Malformed);
+        }
+        let (partial_iv, after) = rest.split_at(piv_len);
+        rest = after;
+
+        let mut kid_context = None;
+        if flags & 0x10 != 0 {
+            let (len, after) = rest.split_first().ok_or(Error::Malformed)?;
+            let len = *len as usize;
+            if after.len() < len {
+                return Err(Error::Malformed);
+            }
+            let (context, after) = after.split_at(len);
+            kid_context = Some(context.to_vec());
+            rest = after;
+        }
+
+        Ok(Self {
+            partial_iv: partial_iv.to_vec(),
+            kid_context,
+            kid: (flags & 0x08 != 0).then(|| rest.to_vec()),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = self.partial_iv.len() as u8;
+        if self.kid.is_some() {
+            flags |= 0x08;
+        }
+        if self.kid_context.is_some() {
+            flags |= 0x10;
+        }
+        if flags == 0 {
+            return vec![];
+        }
+
+        let mut value = vec![flags];
+        value.extend_from_slice(&self.partial_iv);
+        if let Some(kid_context) = &self.kid_context {
+            value.push(kid_context.len() as u8);
+            value.extend_from_slice(kid_context);
+        }
+        if let Some(kid) = &self.kid {
+            value.extend_from_slice(kid);
+        }
+        value
+    }
+}
+
+/// Remembers which sequence numbers were received lately
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit n is set if `highest - n` was received
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// A window where nothing up to `sequence` is fresh
+    fn starting_at(sequence: u64) -> Self {
+        Self {
+            highest: Some(sequence),
+            seen: u64::MAX,
+        }
+    }
+
+    fn is_fresh(&self, sequence: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if sequence > highest => true,
+            Some(highest) => {
+                let age = highest - sequence;
+                age < 64 && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn mark(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence <= highest => self.seen |= 1 << (highest - sequence),
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.seen = if shift >= 64 { 0 } else { self.seen << shift } | 1;
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+/// A random Echo value
+///
+/// # Panics
+/// If the system can't give us random numbers.
+fn new_echo() -> [u8; ECHO_LEN] {
+    let mut echo = [0; ECHO_LEN];
+    getrandom::getrandom(&mut echo).expect("Can't choose an Echo value without random numbers");
+    echo
+}
+
+fn encode_partial_iv(sequence: u64) -> Vec<u8> {
+    let bytes = sequence.to_be_bytes();
+    let first = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len() - 1);
+    bytes[first..].to_vec()
+}
+
+fn decode_partial_iv(partial_iv: &[u8]) -> Result<u64, Error> {
+    if partial_iv.len() > 5 {
+        return Err(Error::Malformed);
+    }
+    Ok(partial_iv.iter().fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+}
+
+fn nonce(id: &[u8], partial_iv: &[u8], common_iv: &[u8; NONCE_LEN]) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[0] = id.len() as u8;
+    nonce[1 + MAX_ID_LEN - id.len()..1 + MAX_ID_LEN].copy_from_slice(id);
+    nonce[NONCE_LEN - partial_iv.len()..].copy_from_slice(partial_iv);
+    for (n, iv) in nonce.iter_mut().zip(common_iv) {
+        *n ^= iv;
+    }
+    nonce
+}
+
+/// A CBOR byte string, with the shortest header for its length
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    let len = bytes.len();
+    if len < 24 {
+        out.push(0x40 | len as u8);
+    } else if let Ok(len) = u8::try_from(len) {
+        out.push(0x58);
+        out.push(len);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0x59);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(0x5a);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x5b);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    out.extend_from_slice(bytes);
+}
+
+/// Fills `out` with the key or IV for `id`
+fn derive(hkdf: &Hkdf<Sha256>, id: &[u8], id_context: Option<&[u8]>, type_: &str, out: &mut [u8]) {
+    hkdf.expand(&kdf_info(id, id_context, type_, out.len()), out)
+        .expect("HKDF output is always short enough, report this");
+}
+
+/// `[id, id_context, alg_aead, type, L]` in CBOR
+fn kdf_info(id: &[u8], id_context: Option<&[u8]>, type_: &str, len: usize) -> Vec<u8> {
+    let mut info = vec![0x85];
+    cbor_bytes(&mut info, id);
+    match id_context {
+        Some(id_context) => cbor_bytes(&mut info, id_context),
+        None => info.push(0xf6),
+    }
+    info.push(ALGORITHM);
+    info.push(0x60 | type_.len() as u8);
+    info.extend_from_slice(type_.as_bytes());
+    info.push(len as u8);
+    info
+}
+
+/// The COSE Encrypt0 structure with the OSCORE external AAD
+fn aad(request: &RequestBinding) -> Vec<u8> {
+    let mut external = vec![0x85, 0x01, 0x81, ALGORITHM];
+    cbor_bytes(&mut external, &request.kid);
+    cbor_bytes(&mut external, &request.partial_iv);
+    cbor_bytes(&mut external, &[]);
+
+    let mut aad = vec![0x83, 0x68];
+    aad.extend_from_slice(b"Encrypt0");
+    cbor_bytes(&mut aad, &[]);
+    cbor_bytes(&mut aad, &external);
+    aad
+}
+
+/// The code, the options (already sorted) and the payload like in a CoAP message
+fn encode_plaintext(code: u8, options: &[(u16, Vec<u8>)], payload: &[u8]) -> Vec<u8> {
+    fn nibble(value: usize, extended: &mut Vec<u8>) -> u8 {
+        match value {
+            0..=12 => value as u8,
+            13..=268 => {
+                extended.push((value - 13) as u8);
+                13
+            }
+            _ => {
+                extended.extend_from_slice(&((value - 269) as u16).to_be_bytes());
+                14
+            }
+        }
+    }
+
+    let mut plaintext = vec![code];
+    let mut last = 0;
+    for (number, value) in options {
+        let mut extended = vec![];
+        let delta = nibble((number - last) as usize, &mut extended);
+        let len = nibble(value.len(), &mut extended);
+        plaintext.push(delta << 4 | len);
+        plaintext.extend(extended);
+        plaintext.extend_from_slice(value);
+        last = *number;
+    }
+    if !payload.is_empty() {
+        plaintext.push(0xFF);
+        plaintext.extend_from_slice(payload);
+    }
+    plaintext
+}
+
+#[allow(clippy::type_complexity)]
+fn decode_plaintext(plaintext: &[u8]) -> Result<(u8, Vec<(u16, Vec<u8>)>, Vec<u8>), Error> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+        if data.len() < n {
+            return Err(Error::Malformed);
+        }
+        let (taken, rest) = data.split_at(n);
+        *data = rest;
+        Ok(taken)
+    }
+
+    fn extended(nibble: u8, data: &mut &[u8]) -> Result<usize, Error> {
+        Ok(match nibble {
+            0..=12 => nibble as usize,
+            13 => take(data, 1)?[0] as usize + 13,
+            14 => {
+                let b = take(data, 2)?;
+                u16::from_be_bytes([b[0], b[1]]) as usize + 269
+            }
+            _ => return Err(Error::Malformed),
+        })
+    }
+
+    let mut data = plaintext;
+    let code = take(&mut data, 1)?[0];
+    let mut options = vec![];
+    let mut number = 0u16;
+    while let Some(&byte) = data.first() {
+        data = &data[1..];
+        if byte == 0xFF {
+            return Ok((code, options, data.to_vec()));
+        }
+
+        let delta = extended(byte >> 4, &mut data)?;
+        let len = extended(byte & 0x0F, &mut data)?;
+        number = number.checked_add(delta as u16).ok_or(Error::Malformed)?;
+        options.push((number, take(&mut data, len)?.to_vec()));
+    }
+
+    Ok((code, options, vec![]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"a secret shared over loopback";
+
+    /// Seals a request for the register, echoing `echo` if given
+    fn request(skill: &mut SecurityContext, echo: Option<&[u8]>, payload: &[u8]) -> (OscoreOption, Vec<u8>, RequestBinding) {
+        let options: Vec<_> = echo.map(|e| (ECHO, e.to_vec())).into_iter().collect();
+        let (option, ciphertext, sent) = skill.seal(&encode_plaintext(2, &options, payload), None).unwrap();
+        (OscoreOption::decode(&option).unwrap(), ciphertext, sent)
+    }
+
+    /// Gets the register to accept the current ID context of the skill
+    fn verify(skill: &mut SecurityContext, register: &mut SecurityContext) {
+        let (option, ciphertext, _) = request(skill, None, b"connect");
+        assert!(matches!(register.open(&option, &ciphertext, None), Err(Error::Unverified(_))));
+        let echo = register.echo;
+        let (option, ciphertext, _) = request(skill, Some(&echo), b"connect");
+        register.open(&option, &ciphertext, None).unwrap();
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut skill = SecurityContext::for_skill("com.example.test", SECRET);
+        let mut register = SecurityContext::for_register("com.example.test", SECRET);
+        verify(&mut skill, &mut register);
+
+        let (option, ciphertext, sent) = request(&mut skill, None, b"query");
+        assert_eq!(option.kid, Some(sender_id("com.example.test")));
+        let (_, _, payload) = decode_plaintext(&register.open(&option, &ciphertext, None).unwrap()).unwrap();
+        assert_eq!(payload, b"query");
+
+        // The same message can't be accepted twice
+        assert_eq!(register.open(&option, &ciphertext, None), Err(Error::Replay));
+
+        // Answers are bound to their request
+        let (option, ciphertext, _) = register.seal(b"created", Some(&sent)).unwrap();
+        let option = OscoreOption::decode(&option).unwrap();
+        assert_eq!(option.kid, None);
+        let wrong = RequestBinding {
+            partial_iv: vec![7],
+            ..sent.clone()
+        };
+        assert_eq!(skill.open(&option, &ciphertext, Some(&wrong)), Err(Error::Decrypt));
+        assert_eq!(skill.open(&option, &ciphertext, Some(&sent)).unwrap(), b"created");
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let mut skill = SecurityContext::for_skill("com.example.test", b"another secret");
+        let mut register = SecurityContext::for_register("com.example.test", SECRET);
+        let mut other = SecurityContext::for_register("com.example.other", SECRET);
+
+        let (option, ciphertext, _) = request(&mut skill, None, b"connect");
+        assert_eq!(other.open(&option, &ciphertext, None), Err(Error::UnknownSender));
+        assert_eq!(register.open(&option, &ciphertext, None), Err(Error::Decrypt));
+    }
+
+    #[test]
+    fn new_contexts_echo_a_challenge() {
+        let mut skill = SecurityContext::for_skill("com.example.test", SECRET);
+        let mut register = SecurityContext::for_register("com.example.test", SECRET);
+        let (recorded, recorded_ciphertext, _) = request(&mut skill, None, b"connect");
+
+        // The first request is answered with a protected 4.01 and an Echo
+        let binding = match register.open(&recorded, &recorded_ciphertext, None) {
+            Err(Error::Unverified(binding)) => binding,
+            r => panic!("Unexpected result: {:?}", r),
+        };
+        let mut answer = Packet::new();
+        register.challenge(&mut answer, &binding).unwrap();
+        skill.unprotect(&mut answer, Some(&binding)).unwrap();
+        assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Unauthorized));
+        let echo = answer.get_first_option(CoapOption::Unknown(ECHO)).unwrap().clone();
+
+        // A wrong Echo isn't enough
+        let (option, ciphertext, _) = request(&mut skill, Some(b"guessed!"), b"connect");
+        assert!(matches!(register.open(&option, &ciphertext, None), Err(Error::Unverified(_))));
+        let (option, ciphertext, _) = request(&mut skill, Some(&echo), b"connect");
+        register.open(&option, &ciphertext, None).unwrap();
+
+        // Requests sent before the Echo can't be taken anymore, and the Echo
+        // can't be used again
+        assert_eq!(register.open(&recorded, &recorded_ciphertext, None), Err(Error::Replay));
+        let mut restarted = SecurityContext::for_register("com.example.test", SECRET);
+        assert!(matches!(restarted.open(&recorded, &recorded_ciphertext, None), Err(Error::Unverified(_))));
+        let (option, ciphertext, _) = request(&mut skill, Some(&echo), b"connect");
+        assert!(matches!(restarted.open(&option, &ciphertext, None), Err(Error::Unverified(_))));
+    }
+
+    #[test]
+    fn restarts_use_new_keys() {
+        let mut skill = SecurityContext::for_skill("com.example.test", SECRET);
+        let mut register = SecurityContext::for_register("com.example.test", SECRET);
+        verify(&mut skill, &mut register);
+        let (before, ciphertext_before, _) = request(&mut skill, None, b"query");
+        register.open(&before, &ciphertext_before, None).unwrap();
+
+        // The sequence starts again, the key doesn't
+        let mut skill = SecurityContext::for_skill("com.example.test", SECRET);
+        let (after, ciphertext_after, _) = request(&mut skill, None, b"connect");
+        assert_eq!(after.partial_iv, [0]);
+        assert_ne!(before.kid_context, after.kid_context);
+        assert!(matches!(register.open(&after, &ciphertext_after, None), Err(Error::Unverified(_))));
+        let echo = register.echo;
+        let (option, ciphertext, _) = request(&mut skill, Some(&echo), b"connect");
+        register.open(&option, &ciphertext, None).unwrap();
+
+        // Messages from before the restart can't be sent again
+        assert_eq!(register.open(&before, &ciphertext_before, None), Err(Error::Replay));
+        let (option, ciphertext, _) = request(&mut skill, None, b"query");
+        register.open(&option, &ciphertext, None).unwrap();
+    }
+
+    #[test]
+    fn old_contexts_are_capped() {
+        let mut register = SecurityContext::for_register("com.example.test", SECRET);
+        for _ in 0..MAX_OLD_CONTEXTS + 2 {
+            verify(&mut SecurityContext::for_skill("com.example.test", SECRET), &mut register);
+        }
+        assert_eq!(register.old_contexts.len(), MAX_OLD_CONTEXTS);
+    }
+
+    #[test]
+    fn long_cbor_bytes() {
+        let mut out = vec![];
+        cbor_bytes(&mut out, &[7; 300]);
+        assert_eq!(out[..3], [0x59, 0x01, 0x2c]);
+        assert_eq!(out.len(), 303);
+
+        let mut out = vec![];
+        cbor_bytes(&mut out, &[7; 70_000]);
+        assert_eq!(out[..5], [0x5a, 0x00, 0x01, 0x11, 0x70]);
+        assert_eq!(out.len(), 70_005);
+    }
+
+    #[test]
+    fn plaintext_roundtrip() {
+        let options = vec![
+            (URI_PATH, b"vap".to_vec()),
+            (URI_PATH, b"skillRegistry".to_vec()),
+            (URI_QUERY, vec![b'a'; 300]),
+        ];
+        let plaintext = encode_plaintext(2, &options, b"payload");
+        assert_eq!(decode_plaintext(&plaintext).unwrap(), (2, options, b"payload".to_vec()));
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        window.mark(5);
+        window.mark(3);
+        assert!(!window.is_fresh(5));
+        assert!(!window.is_fresh(3));
+        assert!(window.is_fresh(4));
+        window.mark(100);
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(99));
+    }
+}
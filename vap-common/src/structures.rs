@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+use unic_langid::LanguageIdentifier;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Language {
+    // Better this or a single string?
+    /// The country code of the language
+    pub country: Option<String>,
+
+    /// The language code
+    pub language: String,
+
+    /// The extra code for the language
+    pub extra: Option<String>, // is this necessary?
+}
+
+impl From<LanguageIdentifier> for Language {
+    fn from(l: LanguageIdentifier) -> Self {
+        Language {
+            country: l.region.map(|r| r.to_string()),
+            language: l.language.to_string(),
+            extra: l.script.map(|s| s.to_string()),
+        }
+    }
+}
+
+impl From<Language> for LanguageIdentifier {
+    fn from(lang: Language) -> Self {
+        LanguageIdentifier::from_parts(
+            lang.language.parse().unwrap(),
+            lang.extra.and_then(|e| e.parse().ok()),
+            lang.country.and_then(|c| c.parse().ok()),
+            &[],
+        )
+    }
+}
+
+/// A structure describing Capability data
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PlainCapability {
+    pub name: String,
+
+    /// Version of the data, see [`crate::capability`]
+    #[serde(default = "crate::capability::default_version")]
+    pub version: u16,
+
+    #[serde(flatten)]
+    pub cap_data: AssociativeMap,
+}
+
+pub type AssociativeMap = HashMap<Value, Value>;
+
+/// Used as variant for the capabilities data. Represents all types in MsgPack
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Binary(Vec<u8>),
+    Array(Vec<Value>),
+    Map(HashMap<Value, Value>),
+    // Timestamp // TODO! Finish this type
+}
+
+/// PartialEq implementation. For floating point instead of direct equality, we
+/// we make sure they are close enough (because of how computers treat decimals).
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
+            (Self::I8(l0), Self::I8(r0)) => l0 == r0,
+            (Self::U8(l0), Self::U8(r0)) => l0 == r0,
+            (Self::I16(l0), Self::I16(r0)) => l0 == r0,
+            (Self::U16(l0), Self::U16(r0)) => l0 == r0,
+            (Self::I32(l0), Self::I32(r0)) => l0 == r0,
+            (Self::U32(l0), Self::U32(r0)) => l0 == r0,
+            (Self::I64(l0), Self::I64(r0)) => l0 == r0,
+            (Self::U64(l0), Self::U64(r0)) => l0 == r0,
+            (Self::F32(l0), Self::F32(r0)) => (l0 - r0) < f32::EPSILON,
+            (Self::F64(l0), Self::F64(r0)) => (l0 - r0) < f64::EPSILON,
+            (Self::String(l0), Self::String(r0)) => l0 == r0,
+            (Self::Binary(l0), Self::Binary(r0)) => l0 == r0,
+            (Self::Array(l0), Self::Array(r0)) => l0 == r0,
+            (Self::Map(l0), Self::Map(r0)) => l0 == r0,
+            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+        }
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        core::mem::discriminant(self).hash(state);
+    }
+}
+
+impl Eq for Value {}
+
+impl Display for Value {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write_vec<D: Display>(
+            v: &[D],
+            fmt: &mut std::fmt::Formatter,
+        ) -> Result<(), std::fmt::Error> {
+            fmt.write_char('[')?;
+            let mut it = v.iter().peekable();
+            while let Some(val) = it.next() {
+                if it.peek().is_some() {
+                    fmt.write_fmt(format_args!("{}, ", &val.to_string()))?;
+                } else {
+                    fmt.write_str(&val.to_string())?;
+                }
+            }
+
+            fmt.write_char(']')?;
+
+            Ok(())
+        }
+
+        fn write_map<D1: Display, D2: Display>(
+            m: &HashMap<D1, D2>,
+            fmt: &mut std::fmt::Formatter,
+        ) -> Result<(), std::fmt::Error> {
+            fmt.write_char('{')?;
+            let mut it = m.iter().peekable();
+            while let Some((k, v)) = it.next() {
+                if it.peek().is_some() {
+                    fmt.write_fmt(format_args!("{}: {}, ", k, v))?;
+                } else {
+                    fmt.write_fmt(format_args!("{}: {}", k, v))?;
+                }
+            }
+            fmt.write_char('}')?;
+
+            Ok(())
+        }
+
+        match self {
+            Value::Nil => fmt.write_str("Nil"),
+            Value::Bool(b) => fmt.write_str(&b.to_string()),
+            Value::I8(i) => fmt.write_str(&i.to_string()),
+            Value::U8(u) => fmt.write_str(&u.to_string()),
+            Value::I16(i) => fmt.write_str(&i.to_string()),
+            Value::U16(u) => fmt.write_str(&u.to_string()),
+            Value::I32(i) => fmt.write_str(&i.to_string()),
+            Value::U32(u) => fmt.write_str(&u.to_string()),
+            Value::I64(i) => fmt.write_str(&i.to_string()),
+            Value::U64(u) => fmt.write_str(&u.to_string()),
+            Value::F32(f) => fmt.write_str(&f.to_string()),
+            Value::F64(f) => fmt.write_str(&f.to_string()),
+            Value::String(str) => fmt.write_str(str),
+            Value::Binary(b) => write_vec(b, fmt),
+            Value::Array(a) => write_vec(a, fmt),
+            Value::Map(m) => write_map(m, fmt),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+/// The body of any error answer, as described in GENERAL.MD
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VapError {
+    /// Same CoAP code as the answer (e.g: 404)
+    pub code: u16,
+
+    /// An identifier of what happened
+    #[serde(rename = "type")]
+    pub type_: String,
+
+    /// What caused the error, not every error has one
+    #[serde(default)]
+    pub object: Option<String>,
+
+    /// Some online documentation about the error
+    #[serde(rename = "docRef", default)]
+    pub doc_ref: Option<String>,
+}
+
+/// The identifiers used in the `type` field of [`VapError`]
+pub mod error_types {
+    pub const NOT_FOUND: &str = "not found";
+    pub const MALFORMED_CONTENT: &str = "malformed content";
+    pub const MISSING_FIELD: &str = "missing field";
+    pub const METHOD_NOT_ALLOWED: &str = "method not allowed";
+    pub const INCOMPATIBLE_VERSION: &str = "vapVersion incompatible";
+    pub const CONNECTION_DENIED: &str = "connectionDenied";
+    pub const WRONG_SKILL_ID: &str = "wrong skillId";
+    pub const WRONG_CLIENT_ID: &str = "wrong clientId";
+    pub const UNAUTHORIZED: &str = "unauthorized";
+    pub const MISSING_ENTITY: &str = "missing entity";
+    pub const MISSING_SLOT: &str = "missing slot";
+    pub const DUPLICATED: &str = "duplicated";
+    pub const INVALID_ENTITY: &str = "invalid entity";
+    pub const MISMATCHED_INTENT: &str = "mismatched intent";
+    pub const UNKNOWN_REQUEST: &str = "unknown request";
+    pub const INTERNAL: &str = "internal error";
+}
+
+impl VapError {
+    pub fn new<S: Into<String>>(code: u16, type_: S, object: Option<String>) -> Self {
+        Self {
+            code,
+            type_: type_.into(),
+            object,
+            doc_ref: None,
+        }
+    }
+
+    /// Something required (most probably a path) was not found
+    pub fn not_found<S: Into<String>>(object: S) -> Self {
+        Self::new(404, error_types::NOT_FOUND, Some(object.into()))
+    }
+
+    /// The data received is not valid MsgPack
+    pub fn malformed_content() -> Self {
+        Self::new(400, error_types::MALFORMED_CONTENT, None)
+    }
+
+    /// The message lacks a mandatory field
+    pub fn missing_field(field: Option<String>) -> Self {
+        Self::new(408, error_types::MISSING_FIELD, field)
+    }
+
+    pub fn method_not_allowed() -> Self {
+        Self::new(405, error_types::METHOD_NOT_ALLOWED, None)
+    }
+
+    pub fn incompatible_version<S: Into<String>>(version: S) -> Self {
+        Self::new(400, error_types::INCOMPATIBLE_VERSION, Some(version.into()))
+    }
+
+    pub fn connection_denied() -> Self {
+        Self::new(401, error_types::CONNECTION_DENIED, None)
+    }
+
+    pub fn wrong_skill_id<S: Into<String>>(id: S) -> Self {
+        Self::new(400, error_types::WRONG_SKILL_ID, Some(id.into()))
+    }
+
+    pub fn wrong_client_id<S: Into<String>>(id: S) -> Self {
+        Self::new(400, error_types::WRONG_CLIENT_ID, Some(id.into()))
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(401, error_types::UNAUTHORIZED, None)
+    }
+
+    /// A slot uses an entity that was not registered
+    pub fn missing_entity<S: Into<String>>(object: S) -> Self {
+        Self::new(408, error_types::MISSING_ENTITY, Some(object.into()))
+    }
+
+    /// An utterance uses a slot that was not declared
+    pub fn missing_slot<S: Into<String>>(object: S) -> Self {
+        Self::new(408, error_types::MISSING_SLOT, Some(object.into()))
+    }
+
+    /// Something that must be unique (e.g: an intent name) was found twice
+    pub fn duplicated<S: Into<String>>(object: S) -> Self {
+        Self::new(400, error_types::DUPLICATED, Some(object.into()))
+    }
+
+    /// An entity can't be used as defined (e.g: a range with `min > max`)
+    pub fn invalid_entity<S: Into<String>>(object: S) -> Self {
+        Self::new(400, error_types::INVALID_ENTITY, Some(object.into()))
+    }
+
+    /// An intent has other scopes or slots than in a previous language
+    pub fn mismatched_intent<S: Into<String>>(object: S) -> Self {
+        Self::new(400, error_types::MISMATCHED_INTENT, Some(object.into()))
+    }
+
+    /// The request doesn't exist (anymore) or was sent to another skill
+    pub fn unknown_request<S: Into<String>>(request_id: S) -> Self {
+        Self::new(402, error_types::UNKNOWN_REQUEST, Some(request_id.into()))
+    }
+
+    pub fn internal() -> Self {
+        Self::new(500, error_types::INTERNAL, None)
+    }
+}
+
+impl Display for VapError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(fmt, "{} ({})", self.type_, self.code)?;
+        if let Some(object) = &self.object {
+            write!(fmt, ": {}", object)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_roundtrip() {
+        let id: LanguageIdentifier = "en-US".parse().unwrap();
+        let lang: Language = id.clone().into();
+        assert_eq!(lang.language, "en");
+        assert_eq!(lang.country.as_deref(), Some("US"));
+
+        let back: LanguageIdentifier = lang.into();
+        assert_eq!(back, id);
+    }
+}
@@ -0,0 +1,140 @@
+//! Versions of the VAP protocol. Each side supports a range of versions and
+//! they agree on the newest one both of them know.
+
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// A version of the protocol, sent as "major.minor"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    /// The first version, sent as "Alpha" since that's the only thing older
+    /// peers understand.
+    pub const ALPHA: Version = Version::new(0, 1);
+
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+}
+
+/// The newest version implemented by this crate
+pub const CURRENT: Version = Version::ALPHA;
+
+/// Every version implemented by this crate
+pub const SUPPORTED: VersionRange = VersionRange::new(Version::ALPHA, CURRENT);
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Version::ALPHA {
+            f.write_str("Alpha")
+        } else {
+            write!(f, "{}.{}", self.major, self.minor)
+        }
+    }
+}
+
+/// The text is not a version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseVersionError(pub String);
+
+impl fmt::Display for ParseVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} is not a VAP version", self.0)
+    }
+}
+
+impl std::error::Error for ParseVersionError {}
+
+impl FromStr for Version {
+    type Err = ParseVersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("alpha") {
+            return Ok(Version::ALPHA);
+        }
+
+        let error = || ParseVersionError(s.to_string());
+        let (major, minor) = s.split_once('.').unwrap_or((s, "0"));
+        Ok(Version {
+            major: major.parse().map_err(|_| error())?,
+            minor: minor.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = ParseVersionError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Version> for String {
+    fn from(v: Version) -> Self {
+        v.to_string()
+    }
+}
+
+/// The oldest and newest versions some side can talk, both included
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VersionRange {
+    pub min: Version,
+    pub max: Version,
+}
+
+impl VersionRange {
+    pub const fn new(min: Version, max: Version) -> Self {
+        Self { min, max }
+    }
+
+    /// Just one version, what peers that don't negotiate support
+    pub const fn exactly(version: Version) -> Self {
+        Self::new(version, version)
+    }
+
+    pub fn contains(&self, version: Version) -> bool {
+        self.min <= version && version <= self.max
+    }
+
+    /// The newest version that both ranges contain, if any
+    pub fn negotiate(&self, other: &VersionRange) -> Option<Version> {
+        let newest = min(self.max, other.max);
+        if newest >= max(self.min, other.min) {
+            Some(newest)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!("Alpha".parse(), Ok(Version::ALPHA));
+        assert_eq!(Version::ALPHA.to_string(), "Alpha");
+        assert_eq!("1.2".parse(), Ok(Version::new(1, 2)));
+        assert_eq!("2".parse(), Ok(Version::new(2, 0)));
+        assert!("Beta".parse::<Version>().is_err());
+
+        let old = VersionRange::exactly(Version::ALPHA);
+        let new = VersionRange::new(Version::ALPHA, Version::new(1, 1));
+        let newer = VersionRange::new(Version::new(1, 0), Version::new(2, 0));
+        assert_eq!(old.negotiate(&new), Some(Version::ALPHA));
+        assert_eq!(new.negotiate(&newer), Some(Version::new(1, 1)));
+        assert_eq!(newer.negotiate(&new), Some(Version::new(1, 1)));
+        assert_eq!(old.negotiate(&newer), None);
+    }
+}
@@ -3,6 +3,7 @@
 # Minimum python 3.6
 
 import asyncio
+import os
 import re
 from typing import Optional
 
@@ -13,6 +14,7 @@ registry_address = "127.0.0.1"
 all_coaps_ip4 = "224.0.1.187"
 skill_id = "com.example.test"
 coap_host_reg = re.compile(r'^coap:\/\/([1-9.a-zA-Z]+)\/')
+token_path = os.path.expanduser(f"~/.local/share/vap/{skill_id}.token")
 
 def list_caps(payload):
     """Transform a list of capabilities into a string."""
@@ -26,6 +28,12 @@ class VapClient():
 
         self.client = await aiocoap.Context.create_client_context()
 
+        # The registry gives us a token the first time, it goes in every message
+        self.token = None
+        if os.path.exists(token_path):
+            with open(token_path) as f:
+                self.token = f.read().strip() or None
+
     async def __find_registry(self) -> Optional[str]:
         """ Find a registry using CoAP's discovery """
         request = aiocoap.Message(code=aiocoap.GET, uri=f'coap://{all_coaps_ip4}/.well-known/core?rt=vap-skill-registry')
@@ -48,6 +56,8 @@ class VapClient():
             "id": skill_id,
             "vapVersion": "Alpha"
         }
+        if self.token is not None:
+            payload["uniqueAuthenticationToken"] = self.token
 
         # Create message
         request = aiocoap.Message(code=aiocoap.POST, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/connect')
@@ -60,7 +70,19 @@ class VapClient():
             raise Exception(f"Failed to register skill: {response.code}")
         
         resp_payload = msgpack.unpackb(response.payload)
-        langs_id = 0
+        if isinstance(resp_payload, dict):
+            langs = resp_payload["langs"]
+            new_token = resp_payload.get("uniqueAuthenticationToken")
+        else:
+            langs = resp_payload[0]
+            new_token = resp_payload[1] if len(resp_payload) > 1 else None
+
+        if new_token is not None:
+            self.token = new_token
+            os.makedirs(os.path.dirname(token_path), exist_ok=True)
+            with open(token_path, "w") as f:
+                f.write(new_token)
+
         def lang_to_str(lang):
             if not lang[0] is None:
                 first_phase = f'{lang[1]}-{lang[0]}'
@@ -72,13 +94,14 @@ class VapClient():
             else:
                 return first_phase
 
-        print(f"Languages available: {','.join( [lang_to_str(x) for x in resp_payload[langs_id]])}")
+        print(f"Languages available: {','.join( [lang_to_str(x) for x in langs])}")
 
     async def registerIntents(self):
         # Send our utterances to the server for them to be taken account of
 
         payload = {
             "skillId": skill_id,
+            "uniqueAuthenticationToken": self.token,
             "nluData": [
                 {
                     "language": {
@@ -130,7 +153,12 @@ class VapClient():
     async def close(self):
         # We have finished, let it know to the server
 
-        request = aiocoap.Message(code=aiocoap.DELETE, uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}')
+        payload = {
+            "skillId": skill_id,
+            "uniqueAuthenticationToken": self.token
+        }
+
+        request = aiocoap.Message(code=aiocoap.DELETE, payload=msgpack.packb(payload), uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}')
 
         # Send it  to the registry and wait for a response
         response = await self.client.request(request).response
@@ -145,6 +173,7 @@ class VapClient():
 
         payload = {
             "skillId": skill_id,
+            "uniqueAuthenticationToken": self.token,
             "data":[{
                 "type": "standalone",
                 "clientId": "123456789a",
@@ -171,6 +200,7 @@ class VapClient():
         # is dependent on the capability and it is defined by it.
         payload = {
             "skillId": skill_id,
+            "uniqueAuthenticationToken": self.token,
             "data":[{
                 "clientId": "vap.SYSTEM",
                 "capabilities": [{
@@ -210,6 +240,7 @@ class VapClient():
 
         payload = {
             "skillId": skill_id,
+            "uniqueAuthenticationToken": self.token,
             "data": [{
                 "type": "requested",
                 "requestId": requestId,
@@ -230,6 +261,19 @@ class VapClient():
         if response.code != aiocoap.VALID:
             raise Exception(f"Failed to disconenct from registry: {response.code}")
 
+    async def heartbeat(self):
+        """ Tell the registry every so often that we are still alive """
+        while True:
+            await asyncio.sleep(20)
+            message = aiocoap.Message(
+                code=aiocoap.GET,
+                uri=f'coap://{registry_address}/vap/skillRegistry/skills/{skill_id}'
+            )
+            response = await self.client.request(message).response
+
+            if response.code != aiocoap.CONTENT:
+                print(f"The registry forgot about us: {response.code}")
+
     async def register(self):
         print(skill_id)
         message = aiocoap.Message(
@@ -282,6 +326,7 @@ async def main():
     await client.notification()
     await client.query()
 
+    asyncio.create_task(client.heartbeat())
     await client.register()
 
     await wait
@@ -1,31 +1,146 @@
 mod load;
+mod transport;
 
-use std::{io::Cursor, path::Path, time::Duration};
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self as std_mpsc, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
 
 use coap::CoAPClient;
-use coap_lite::{MessageClass, RequestType as Method, ResponseType};
+use transport::{Context, Transport};
+use coap_lite::{CoapOption, CoapRequest, MessageClass, RequestType as Method, ResponseType};
 use fluent_langneg::negotiate_languages;
 use futures::channel::mpsc;
-use log::warn;
+use log::{debug, warn};
 use serde::Serialize;
 use thiserror::Error;
 use unic_langid::LanguageIdentifier;
+use vap_common_skill::auth::{default_token_path, load_token, save_token, TOKEN_QUERY};
+use vap_common_skill::capability::CapabilityRange;
+use vap_common_skill::version::{self, Version};
 use vap_common_skill::structures::{msg_notification::Data, msg_query::QueryData, *, msg_skill_request::RequestSlot};
 
 pub use vap_common_skill::structures::{msg_skill_request::RequestDataKind, PlainCapability};
+pub use load::LoadError;
 
 /// The skill itself, use this to communicate with the registry.
 pub struct Skill {
-    client: CoAPClient,
+    transport: Transport,
     id: String,
     langs: Vec<LanguageIdentifier>,
+    /// Given by the registry, it goes in every message
+    token: Option<String>,
+    /// The VAP version agreed with the registry
+    vap_version: Version,
     sender: mpsc::Sender<SkillRequest>,
+    _heartbeat: std_mpsc::Sender<()>,
+}
+
+/// Port in which the skill registry listens by default
+const REGISTRY_PORT: u16 = 5683;
+
+/// Multicast address for "All CoAP nodes" in IPv4
+const ALL_COAP_IPV4: &str = "224.0.1.187";
+
+/// How much to wait for a registry to answer to service discovery
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often to tell the registry that we are still alive, at most, registries
+/// with a lease get a heartbeat three times per lease
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Environment variable with the address of the registry (e.g: 192.168.1.2:5683)
+pub const REGISTRY_ADDRESS_VAR: &str = "VAP_SKILL_REGISTRY";
+
+/// Creates a skill with some extra options, get one with [`Skill::builder`].
+pub struct SkillBuilder<P> {
+    name: String,
+    id: String,
+    intents: P,
+    address: Option<String>,
+    token_file: Option<PathBuf>,
+    context: Context,
+    capabilities: Vec<CapabilityRange>,
+}
+
+impl<P: AsRef<Path> + Clone> SkillBuilder<P> {
+    /// Connect to the registry at this address (e.g: 192.168.1.2:5683)
+    /// instead of searching for it.
+    pub fn address<S: Into<String>>(mut self, address: S) -> Self {
+        self.address = Some(address.into());
+        self
+    }
+
+    /// Keep the token given by the registry in this file instead of
+    /// `$HOME/.local/share/vap/{id}.token`.
+    pub fn token_file<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.token_file = Some(path.into());
+        self
+    }
+
+    /// The skill can handle versions `min_version` to `max_version` of the
+    /// capability `name`. Once any capability is declared, the capabilities
+    /// of clients in requests and the data in query answers only include
+    /// declared capabilities (converted to a supported version if needed).
+    pub fn capability<S: Into<String>>(mut self, name: S, min_version: u16, max_version: u16) -> Self {
+        self.capabilities.push(CapabilityRange::new(name, min_version, max_version));
+        self
+    }
+
+    /// Protect every message with a secret shared with the registry (see
+    /// `SkillRegisterConfig::psk`).
+    #[cfg(feature = "oscore")]
+    pub fn psk(mut self, master_secret: &[u8]) -> Self {
+        let context = vap_common_skill::oscore::SecurityContext::for_skill(&self.id, master_secret);
+        self.context = Some(std::sync::Arc::new(std::sync::Mutex::new(context)));
+        self
+    }
+
+    /// Connects to the skill registry, see [`Skill::new`] for more info.
+    /// The registry is searched for in this order: the address given to
+    /// the builder, the `VAP_SKILL_REGISTRY` environment variable, CoAP's
+    /// service discovery and lastly this same device.
+    pub fn build(self) -> Result<(Skill, SkillIn)> {
+        let address = self
+            .address
+            .or_else(|| std::env::var(REGISTRY_ADDRESS_VAR).ok())
+            .unwrap_or_else(Skill::get_address);
+        let token_file = self.token_file.or_else(|| default_token_path(&self.id));
+
+        let transport = Transport::new(&address, self.context)?;
+        Skill::connect(self.name, self.id, self.intents, transport, token_file, self.capabilities)
+    }
 }
 
 impl Skill {
+    /// Search for the skill registry using CoAP's service discovery, if
+    /// nobody answers the registry is assumed to be on this same device.
     fn get_address() -> String {
-        const PORT: u16 = 5683;
-        format!("127.0.0.1:{}", PORT)
+        fn discover() -> std::io::Result<Option<SocketAddr>> {
+            let client = CoAPClient::new((ALL_COAP_IPV4, REGISTRY_PORT))?;
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path(".well-known/core");
+            request
+                .message
+                .add_option(CoapOption::UriQuery, b"rt=vap-skill-registry".to_vec());
+
+            client.send_all_coap(&request, 0)?;
+            client.set_receive_timeout(Some(DISCOVERY_TIMEOUT))?;
+            let (resp, address) = client.receive_from()?;
+            let links = String::from_utf8_lossy(&resp.message.payload);
+
+            Ok(links.contains("vap-skill-registry").then_some(address))
+        }
+
+        match discover() {
+            Ok(Some(address)) => address.to_string(),
+            _ => format!("127.0.0.1:{}", REGISTRY_PORT),
+        }
     }
 
     /// Creates a new skill, will also connect to the skill registry and register
@@ -37,7 +152,9 @@ impl Skill {
     /// 
     /// * `name` - A human-readable name for this skill
     /// * `id` -  This skill id like 'com.my_company.my_skill'
-    /// * `intents` - Where are the skills stored
+    /// * `intents` - Where are the skills stored: a file per language
+    ///   (`en-US.toml`) or a folder per language (`en-US/`), written in TOML,
+    ///   YAML or JSON. Anything else in there is skipped.
     /// 
     pub fn new<S1, S2, P>(name: S1, id: S2, intents: P) -> Result<(Self, SkillIn)>
     where
@@ -45,36 +162,90 @@ impl Skill {
         S2: Into<String>,
         P: AsRef<Path> + Clone,
     {
-        let id_str = id.into();
+        Self::builder(name, id, intents).build()
+    }
+
+    /// Same as [`Skill::new`] but allows to configure how to reach the registry.
+    pub fn builder<S1, S2, P>(name: S1, id: S2, intents: P) -> SkillBuilder<P>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+        P: AsRef<Path> + Clone,
+    {
+        SkillBuilder {
+            name: name.into(),
+            id: id.into(),
+            intents,
+            address: None,
+            token_file: None,
+            context: Context::default(),
+            capabilities: vec![],
+        }
+    }
+
+    fn connect<P>(
+        name: String,
+        id_str: String,
+        intents: P,
+        mut transport: Transport,
+        token_file: Option<PathBuf>,
+        capabilities: Vec<CapabilityRange>,
+    ) -> Result<(Self, SkillIn)>
+    where
+        P: AsRef<Path> + Clone,
+    {
+        let token = token_file.as_ref().and_then(load_token);
         let payload = rmp_serde::to_vec_named(&MsgConnect {
             id: id_str.clone(),
-            name: name.into(),
-            vap_version: "Alpha".into(),
+            name,
+            // What registries that don't negotiate will use
+            vap_version: version::SUPPORTED.min.to_string(),
+            vap_versions: Some(version::SUPPORTED),
+            capabilities,
+            auth_token: token.clone(),
         })
         .expect("Failed to make initial payload, report this");
-        let mut client = CoAPClient::new(Self::get_address())?;
 
         let mut remaining_retries = 3;
         while remaining_retries > 0 {
             
-            let resp = client.request_path(
-                "vap/skillRegistry/connect",
+            let resp = transport.request(
                 Method::Post,
+                "vap/skillRegistry/connect",
                 Some(payload.clone()),
-                None,
             )?;
 
             match resp.message.header.code {
                 MessageClass::Response(ResponseType::Created) => {
                     let payload: MsgConnectResponse =
                         rmp_serde::from_read(Cursor::new(resp.message.payload)).unwrap();
+                    let vap_version = payload.vap_version.unwrap_or(version::SUPPORTED.min);
+                    if !version::SUPPORTED.contains(vap_version) {
+                        return Err(Error::IncompatibleVersion);
+                    }
                     let (sender, receiver) = mpsc::channel(10);
 
+                    // We only receive a token the first time we connect
+                    let token = match (payload.auth_token, token_file) {
+                        (Some(new_token), Some(path)) => {
+                            if let Err(e) = save_token(&path, &new_token) {
+                                warn!("Couldn't save our token, we won't be able to connect again: {}", e);
+                            }
+                            Some(new_token)
+                        }
+                        (new_token, _) => new_token.or(token),
+                    };
+                    let interval = heartbeat_interval(payload.lease);
+                    let _heartbeat = start_heartbeat(transport.try_clone()?, &id_str, token.as_deref(), interval)?;
+
                     let mut skill = Self {
-                        client,
+                        transport,
                         id: id_str,
                         langs: payload.langs.into_iter().map(|l| l.into()).collect(),
+                        token,
+                        vap_version,
                         sender,
+                        _heartbeat,
                     };
 
                     skill.register_intents(intents)?;
@@ -84,16 +255,18 @@ impl Skill {
                 }
                 MessageClass::Response(ResponseType::BadRequest) => {
                     remaining_retries -=1;
-                    println!("There's seemingly some problem, waiting and retrying");
+                    warn!("The registry refused our connection, waiting and retrying");
+                    if remaining_retries == 0 {
+                        return Err(Error::from_response(ResponseType::BadRequest, &resp.message.payload));
+                    }
                 }
-                _ => {
-                    println!("{}", resp.message.header.code);
-                    panic!("ERROR, got unexpected message!!")
+                code => {
+                    return Err(Error::from_response(extract_type(code), &resp.message.payload));
                 }
             }
             std::thread::sleep(Duration::from_secs(1));
         }
-        panic!("Connection failed!");
+        Err(Error::Unknown)
     }
 
     fn send_message<T: Serialize>(
@@ -102,37 +275,27 @@ impl Skill {
         path: &str,
         data: T,
     ) -> Result<(ResponseType, Vec<u8>)> {
-        println!("Sending message");
         let d = rmp_serde::to_vec_named(&data).expect("Failed to encode message, report this");
-        let resp = self
-            .client
-            .request_path(path, method, Some(d), None)
-            .unwrap();
-        println!("Received!");
+        let resp = self.transport.request(method, path, Some(d))?;
 
-        Ok((
-            extract_type(resp.message.header.code), 
-            resp.message.payload
-        ))
+        let status = extract_type(resp.message.header.code);
+        if is_error(status) {
+            Err(Error::from_response(status, &resp.message.payload))
+        } else {
+            Ok((status, resp.message.payload))
+        }
     }
 
-    fn send_message_no_payload(&mut self, method: Method, path: &str) -> ResponseType {
-        extract_type(
-            self.client
-                .request_path(path, method, None, None)
-                .unwrap()
-                .message
-                .header
-                .code,
-        )
+    /// The VAP version agreed with the registry
+    pub fn vap_version(&self) -> Version {
+        self.vap_version
     }
 
     pub fn register_intents<P>(&mut self, intents: P) -> Result<()>
     where
         P: AsRef<Path> + Clone,
     {
-        let langs = load::list_langs(intents.clone());
-        println!("payload langs: {:?}", &self.langs);
+        let langs = load::list_langs(intents.clone())?;
         let langs = negotiate_languages(
             &self.langs,
             &langs,
@@ -140,8 +303,8 @@ impl Skill {
             fluent_langneg::NegotiationStrategy::Matching,
         );
 
-        let nlu_data = load::load_intents(&langs, intents);
-        println!("INtents: {:?}", nlu_data);
+        let nlu_data = load::load_intents(&langs, intents)?;
+        debug!("Registering intents for {:?}", langs);
 
         match self.send_message(
             Method::Post,
@@ -149,6 +312,7 @@ impl Skill {
             MsgRegisterIntents {
                 skill_id: self.id.clone(),
                 nlu_data,
+                auth_token: self.token.clone(),
             },
         )? {
             (ResponseType::Created, _) => Ok(()),
@@ -156,15 +320,46 @@ impl Skill {
         }
     }
 
+    /// Makes the intents of these scopes (as named in the intents files)
+    /// recognizable, the "main" scope is enabled from the start.
+    pub fn enable_scopes(&mut self, scopes: &[&str]) -> Result<()> {
+        self.set_scopes(scopes, &[])
+    }
+
+    /// Stops recognizing the intents of these scopes (e.g: a follow-up
+    /// question that was already answered).
+    pub fn disable_scopes(&mut self, scopes: &[&str]) -> Result<()> {
+        self.set_scopes(&[], scopes)
+    }
+
+    fn set_scopes(&mut self, enable: &[&str], disable: &[&str]) -> Result<()> {
+        let to_vec = |scopes: &[&str]| scopes.iter().map(|s| s.to_string()).collect();
+        match self.send_message(
+            Method::Post,
+            "vap/skillRegistry/scopes",
+            MsgSetScopes {
+                skill_id: self.id.clone(),
+                enable: to_vec(enable),
+                disable: to_vec(disable),
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Changed, _) => Ok(()),
+            _ => Err(Error::Unknown),
+        }
+    }
+
     fn close(&mut self) -> Result<()> {
-        if self.send_message_no_payload(
+        match self.send_message(
             Method::Delete,
             &format!("vap/skillRegistry/skills/{}", &self.id),
-        ) != ResponseType::Deleted
-        {
-            Err(Error::Unknown)
-        } else {
-            Ok(())
+            MsgSkillClose {
+                skill_id: self.id.clone(),
+                auth_token: self.token.clone(),
+            },
+        )? {
+            (ResponseType::Deleted, _) => Ok(()),
+            _ => Err(Error::Unknown),
         }
     }
 
@@ -182,19 +377,17 @@ impl Skill {
 
     /// Send notifications (of any type) to several clients
     pub fn notify_multiple(&mut self, data: Vec<Data>) -> Result<MsgNotificationResponse> {
-        println!("Send answer");
-        match self.send_message(
+        // Registries answer with 2.03, 2.04 or 2.05, errors are already out
+        let (_, d) = self.send_message(
             Method::Post,
             "vap/skillRegistry/notification",
             MsgNotification {
                 skill_id: self.id.clone(),
                 data,
+                auth_token: self.token.clone(),
             },
-        )? {
-            (ResponseType::Content, d) => Ok(rmp_serde::from_read(Cursor::new(d))
-                .expect("Failed to create MsgNotification, report this")),
-            _ => Err(Error::Unknown),
-        }
+        )?;
+        Ok(rmp_serde::from_read(Cursor::new(d)).expect("Failed to create MsgNotification, report this"))
     }
 
     /// Send queries to any number of IDs (clients or the system itself).
@@ -205,31 +398,31 @@ impl Skill {
             MsgQuery {
                 skill_id: self.id.clone(),
                 data,
+                auth_token: self.token.clone(),
             },
         )? {
             (ResponseType::Content, d) => Ok(rmp_serde::from_read(Cursor::new(d))
                 .expect("Failed to create MsgQuery, report this")),
-            (ResponseType::BadRequest, _) => Err(Error::BadRequest),
-
             _ => Err(Error::Unknown),
         }
     }
 
     fn register(&mut self) -> Result<()> {
         let mut sender = self.sender.clone();
-        self.client
+        let mut path = format!("vap/skillRegistry/skills/{}", &self.id);
+        if let Some(token) = &self.token {
+            path = format!("{}?{}={}", path, TOKEN_QUERY, token);
+        }
+        self.transport
             .observe(
-                &format!("vap/skillRegistry/skills/{}", &self.id),
+                &path,
                 move |m| {
-                    println!("Oberseve returned something!!!");
-                    println!("{:?}", m);
                     if !m.payload.is_empty()
                         && m.header.code == MessageClass::Response(ResponseType::Content)
                     {
-                        println!("Msg:  {:?}", debug_msg_pack(&m.payload));
-
                         match rmp_serde::from_read::<_, MsgSkillRequest>(Cursor::new(m.payload)) {
                             Ok(payload) => {
+                                debug!("Received request {}", payload.request_id);
                                 sender.try_send(payload.into()).unwrap();
                             }
                             Err(e) => {
@@ -263,9 +456,42 @@ impl Drop for Skill {
     }
 }
 
-fn debug_msg_pack(payload: &[u8]) -> String {
-    let v: Value = rmp_serde::from_read(Cursor::new(payload.to_vec())).unwrap();
-    v.to_string()
+/// How often to send heartbeats to a registry with `lease` (in milliseconds)
+fn heartbeat_interval(lease: Option<u64>) -> Duration {
+    match lease {
+        Some(lease) => HEARTBEAT_INTERVAL.min(Duration::from_millis(lease) / 3),
+        None => HEARTBEAT_INTERVAL,
+    }
+}
+
+/// Tells the registry every `interval` that we are still alive (heartbeats
+/// carry our token), it stops as soon as the returned sender is dropped.
+fn start_heartbeat(transport: Transport, id: &str, token: Option<&str>, interval: Duration) -> Result<std_mpsc::Sender<()>> {
+    let mut path = format!("vap/skillRegistry/skills/{}", id);
+    if let Some(token) = token {
+        path = format!("{}?{}={}", path, TOKEN_QUERY, token);
+    }
+    let (stop_send, stop_recv) = std_mpsc::channel();
+
+    thread::spawn(move || {
+        let mut transport = transport;
+        while let Err(RecvTimeoutError::Timeout) = stop_recv.recv_timeout(interval) {
+            match transport.request(Method::Get, &path, None) {
+                Ok(resp) if matches!(resp.message.header.code, MessageClass::Response(c) if is_error(c)) => {
+                    warn!("The registry doesn't know about us anymore");
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to send a heartbeat: {}", e),
+            }
+        }
+    });
+
+    Ok(stop_send)
+}
+
+/// Whether the response is a 4.xx or 5.xx one
+fn is_error(status: ResponseType) -> bool {
+    u8::from(MessageClass::Response(status)) >> 5 >= 4
 }
 
 fn extract_type(code: MessageClass) -> ResponseType {
@@ -342,23 +568,235 @@ pub enum Error {
     #[error("IO")]
     IO(#[from] std::io::Error),
 
+    #[error("The intents couldn't be loaded: {0}")]
+    Load(#[from] LoadError),
+
     #[error("The data sent had a wrong format or didn't meet the VAP rules")]
     BadRequest,
 
+    #[error("The registry could not read our message")]
+    MalformedContent,
+
+    #[error("The message lacked the mandatory field {field:?}")]
+    MissingField { field: Option<String> },
+
+    #[error("The registry does not know the skill id {id}")]
+    WrongSkillId { id: String },
+
+    #[error("{object} was not found in the registry")]
+    NotFound { object: String },
+
+    #[error("The registry is not compatible with our VAP version")]
+    IncompatibleVersion,
+
+    #[error("The connection was denied by the registry")]
+    ConnectionDenied,
+
+    #[error("We are not authorized to do this")]
+    Unauthorized,
+
+    #[error("The entity {object} was not registered")]
+    MissingEntity { object: String },
+
+    #[error("The slot {object} was not declared")]
+    MissingSlot { object: String },
+
+    #[error("{object} was registered more than once")]
+    Duplicated { object: String },
+
+    #[error("The entity {object} is not valid")]
+    InvalidEntity { object: String },
+
+    #[error("The intent {object} is not the same as in other languages")]
+    MismatchedIntent { object: String },
+
+    #[error("The registry answered with an error: {0}")]
+    Vap(VapError),
+
     #[error("We got an error, but we don't know why")]
     Unknown,
 }
 
+impl From<VapError> for Error {
+    fn from(e: VapError) -> Self {
+        match (e.type_.as_str(), e.object) {
+            (error_types::MALFORMED_CONTENT, _) => Error::MalformedContent,
+            (error_types::MISSING_FIELD, field) => Error::MissingField { field },
+            (error_types::WRONG_SKILL_ID, Some(id)) => Error::WrongSkillId { id },
+            (error_types::NOT_FOUND, Some(object)) => Error::NotFound { object },
+            (error_types::INCOMPATIBLE_VERSION, _) => Error::IncompatibleVersion,
+            (error_types::CONNECTION_DENIED, _) => Error::ConnectionDenied,
+            (error_types::UNAUTHORIZED, _) => Error::Unauthorized,
+            (error_types::MISSING_ENTITY, Some(object)) => Error::MissingEntity { object },
+            (error_types::MISSING_SLOT, Some(object)) => Error::MissingSlot { object },
+            (error_types::DUPLICATED, Some(object)) => Error::Duplicated { object },
+            (error_types::INVALID_ENTITY, Some(object)) => Error::InvalidEntity { object },
+            (error_types::MISMATCHED_INTENT, Some(object)) => Error::MismatchedIntent { object },
+            (_, object) => Error::Vap(VapError { object, ..e }),
+        }
+    }
+}
+
+impl Error {
+    /// Decode the error sent by the registry, registries that do not send an
+    /// error body get a generic error.
+    fn from_response(status: ResponseType, payload: &[u8]) -> Self {
+        match rmp_serde::from_read::<_, VapError>(Cursor::new(payload)) {
+            Ok(e) => e.into(),
+            Err(_) if status == ResponseType::BadRequest => Error::BadRequest,
+            Err(_) => Error::Unknown,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::Skill;
+    use std::fs;
+    use std::io::Cursor;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::sync::mpsc as std_mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::{heartbeat_interval, Error, Request, Skill, HEARTBEAT_INTERVAL};
+    use coap_lite::{CoapRequest, MessageClass, MessageType, Packet, RequestType as Method, ResponseType};
     use futures::StreamExt;
+    use serde::Serialize;
+    use vap_common_skill::structures::{
+        msg_skill_request::{ClientData, RequestData, RequestDataKind},
+        Language, MsgConnectResponse, MsgRegisterIntents, MsgSkillRequest, VapError,
+    };
+
+    #[test]
+    fn decodes_vap_errors() {
+        let payload = rmp_serde::to_vec_named(&VapError::wrong_skill_id("com.example.test")).unwrap();
+        match Error::from_response(ResponseType::BadRequest, &payload) {
+            Error::WrongSkillId { id } => assert_eq!(id, "com.example.test"),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        assert!(matches!(
+            Error::from_response(ResponseType::BadRequest, &[]),
+            Error::BadRequest
+        ));
+    }
+
+    #[test]
+    fn heartbeats_fit_in_the_lease() {
+        assert_eq!(heartbeat_interval(None), HEARTBEAT_INTERVAL);
+        assert_eq!(heartbeat_interval(Some(3_000)), Duration::from_secs(1));
+        assert_eq!(heartbeat_interval(Some(600_000)), HEARTBEAT_INTERVAL);
+    }
+
+    fn answer<T: Serialize>(request: &Packet, status: ResponseType, data: Option<T>) -> Vec<u8> {
+        let mut response = Packet::new();
+        response.header.set_type(MessageType::Acknowledgement);
+        response.header.message_id = request.header.message_id;
+        response.header.code = MessageClass::Response(status);
+        response.set_token(request.get_token().clone());
+        if let Some(data) = data {
+            response.payload = rmp_serde::to_vec_named(&data).unwrap();
+        }
+        response.to_bytes().unwrap()
+    }
+
+    /// A registry that accepts the skill, sends it a request once it
+    /// observes and reports the paths it is asked for until the skill leaves
+    fn fake_registry(request: MsgSkillRequest) -> (SocketAddr, std_mpsc::Receiver<String>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let (paths, received) = std_mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buf = [0; 1500];
+            let (size, from) = socket.recv_from(&mut buf).unwrap();
+            let packet = Packet::from_bytes(&buf[..size]).unwrap();
+            let request_packet = CoapRequest::from_packet(packet.clone(), from);
+            let path = request_packet.get_path();
+            let bytes = match (request_packet.get_method(), path.as_str()) {
+                (Method::Post, "vap/skillRegistry/connect") => {
+                    let connected = MsgConnectResponse {
+                        langs: vec![Language {
+                            language: "en".into(),
+                            country: Some("US".into()),
+                            extra: None,
+                        }],
+                        vap_version: None,
+                        lease: None,
+                        auth_token: Some("1234".into()),
+                    };
+                    answer(&packet, ResponseType::Created, Some(connected))
+                }
+                (Method::Post, "vap/skillRegistry/registerIntents") => {
+                    let msg: MsgRegisterIntents =
+                        rmp_serde::from_read(Cursor::new(&packet.payload)).unwrap();
+                    let intents: Vec<_> = msg
+                        .nlu_data
+                        .iter()
+                        .flat_map(|d| d.intents.iter().map(|i| i.name.clone()))
+                        .collect();
+                    paths.send(format!("{}: {}", path, intents.join(","))).unwrap();
+                    answer::<()>(&packet, ResponseType::Created, None)
+                }
+                (Method::Get, _) => {
+                    let bytes = answer(&packet, ResponseType::Content, Some(&request));
+                    let mut notification = Packet::from_bytes(&bytes).unwrap();
+                    notification.set_observe_value(1);
+                    notification.to_bytes().unwrap()
+                }
+                (Method::Delete, _) => {
+                    socket.send_to(&answer::<()>(&packet, ResponseType::Deleted, None), from).unwrap();
+                    let _ = paths.send(path);
+                    break;
+                }
+                _ => answer::<()>(&packet, ResponseType::NotFound, None),
+            };
+            socket.send_to(&bytes, from).unwrap();
+        });
+
+        (address, received)
+    }
 
     #[tokio::test]
     async fn it_works() {
-        let (skill, mut skill_in) = Skill::new("Test", "com.example.test", "assets").unwrap();
-        loop {
-            let req = skill_in.next().await.unwrap();
+        let folder = std::env::temp_dir().join(format!("vap-skill-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let intents = "[intents.main.greet]\nutterances = [\"hello\"]\n";
+        fs::write(folder.join("en-US.toml"), intents).unwrap();
+        let (address, paths) = fake_registry(MsgSkillRequest {
+            request_id: 7,
+            client: ClientData {
+                system_id: "test_client".into(),
+                capabilities: vec![],
+            },
+            request: RequestData {
+                type_: RequestDataKind::Intent,
+                intent: "greet".into(),
+                locale: "en-US".into(),
+                slots: vec![],
+            },
+        });
+
+        let (skill, mut skill_in) = Skill::builder("Test", "com.example.test", &folder)
+            .address(address.to_string())
+            .token_file(folder.join("token"))
+            .build()
+            .unwrap();
+        assert_eq!(paths.recv().unwrap(), "vap/skillRegistry/registerIntents: greet");
+        assert_eq!(fs::read_to_string(folder.join("token")).unwrap(), "1234");
+
+        let req = skill_in.next().await.unwrap();
+        assert_eq!(req.request_id, 7);
+        assert_eq!(req.client.system_id, "test_client");
+        match req.request {
+            Request::Intent(intent, data) => {
+                assert_eq!(intent, "greet");
+                assert_eq!(data.locale, "en-US");
+            }
+            r => panic!("Unexpected request: {:?}", r),
         }
+
+        drop(skill);
+        assert_eq!(paths.recv().unwrap(), "vap/skillRegistry/skills/com.example.test");
+        fs::remove_dir_all(&folder).unwrap();
     }
 }
@@ -1,76 +1,197 @@
-use std::{collections::HashMap, fs::DirEntry};
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::path::{Path, PathBuf};
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
-use toml::de::from_str;
+use thiserror::Error;
 use unic_langid::LanguageIdentifier;
 use vap_common_skill::structures::{
     msg_register_intents::{
-        NluData, NluDataEntity, NluDataEntityData, NluDataIntent, NluDataIntentUtterance,
-        NluDataSlot,
+        NluData, NluDataEntity, NluDataEntityData, NluDataEntityKind, NluDataIntent,
+        NluDataIntentUtterance, NluDataSlot,
     },
     Language,
 };
 
-pub fn list_langs<P>(intents: P) -> Vec<LanguageIdentifier>
+/// Why the intents of a skill couldn't be loaded
+#[derive(Debug, Error)]
+pub enum LoadError {
+    /// The folder or one of its files couldn't be read
+    #[error("couldn't read {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    /// A file is not valid TOML, YAML or JSON, or doesn't describe intents.
+    /// Line and column start at 1, and are missing when not known.
+    #[error("couldn't load {}: {message}", path.display())]
+    Parse {
+        path: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+
+    /// Two files of the same language define an intent (as `scope/intent`)
+    /// or an entity with the same name
+    #[error("{} defines {object} again", path.display())]
+    Duplicated { path: PathBuf, object: String },
+
+    /// An entity mixes kinds that exclude each other: more than one of
+    /// `list`, `regex` and `range`, or `data` along with `regex` or `range`
+    #[error("{} gives entity {entity} conflicting kinds", path.display())]
+    Conflicting { path: PathBuf, entity: String },
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> LoadError + '_ {
+    move |source| LoadError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// The formats intent files can be written in, known by their extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, path: &Path, text: &str) -> Result<T, LoadError> {
+        let (position, message) = match self {
+            Format::Toml => match toml::from_str(text) {
+                Ok(t) => return Ok(t),
+                // toml counts from 0
+                Err(e) => (e.line_col().map(|(l, c)| (l + 1, c + 1)), e.to_string()),
+            },
+            Format::Yaml => match serde_yaml::from_str(text) {
+                Ok(t) => return Ok(t),
+                Err(e) => (e.location().map(|l| (l.line(), l.column())), e.to_string()),
+            },
+            Format::Json => match serde_json::from_str(text) {
+                Ok(t) => return Ok(t),
+                // Errors that aren't about the text (e.g: wrong type) are at line 0
+                Err(e) => (Some((e.line(), e.column())).filter(|(l, _)| *l > 0), e.to_string()),
+            },
+        };
+
+        Err(LoadError::Parse {
+            path: path.to_path_buf(),
+            line: position.map(|(l, _)| l),
+            column: position.map(|(_, c)| c),
+            message,
+        })
+    }
+}
+
+/// Every language in the folder with the files that describe it. A language
+/// is either a file (`en-US.toml`) or a folder (`en-US/`) whose files are
+/// merged, anything else (e.g: a README or `config.json`) is skipped.
+fn find_langs(folder: &Path) -> Result<Vec<(LanguageIdentifier, Vec<PathBuf>)>, LoadError> {
+    let mut langs: Vec<(LanguageIdentifier, Vec<PathBuf>)> = Vec::new();
+    for entry in folder.read_dir().map_err(io_error(folder))? {
+        let path = entry.map_err(io_error(folder))?.path();
+        let files = if path.is_dir() {
+            lang_files(&path)?
+        } else if Format::of(&path).is_some() {
+            vec![path.clone()]
+        } else {
+            continue;
+        };
+
+        let lang = path.file_stem().and_then(|s| s.to_str()).and_then(parse_lang);
+        match lang {
+            Some(lang) if !files.is_empty() => match langs.iter_mut().find(|(l, _)| *l == lang) {
+                Some((_, known)) => known.extend(files),
+                None => langs.push((lang, files)),
+            },
+            _ => {}
+        }
+    }
+
+    for (_, files) in &mut langs {
+        files.sort();
+    }
+    langs.sort_by_key(|(l, _)| l.to_string());
+    Ok(langs)
+}
+
+/// Almost any word is a valid language identifier (`config` included), only
+/// two letter languages (`en`) or those with a script or region (`fil-PH`)
+/// are taken as one
+fn parse_lang(name: &str) -> Option<LanguageIdentifier> {
+    let lang: LanguageIdentifier = name.parse().ok()?;
+    let is_lang = lang.language.as_str().len() == 2 || lang.script.is_some() || lang.region.is_some();
+    Some(lang).filter(|_| is_lang)
+}
+
+/// The intent files inside the folder of a language
+fn lang_files(folder: &Path) -> Result<Vec<PathBuf>, LoadError> {
+    let mut files = Vec::new();
+    for entry in folder.read_dir().map_err(io_error(folder))? {
+        let path = entry.map_err(io_error(folder))?.path();
+        if path.is_file() && Format::of(&path).is_some() {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+fn load_file(path: &Path) -> Result<LangData, LoadError> {
+    let text = fs::read_to_string(path).map_err(io_error(path))?;
+    // Only files with a known format get here
+    let mut data: LangData = Format::of(path).unwrap().parse(path, &text)?;
+    for entity in data.entities.values_mut() {
+        entity.path = path.to_path_buf();
+    }
+
+    Ok(data)
+}
+
+pub fn list_langs<P>(intents: P) -> Result<Vec<LanguageIdentifier>, LoadError>
 where
     P: AsRef<Path>,
 {
-    let folder = intents.as_ref();
-    folder
-        .read_dir()
-        .unwrap()
-        .into_iter()
-        .filter_map(|r| {
-            get_lang_id(&r.unwrap())
-        })
-        .collect()
+    Ok(find_langs(intents.as_ref())?.into_iter().map(|(l, _)| l).collect())
 }
 
-pub fn load_intents<P>(langs: &[&LanguageIdentifier], intents: P) -> Vec<NluData>
+pub fn load_intents<P>(langs: &[&LanguageIdentifier], intents: P) -> Result<Vec<NluData>, LoadError>
 where
     P: AsRef<Path>,
 {
-    let folder = intents.as_ref();
-
-    folder
-        .read_dir()
-        .unwrap()
-        .into_iter()
-        .filter_map(|r| {
-            let r = r.unwrap();
-            let t = r.file_type().unwrap();
-            if t.is_file() {
-                let i: LanguageIdentifier = get_lang_id(&r).unwrap();
-
-                if langs.contains(&&i) {
-                    let l: LangData = from_str(&fs::read_to_string(r.path()).unwrap()).unwrap();
-                    Some(l.into_nlu_data(i.into()))
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        })
-        .collect()
-}
+    let mut nlu_data = Vec::new();
+    for (lang, files) in find_langs(intents.as_ref())? {
+        if !langs.contains(&&lang) {
+            continue;
+        }
+
+        let mut data = LangData::default();
+        for file in files {
+            data.merge(load_file(&file)?, &file)?;
+        }
+        nlu_data.push(data.into_nlu_data(lang.into())?);
+    }
 
-fn get_lang_id (entry: &DirEntry) -> Option<LanguageIdentifier> {
-    Path::new(&entry.path())
-    .file_stem()
-    .unwrap()
-    .to_str()
-    .unwrap()
-    .parse()
-    .ok()
+    Ok(nlu_data)
 }
+
 type ScopeData = HashMap<String, IntentData>;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct LangData {
-    #[serde(rename = "intents")]
+    #[serde(rename = "intents", default)]
     scopes: HashMap<String, ScopeData>,
 
     #[serde(default)]
@@ -78,23 +199,52 @@ struct LangData {
 }
 
 impl LangData {
-    pub fn into_nlu_data(self, language: Language) -> NluData {
-        // Just one scope right now
-        let intents = self.scopes["main"]
-            .clone()
+    /// Adds what another file of the same language has
+    fn merge(&mut self, other: LangData, path: &Path) -> Result<(), LoadError> {
+        let duplicated = |object: String| LoadError::Duplicated {
+            path: path.to_path_buf(),
+            object,
+        };
+
+        for (scope, intents) in other.scopes {
+            let known = self.scopes.entry(scope.clone()).or_default();
+            for (name, intent) in intents {
+                if known.contains_key(&name) {
+                    return Err(duplicated(format!("{}/{}", scope, name)));
+                }
+                known.insert(name, intent);
+            }
+        }
+        for (name, entity) in other.entities {
+            if self.entities.contains_key(&name) {
+                return Err(duplicated(name));
+            }
+            self.entities.insert(name, entity);
+        }
+
+        Ok(())
+    }
+
+    pub fn into_nlu_data(self, language: Language) -> Result<NluData, LoadError> {
+        let intents = self
+            .scopes
             .into_iter()
-            .map(|(n, i)| i.into_vap(n))
+            .flat_map(|(scope, intents)| {
+                intents
+                    .into_iter()
+                    .map(move |(n, i)| i.into_vap(n, scope.clone()))
+            })
             .collect();
         let entities = self
             .entities
             .into_iter()
             .map(|(n, e)| e.into_vap(n))
-            .collect();
-        NluData {
+            .collect::<Result<_, _>>()?;
+        Ok(NluData {
             language,
             intents,
             entities,
-        }
+        })
     }
 }
 
@@ -103,11 +253,57 @@ struct IntentData {
     utterances: Vec<String>,
 
     #[serde(default)]
-    slots: Option<HashMap<String, String>>,
+    slots: Option<HashMap<String, SlotData>>,
+}
+
+/// Either just the entity (`slot = "entity"`) or the whole slot
+/// (`slot = { entity = "entity", required = true, prompt = "..." }`)
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum SlotData {
+    Entity(String),
+    Full {
+        entity: String,
+
+        #[serde(default)]
+        required: bool,
+
+        #[serde(default)]
+        prompt: Option<String>,
+
+        #[serde(default)]
+        reprompt: Option<String>,
+    },
+}
+
+impl SlotData {
+    fn into_vap(self, name: String) -> NluDataSlot {
+        match self {
+            SlotData::Entity(entity) => NluDataSlot {
+                name,
+                entity,
+                required: false,
+                prompt: None,
+                reprompt: None,
+            },
+            SlotData::Full {
+                entity,
+                required,
+                prompt,
+                reprompt,
+            } => NluDataSlot {
+                name,
+                entity,
+                required,
+                prompt,
+                reprompt,
+            },
+        }
+    }
 }
 
 impl IntentData {
-    fn into_vap(self, name: String) -> NluDataIntent {
+    fn into_vap(self, name: String, scope: String) -> NluDataIntent {
         let utterances = self
             .utterances
             .into_iter()
@@ -118,28 +314,265 @@ impl IntentData {
             .slots
             .unwrap_or_default()
             .into_iter()
-            .map(|(n, e)| NluDataSlot { name: n, entity: e })
+            .map(|(n, s)| s.into_vap(n))
             .collect();
 
         NluDataIntent {
             name,
             utterances,
             slots,
+            scope,
         }
     }
 }
 
+/// A list of values (`data`), optionally with the values of another list
+/// (`list = "entity"`), or one of `regex = "..."` or
+/// `range = { min = 0, max = 10 }`.
 #[derive(Debug, Deserialize)]
 struct EntityData {
+    #[serde(default)]
+    strict: bool,
+
+    #[serde(default)]
     data: Vec<NluDataEntityData>,
+
+    #[serde(default)]
+    list: Option<String>,
+
+    #[serde(default)]
+    regex: Option<String>,
+
+    #[serde(default)]
+    range: Option<RangeData>,
+
+    /// The file it comes from, to report errors
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeData {
+    min: f64,
+    max: f64,
+
+    #[serde(default)]
+    step: Option<f64>,
 }
 
 impl EntityData {
-    pub fn into_vap(self, name: String) -> NluDataEntity {
-        NluDataEntity {
+    pub fn into_vap(self, name: String) -> Result<NluDataEntity, LoadError> {
+        let kind = match (self.list, self.regex, self.range) {
+            (None, Some(pattern), None) if self.data.is_empty() => NluDataEntityKind::Regex { pattern },
+            (None, None, Some(RangeData { min, max, step })) if self.data.is_empty() => {
+                NluDataEntityKind::Range { min, max, step }
+            }
+            (Some(entity), None, None) => NluDataEntityKind::Reference { entity },
+            (None, None, None) => NluDataEntityKind::List,
+            _ => {
+                return Err(LoadError::Conflicting {
+                    path: self.path,
+                    entity: name,
+                })
+            }
+        };
+
+        Ok(NluDataEntity {
             name,
-            strict: false,
+            strict: self.strict,
             data: self.data,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::de::from_str;
+
+    use super::*;
+
+    #[test]
+    fn loads_slots() {
+        let data: LangData = from_str(
+            r#"
+            [intents.main.set_alarm]
+            utterances = ["wake me up at $time"]
+            slots = { time = { entity = "time", required = true, prompt = "When?" } }
+
+            [intents.main.greet]
+            utterances = ["hello $name"]
+            slots = { name = "name" }
+
+            [intents.confirm.yes]
+            utterances = ["yes", "sure"]
+            "#,
+        )
+        .unwrap();
+        let nlu = data.into_nlu_data("en-US".parse::<LanguageIdentifier>().unwrap().into()).unwrap();
+
+        let slot = |intent: &str| {
+            let intent = nlu.intents.iter().find(|i| i.name == intent).unwrap();
+            intent.slots[0].clone()
+        };
+        let time = slot("set_alarm");
+        assert_eq!(time.entity, "time");
+        assert!(time.required);
+        assert_eq!(time.prompt.as_deref(), Some("When?"));
+        assert_eq!(time.reprompt, None);
+
+        let name = slot("greet");
+        assert_eq!(name.entity, "name");
+        assert!(!name.required);
+
+        let scope = |intent: &str| nlu.intents.iter().find(|i| i.name == intent).unwrap().scope.clone();
+        assert_eq!(scope("greet"), "main");
+        assert_eq!(scope("yes"), "confirm");
+    }
+
+    #[test]
+    fn loads_entities() {
+        let data: LangData = from_str(
+            r#"
+            [intents.main.travel]
+            utterances = ["take me to $city"]
+            slots = { city = "cities" }
+
+            [entities.cities]
+            strict = true
+            data = [{ value = "Madrid", synonyms = ["Villa y Corte"] }]
+
+            [entities.hometown]
+            list = "cities"
+            data = [{ value = "Soria", synonyms = [] }]
+
+            [entities.code]
+            regex = "[A-Z]{3}"
+
+            [entities.volume]
+            range = { min = 0, max = 100, step = 5 }
+            "#,
+        )
+        .unwrap();
+        let nlu = data.into_nlu_data("en-US".parse::<LanguageIdentifier>().unwrap().into()).unwrap();
+        let entity = |name: &str| nlu.entities.iter().find(|e| e.name == name).unwrap().clone();
+
+        let cities = entity("cities");
+        assert!(cities.strict);
+        assert_eq!(cities.kind, NluDataEntityKind::List);
+        assert_eq!(cities.data[0].synonyms, ["Villa y Corte"]);
+
+        let hometown = entity("hometown");
+        assert!(!hometown.strict);
+        assert_eq!(hometown.kind, NluDataEntityKind::Reference { entity: "cities".into() });
+        assert_eq!(hometown.data.len(), 1);
+
+        assert_eq!(entity("code").kind, NluDataEntityKind::Regex { pattern: "[A-Z]{3}".into() });
+        assert_eq!(
+            entity("volume").kind,
+            NluDataEntityKind::Range { min: 0.0, max: 100.0, step: Some(5.0) }
+        );
+    }
+
+    /// A scratch folder of intent files, removed afterwards
+    struct Assets(PathBuf);
+
+    impl Assets {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let root = std::env::temp_dir().join(format!("vap-load-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            for (file, content) in files {
+                let path = root.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+
+            Self(root)
+        }
+    }
+
+    impl Drop for Assets {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn loads_folders() {
+        let assets = Assets::new(
+            "folders",
+            &[
+                ("README.md", "# Not intents"),
+                ("config.json", r#"{"name": "Not intents"}"#),
+                ("entities.toml", "[entities.cities]\ndata = []\n"),
+                ("notes/todo.yaml", "- Not intents\n"),
+                ("de.toml", "[intents.main.greet]\nutterances = [\"hallo\"]\n"),
+                ("en-US.toml", "[intents.main.greet]\nutterances = [\"hello\"]\n"),
+                ("es-ES/intents.yaml", "intents:\n  main:\n    greet:\n      utterances: [\"hola\"]\n"),
+                (
+                    "es-ES/entities.json",
+                    r#"{"entities": {"cities": {"data": [{"value": "Madrid", "synonyms": []}]}}}"#,
+                ),
+            ],
+        );
+        let langs = list_langs(&assets.0).unwrap();
+        assert_eq!(langs.iter().map(|l| l.to_string()).collect::<Vec<_>>(), ["de", "en-US", "es-ES"]);
+
+        let es: LanguageIdentifier = "es-ES".parse().unwrap();
+        let nlu = load_intents(&[&es], &assets.0).unwrap();
+        assert_eq!(nlu.len(), 1);
+        assert_eq!(nlu[0].intents[0].utterances[0].text, "hola");
+        assert_eq!(nlu[0].entities[0].name, "cities");
+    }
+
+    #[test]
+    fn reports_errors() {
+        let assets = Assets::new(
+            "errors",
+            &[
+                ("en-US.toml", "[intents.main.greet]\nutterances = [\"hello\"\n"),
+                ("es-ES.yaml", "intents:\n  main:\n    greet:\n      utterances: hola\n"),
+                ("fr-FR/a.json", r#"{"entities": {"cities": {"data": []}}}"#),
+                ("fr-FR/b.json", r#"{"entities": {"cities": {"data": []}}}"#),
+                ("de-DE.toml", "[entities.code]\nregex = \"[A-Z]{3}\"\nlist = \"codes\"\n"),
+                (
+                    "it-IT.toml",
+                    "[entities.volume]\nrange = { min = 0, max = 10 }\ndata = [{ value = \"max\", synonyms = [] }]\n",
+                ),
+            ],
+        );
+        let load = |lang: &str| load_intents(&[&lang.parse().unwrap()], &assets.0).unwrap_err();
+
+        match load("en-US") {
+            LoadError::Parse { path, line, column, .. } => {
+                assert!(path.ends_with("en-US.toml"));
+                assert_eq!(line, Some(3));
+                assert!(column.is_some());
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        match load("es-ES") {
+            LoadError::Parse { path, line, .. } => {
+                assert!(path.ends_with("es-ES.yaml"));
+                assert_eq!(line, Some(4));
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        match load("fr-FR") {
+            LoadError::Duplicated { path, object } => {
+                assert!(path.ends_with("b.json"));
+                assert_eq!(object, "cities");
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        for (lang, name) in [("de-DE", "code"), ("it-IT", "volume")] {
+            match load(lang) {
+                LoadError::Conflicting { path, entity } => {
+                    assert!(path.ends_with(format!("{}.toml", lang)));
+                    assert_eq!(entity, name);
+                }
+                e => panic!("unexpected error: {}", e),
+            }
         }
     }
 }
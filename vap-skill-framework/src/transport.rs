@@ -0,0 +1,218 @@
+// How messages reach the registry: plain CoAP, or protected with a key shared
+// with the registry when the `oscore` feature is enabled.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::OnceLock;
+
+use coap::CoAPClient;
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageType, ObserveOption, Packet, RequestType as Method};
+
+#[cfg(feature = "oscore")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "oscore")]
+use coap_lite::{MessageClass, ResponseType};
+#[cfg(feature = "oscore")]
+use vap_common_skill::oscore::{RequestBinding, SecurityContext, ECHO};
+
+/// The keys used with the registry, shared by every transport of a skill
+#[cfg(feature = "oscore")]
+pub type Context = Option<Arc<Mutex<SecurityContext>>>;
+
+/// Without the `oscore` feature there are never keys
+#[cfg(not(feature = "oscore"))]
+pub type Context = Option<std::convert::Infallible>;
+
+/// What an answer must be verified against, if the request was protected
+#[cfg(feature = "oscore")]
+type Binding = Option<RequestBinding>;
+
+#[cfg(not(feature = "oscore"))]
+type Binding = Option<std::convert::Infallible>;
+
+pub struct Transport {
+    client: CoAPClient,
+    address: String,
+    context: Context,
+}
+
+impl Transport {
+    pub fn new(address: &str, context: Context) -> io::Result<Self> {
+        Ok(Self {
+            client: CoAPClient::new(address)?,
+            address: address.to_string(),
+            context,
+        })
+    }
+
+    /// Another transport to the same registry with the same keys
+    #[allow(clippy::clone_on_copy)] // Only a copy without the oscore feature
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Self::new(&self.address, self.context.clone())
+    }
+
+    /// Sends a request and waits for its answer, `path` can have a query
+    /// (e.g: `vap/skillRegistry/skills/id?uniqueAuthenticationToken=1234`).
+    /// A request the registry can't tell apart from an old one is sent again
+    /// with the Echo it asks for.
+    pub fn request(&mut self, method: Method, path: &str, payload: Option<Vec<u8>>) -> io::Result<CoapResponse> {
+        let response = self.send(method, path, payload.clone(), None)?;
+        match challenge(&response) {
+            Some(echo) => self.send(method, path, payload, Some(echo)),
+            None => Ok(response),
+        }
+    }
+
+    fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        payload: Option<Vec<u8>>,
+        echo: Option<Vec<u8>>,
+    ) -> io::Result<CoapResponse> {
+        let mut request = new_request(method, path, payload)?;
+        let binding = protect(&self.context, &mut request.message, echo)?;
+        self.client.send(&request)?;
+
+        loop {
+            let mut response = self.client.receive()?;
+            // Anything else is a late answer to an older request
+            if response.message.get_token() == request.message.get_token() {
+                unprotect(&self.context, &mut response.message, &binding)?;
+                return Ok(response);
+            }
+        }
+    }
+
+    /// Calls `handler` with every notification of `path`, which can have a
+    /// query like in [`Transport::request`].
+    #[allow(clippy::clone_on_copy)] // Only a copy without the oscore feature
+    pub fn observe<H>(&mut self, path: &str, handler: H) -> io::Result<()>
+    where
+        H: FnMut(Packet) + Send + 'static,
+    {
+        let mut request = new_request(Method::Get, path, None)?;
+        request.set_observe_flag(ObserveOption::Register);
+        let binding = protect(&self.context, &mut request.message, None)?;
+        let token = request.message.get_token().clone();
+
+        // Notifications arrive at the socket that sent the request
+        let mut client = CoAPClient::new(self.address.as_str())?;
+        client.send(&request)?;
+        let context = self.context.clone();
+        let mut handler = handler;
+        std::thread::spawn(move || loop {
+            match client.receive() {
+                Ok(response) if response.message.get_token() != &token => {}
+                Ok(mut response) => match unprotect(&context, &mut response.message, &binding) {
+                    Ok(()) => handler(response.message),
+                    Err(e) => log::warn!("Ignoring a notification: {}", e),
+                },
+                // Nothing was sent for a while, keep waiting
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                Err(e) => {
+                    log::warn!("Stopped receiving notifications: {}", e);
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// The registry takes requests with the same message id as retransmissions,
+/// so ids go up from a random start shared by every transport
+fn next_message_id() -> u16 {
+    static MESSAGE_ID: OnceLock<AtomicU16> = OnceLock::new();
+    let id = MESSAGE_ID.get_or_init(|| {
+        let mut start = [0; 2];
+        let _ = getrandom::getrandom(&mut start);
+        AtomicU16::new(u16::from_be_bytes(start))
+    });
+
+    id.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A random token, answers are matched to requests with it
+fn new_token() -> io::Result<Vec<u8>> {
+    let mut token = vec![0; 8];
+    getrandom::getrandom(&mut token).map_err(io::Error::from)?;
+    Ok(token)
+}
+
+fn new_request(method: Method, path: &str, payload: Option<Vec<u8>>) -> io::Result<CoapRequest<SocketAddr>> {
+    let mut request = CoapRequest::new();
+    request.set_method(method);
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    request.set_path(path);
+    for query in query.into_iter().flat_map(|q| q.split('&')) {
+        request.message.add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+    }
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = next_message_id();
+    request.message.set_token(new_token()?);
+    request.message.payload = payload.unwrap_or_default();
+    Ok(request)
+}
+
+/// Encrypts a request, along with the Echo the registry asked for if any
+#[cfg(feature = "oscore")]
+fn protect(context: &Context, packet: &mut Packet, echo: Option<Vec<u8>>) -> io::Result<Binding> {
+    if let Some(echo) = echo {
+        packet.add_option(CoapOption::Unknown(ECHO), echo);
+    }
+    match context {
+        Some(context) => context
+            .lock()
+            .unwrap()
+            .protect(packet, None)
+            .map(Some)
+            .map_err(io::Error::other),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "oscore")]
+fn unprotect(context: &Context, packet: &mut Packet, binding: &Binding) -> io::Result<()> {
+    match (context, binding) {
+        (Some(context), Some(binding)) => context
+            .lock()
+            .unwrap()
+            .unprotect(packet, Some(binding))
+            .map(|_| ())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        _ => Ok(()),
+    }
+}
+
+/// The Echo of an answer asking to send the request again
+#[cfg(feature = "oscore")]
+fn challenge(response: &CoapResponse) -> Option<Vec<u8>> {
+    match response.message.header.code {
+        MessageClass::Response(ResponseType::Unauthorized) => {
+            response.message.get_first_option(CoapOption::Unknown(ECHO)).cloned()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(feature = "oscore"))]
+fn challenge(_response: &CoapResponse) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(feature = "oscore"))]
+fn protect(_context: &Context, _packet: &mut Packet, _echo: Option<Vec<u8>>) -> io::Result<Binding> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "oscore"))]
+fn unprotect(_context: &Context, _packet: &mut Packet, _binding: &Binding) -> io::Result<()> {
+    Ok(())
+}
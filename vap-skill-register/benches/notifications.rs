@@ -0,0 +1,173 @@
+// Measures how long it takes for a request to reach a skill and come back.
+// `loopback_round_trip` is the cost of a single CoAP exchange with the
+// register, which is what sending requests through a loopback PUT used to add
+// on top of every notification.
+
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+
+use coap_lite::{
+    CoapOption, CoapRequest, MessageClass, MessageType, ObserveOption, Packet, RequestType as Method,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use serde::Serialize;
+use vap_common_skill::auth::TOKEN_QUERY;
+use vap_skill_register::{
+    structures::{
+        msg_notification,
+        msg_skill_request::{ClientData, RequestData, RequestDataKind},
+        Language, MsgConnect, MsgConnectResponse, MsgNotification, MsgSkillRequest,
+    },
+    Response, ResponseType, SkillRegister, SkillRegisterOut, VAP_VERSION,
+};
+
+const PORT: u16 = 5693;
+const SKILL_ID: &str = "com.example.bench_skill";
+const OBSERVE_TOKEN: &[u8] = b"obs";
+
+fn packet(method: Method, path: &str, message_id: u16, payload: Vec<u8>) -> Vec<u8> {
+    let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+    request.set_method(method);
+    request.set_path(path);
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = message_id;
+    request.message.payload = payload;
+    request.message.to_bytes().unwrap()
+}
+
+fn exchange(socket: &UdpSocket, bytes: &[u8]) -> Packet {
+    let mut buf = [0; 1500];
+    socket.send(bytes).unwrap();
+    let size = socket.recv(&mut buf).unwrap();
+    Packet::from_bytes(&buf[..size]).unwrap()
+}
+
+fn encode<T: Serialize>(msg: &T) -> Vec<u8> {
+    rmp_serde::to_vec_named(msg).unwrap()
+}
+
+/// A skill that answers every request with full confidence
+fn start_skill() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(("127.0.0.1", PORT)).unwrap();
+
+    let connect = encode(&MsgConnect {
+        id: SKILL_ID.into(),
+        name: "Bench skill".into(),
+        vap_version: VAP_VERSION.into(),
+        vap_versions: None,
+        capabilities: vec![],
+        auth_token: None,
+    });
+    let connected = exchange(&socket, &packet(Method::Post, "vap/skillRegistry/connect", 0, connect));
+    let connected: MsgConnectResponse = rmp_serde::from_read(Cursor::new(&connected.payload)).unwrap();
+    let token = connected.auth_token;
+
+    let mut observe: CoapRequest<SocketAddr> = CoapRequest::new();
+    observe.set_method(Method::Get);
+    observe.set_path(&format!("vap/skillRegistry/skills/{}", SKILL_ID));
+    if let Some(token) = &token {
+        let query = format!("{}={}", TOKEN_QUERY, token);
+        observe.message.add_option(CoapOption::UriQuery, query.into_bytes());
+    }
+    observe.set_observe_flag(ObserveOption::Register);
+    observe.message.header.set_type(MessageType::Confirmable);
+    observe.message.header.message_id = 1;
+    observe.message.set_token(OBSERVE_TOKEN.to_vec());
+    exchange(&socket, &observe.message.to_bytes().unwrap());
+
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        let mut message_id = 2u16;
+        loop {
+            let size = socket.recv(&mut buf).unwrap();
+            let received = Packet::from_bytes(&buf[..size]).unwrap();
+            let is_notification = received.get_token() == OBSERVE_TOKEN
+                && received.header.code == MessageClass::Response(ResponseType::Content);
+
+            // Anything else is the answer to one of our notifications
+            if is_notification {
+                let request: MsgSkillRequest =
+                    rmp_serde::from_read(Cursor::new(&received.payload)).unwrap();
+                let answer = encode(&MsgNotification {
+                    skill_id: SKILL_ID.into(),
+                    data: vec![msg_notification::Data::CanYouAnswer {
+                        request_id: request.request_id,
+                        confidence: 1.0,
+                    }],
+                    auth_token: token.clone(),
+                });
+
+                message_id = message_id.wrapping_add(1);
+                let path = "vap/skillRegistry/notification";
+                socket.send(&packet(Method::Post, path, message_id, answer)).unwrap();
+            }
+        }
+    });
+}
+
+fn start_register(rt: &tokio::runtime::Runtime) -> SkillRegisterOut {
+    let (register, mut stream, out) = SkillRegister::new(PORT).unwrap();
+    rt.spawn(async move { register.run().await.unwrap() });
+    rt.spawn(async move {
+        while let Ok((_, responder)) = stream.recv().await {
+            let payload = encode(&MsgConnectResponse {
+                langs: vec![Language {
+                    language: "en".into(),
+                    country: None,
+                    extra: None,
+                }],
+                vap_version: None,
+                lease: None,
+                auth_token: None,
+            });
+            let _ = responder.send(Response {
+                status: ResponseType::Created,
+                payload,
+            });
+        }
+    });
+
+    out
+}
+
+fn notifications(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut out = start_register(&rt);
+    start_skill();
+
+    let request = RequestData {
+        type_: RequestDataKind::CanAnswer,
+        intent: "hello".into(),
+        locale: "en-US".into(),
+        slots: vec![],
+    };
+    let client = ClientData {
+        system_id: "bench_client".into(),
+        capabilities: vec![],
+    };
+    let ids = [SKILL_ID.to_string()];
+
+    c.bench_function("loopback_round_trip", |b| {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(("127.0.0.1", PORT)).unwrap();
+        // Repeated message ids would be answered from the retransmission cache
+        let mut message_id = 0u16;
+        b.iter(|| {
+            message_id = message_id.wrapping_add(1);
+            exchange(&socket, &packet(Method::Get, ".well-known/core", message_id, vec![]))
+        })
+    });
+
+    c.bench_function("skills_answerable", |b| {
+        b.iter(|| {
+            let answers =
+                rt.block_on(out.skills_answerable(&ids, request.clone(), client.clone()));
+            assert_eq!(answers.len(), 1);
+        })
+    });
+}
+
+criterion_group!(benches, notifications);
+criterion_main!(benches);
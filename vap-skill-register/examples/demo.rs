@@ -36,6 +36,10 @@ impl MyData {
                             country: Some("US".to_string()),
                             extra: None,
                         }],
+                        // The register adds the version and the token
+                        vap_version: None,
+                        lease: None,
+                        auth_token: None,
                     })
                     .unwrap();
                     
@@ -54,6 +58,16 @@ impl MyData {
                         payload: vec![],
                     }
                 }
+                SkillRegisterMessage::SetScopes(m) => {
+                    println!(
+                        "{} enables {:?} and disables {:?}",
+                        m.skill_id, m.enable, m.disable
+                    );
+                    Response {
+                        status: ResponseType::Changed,
+                        payload: vec![],
+                    }
+                }
                 SkillRegisterMessage::Query(m) => {
                     println!("{} wants to query this data: {:?}", m.skill_id, m.data);
 
@@ -85,6 +99,7 @@ impl MyData {
                                     QueryDataCapability {
                                         name: x.name,
                                         code,
+                                        version: 1,
                                         data: payload,
                                     }
                                 })
@@ -155,7 +170,7 @@ impl MyDataOut {
             .await
             .unwrap();
 
-        sender.send(RequestResponse{ code: 200}).unwrap();
+        sender.send(RequestResponse{ code: 200, capability_errors: vec![] }).unwrap();
         println!("Skill answer: {:?}", answer);
     }
 }
@@ -0,0 +1,132 @@
+// Choose which skill gets to handle a request
+
+use std::collections::HashMap;
+
+use vap_common_skill::structures::msg_skill_request::RequestData;
+
+/// A skill which was asked whether it could handle a request
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub skill_id: String,
+    /// How sure the skill is about being able to handle the request (0 to 1)
+    pub confidence: f32,
+    /// Skills registered earlier have lower numbers
+    pub registration_order: u64,
+}
+
+/// Decides which skill will handle a request out of those that answered
+/// canYouAnswer. Implement this to provide your own strategy.
+pub trait Arbiter {
+    /// Returns the id of the chosen skill, or `None` if nobody should handle
+    /// it. `candidates` only contains skills that answered in time.
+    fn choose(&self, request: &RequestData, candidates: &[Candidate]) -> Option<String>;
+}
+
+/// The candidate with the highest confidence, ties are broken in favour of
+/// the skill that registered first.
+pub fn best_candidate(candidates: &[Candidate]) -> Option<&Candidate> {
+    candidates.iter().min_by(|a, b| {
+        b.confidence
+            .total_cmp(&a.confidence)
+            .then(a.registration_order.cmp(&b.registration_order))
+    })
+}
+
+/// Chooses the skill with the highest confidence, as long as it is above 0.
+#[derive(Debug, Clone, Default)]
+pub struct HighestConfidence;
+
+impl Arbiter for HighestConfidence {
+    fn choose(&self, _request: &RequestData, candidates: &[Candidate]) -> Option<String> {
+        best_candidate(candidates)
+            .filter(|c| c.confidence > 0.0)
+            .map(|c| c.skill_id.clone())
+    }
+}
+
+/// Chooses the skill with the highest confidence if it reaches `threshold`,
+/// otherwise the `fallback` skill is chosen (e.g: one that asks the user to
+/// repeat).
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    pub threshold: f32,
+    pub fallback: Option<String>,
+}
+
+impl Arbiter for Threshold {
+    fn choose(&self, _request: &RequestData, candidates: &[Candidate]) -> Option<String> {
+        match best_candidate(candidates) {
+            Some(c) if c.confidence >= self.threshold => Some(c.skill_id.clone()),
+            _ => self.fallback.clone(),
+        }
+    }
+}
+
+/// Honours the user choices: when an intent has a pinned skill and that skill
+/// says it can handle it, it wins. Anything else is left to `inner`.
+pub struct Pinned<A: Arbiter> {
+    /// Intent name -> skill id
+    pub preferences: HashMap<String, String>,
+    pub inner: A,
+}
+
+impl<A: Arbiter> Pinned<A> {
+    pub fn new(inner: A) -> Self {
+        Self {
+            preferences: HashMap::new(),
+            inner,
+        }
+    }
+
+    /// Always use `skill_id` for `intent` if it can answer
+    pub fn pin<S1: Into<String>, S2: Into<String>>(&mut self, intent: S1, skill_id: S2) {
+        self.preferences.insert(intent.into(), skill_id.into());
+    }
+}
+
+impl<A: Arbiter> Arbiter for Pinned<A> {
+    fn choose(&self, request: &RequestData, candidates: &[Candidate]) -> Option<String> {
+        let pinned = self.preferences.get(&request.intent).and_then(|skill_id| {
+            candidates
+                .iter()
+                .find(|c| &c.skill_id == skill_id && c.confidence > 0.0)
+        });
+
+        match pinned {
+            Some(c) => Some(c.skill_id.clone()),
+            None => self.inner.choose(request, candidates),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{candidate, request};
+
+    #[test]
+    fn strategies() {
+        let candidates = [
+            candidate("com.example.late", 0.8, 2),
+            candidate("com.example.early", 0.8, 1),
+            candidate("com.example.unsure", 0.3, 0),
+        ];
+
+        let chosen = HighestConfidence.choose(&request("weather"), &candidates);
+        assert_eq!(chosen.as_deref(), Some("com.example.early"));
+
+        let threshold = Threshold {
+            threshold: 0.9,
+            fallback: Some("com.example.fallback".into()),
+        };
+        let chosen = threshold.choose(&request("weather"), &candidates);
+        assert_eq!(chosen.as_deref(), Some("com.example.fallback"));
+
+        let mut pinned = Pinned::new(HighestConfidence);
+        pinned.pin("weather", "com.example.unsure");
+        let chosen = pinned.choose(&request("weather"), &candidates);
+        assert_eq!(chosen.as_deref(), Some("com.example.unsure"));
+
+        assert_eq!(HighestConfidence.choose(&request("weather"), &[]), None);
+    }
+}
@@ -0,0 +1,129 @@
+// How the skill register should be set up
+
+#[cfg(feature = "oscore")]
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{Error, SkillRegister, SkillRegisterOut, SkillRegisterStream};
+
+/// The port in which VAP skill registers listen by default
+pub const DEFAULT_PORT: u16 = 5683;
+
+/// Everything that can be configured about a skill register. Every method
+/// consumes the configuration and returns it, so that they can be chained.
+/// # Examples
+/// ```ignore
+/// let (register, stream, out) = SkillRegisterConfig::new()
+///     .address("::".parse().unwrap())
+///     .port(5683)
+///     .build()?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct SkillRegisterConfig {
+    address: IpAddr,
+    port: u16,
+    pub(crate) dual_stack: bool,
+    pub(crate) stream_capacity: usize,
+    pub(crate) request_timeout: Duration,
+    pub(crate) skill_lease: Option<Duration>,
+    pub(crate) token_file: Option<PathBuf>,
+    /// Skill id -> Master secret
+    #[cfg(feature = "oscore")]
+    pub(crate) keys: HashMap<String, Vec<u8>>,
+}
+
+impl Default for SkillRegisterConfig {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            dual_stack: true,
+            stream_capacity: 20,
+            request_timeout: Duration::from_secs(10),
+            skill_lease: None,
+            token_file: None,
+            #[cfg(feature = "oscore")]
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl SkillRegisterConfig {
+    /// A configuration listening on `127.0.0.1` and the default port
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The address to listen on, use `0.0.0.0` or `::` to accept skills
+    /// from other devices.
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// The port to listen on, use 0 to let the OS choose one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// When listening on an IPv6 address, whether IPv4 skills are accepted
+    /// too. Ignored for IPv4 addresses.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    /// How many messages from skills can be waiting on the stream before
+    /// the register stops accepting more.
+    pub fn stream_capacity(mut self, capacity: usize) -> Self {
+        self.stream_capacity = capacity;
+        self
+    }
+
+    /// How much skills have by default to answer a request before it is
+    /// considered failed and forgotten.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Skills that don't send anything (heartbeats included) in this time are
+    /// considered gone and removed, `None` (the default) keeps them until
+    /// they close or stop observing. Skills learn the lease when they connect
+    /// and the skill framework sends a heartbeat every third of it (every 20
+    /// seconds at most). Building the register fails with [`Error::ZeroLease`]
+    /// if the lease is zero.
+    pub fn skill_lease(mut self, lease: Option<Duration>) -> Self {
+        self.skill_lease = lease;
+        self
+    }
+
+    /// Where to keep the ids of the skills that ever connected along with
+    /// their tokens, `None` forgets them when the register is dropped (and
+    /// skills will receive new tokens next time).
+    pub fn token_file(mut self, path: Option<PathBuf>) -> Self {
+        self.token_file = path;
+        self
+    }
+
+    /// The skill `skill_id` shares `master_secret` with the register, all of
+    /// its messages must be protected with it. Once any skill has a key, plain
+    /// messages are refused (except for discovery).
+    #[cfg(feature = "oscore")]
+    pub fn psk<S: Into<String>>(mut self, skill_id: S, master_secret: Vec<u8>) -> Self {
+        self.keys.insert(skill_id.into(), master_secret);
+        self
+    }
+
+    pub(crate) fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// Creates the skill register, see [`SkillRegister::new`] for more info.
+    pub fn build(self) -> Result<(SkillRegister, SkillRegisterStream, SkillRegisterOut), Error> {
+        SkillRegister::with_config(self)
+    }
+}
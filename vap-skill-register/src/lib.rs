@@ -1,37 +1,93 @@
 //! The reference implementation of the VAP skill register.
 
+mod arbiter;
+mod config;
 mod method_handlers;
+mod policy;
+mod registry;
+mod security;
+mod server;
+#[cfg(test)]
+mod test_support;
+mod validation;
 mod vars;
 
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, Barrier, Mutex as SyncMutex};
-use std::thread;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
 
-use coap::{CoAPClient, Server};
 use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
 use futures::{
     channel::{mpsc, oneshot},
+    future,
     lock::Mutex,
-    SinkExt, StreamExt,
+    stream::FuturesUnordered,
+    Stream, StreamExt,
 };
+use registry::{SharedConverters, SharedSkills, SharedTokens};
+use security::Security;
+use server::{Notifier, ServerSocket};
 use thiserror::Error;
-use tokio::runtime::Runtime;
-use vap_common_skill::structures::msg_skill_request::{ClientData, RequestData};
+use tokio::time::Instant;
+use vap_common_skill::auth::TokenStore;
+use vap_common_skill::capability::{CapabilityError, Converters};
+use vap_common_skill::structures::msg_skill_request::{ClientData, RequestData, RequestDataKind};
 use vap_common_skill::structures::*;
 
+pub use arbiter::{best_candidate, Arbiter, Candidate, HighestConfidence, Pinned, Threshold};
 pub use coap_lite::ResponseType;
+pub use config::{SkillRegisterConfig, DEFAULT_PORT};
+pub use policy::{AllowAll, AllowList, Approval, AskUser, ConnectAttempt, ConnectionPolicy, DenyList, RateLimit, Verdict};
+pub use registry::SkillRecord;
+pub use vap_common_skill::capability;
 pub use vap_common_skill::structures;
-pub use vars::{SYSTEM_SELF_ID, VAP_VERSION};
+pub use vap_common_skill::version::{Version, VersionRange};
+pub use vars::{SUPPORTED_VERSIONS, SYSTEM_SELF_ID, VAP_VERSION};
 
 type RequestId = u64;
-type SharedPending<D> = Arc<Mutex<HashMap<RequestId, oneshot::Sender<D>>>>;
+type SharedPending<D> = Arc<Mutex<HashMap<RequestId, Pending<D>>>>;
+
+/// A request sent to a skill which is waiting for an answer
+struct Pending<D> {
+    /// Only this skill can answer
+    skill_id: String,
+    sender: oneshot::Sender<D>,
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("A Oneshot channel was closed")]
     ClosedChannel,
+
+    #[error("IO")]
+    IO(#[from] std::io::Error),
+
+    #[error("Failed to encode a message")]
+    Encode(#[from] rmp_serde::encode::Error),
+
+    #[error("The skill {0} is not observing its resource, it can't receive requests")]
+    SkillNotObserving(String),
+
+    #[error("A CoAP packet couldn't be encoded, it is probably too big")]
+    PacketTooBig,
+
+    #[error("The skill didn't answer in time")]
+    Timeout,
+
+    #[error("No skill was chosen to handle the request")]
+    NoSkillChosen,
+
+    #[error("The skill lease can't be zero")]
+    ZeroLease,
+
+    #[error("The register isn't running, nothing can be sent to skills")]
+    NotServing,
+
+    #[cfg(feature = "oscore")]
+    #[error("A message couldn't be protected or verified: {0}")]
+    Oscore(#[from] vap_common_skill::oscore::Error),
 }
 
 pub struct Response {
@@ -42,14 +98,16 @@ pub struct Response {
 /// Will handle incoming and outgoing messages to and from the skills, also
 /// keeps account of the skills registered on the system.
 pub struct SkillRegister {
-    ip_address: String,
+    socket: ServerSocket,
     in_send: mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
     pending_requests: SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
     pending_can_you: SharedPending<f32>,
-    current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
-    barrier: Arc<Barrier>,
-    _clnt_thrd: thread::JoinHandle<()>,
-    self_send: mpsc::Sender<(String, Vec<u8>)>,
+    current_skills: SharedSkills,
+    tokens: SharedTokens,
+    notifier: Arc<Notifier>,
+    converters: SharedConverters,
+    skill_lease: Option<Duration>,
+    policy: Box<dyn ConnectionPolicy + Send + Sync>,
 }
 
 /// A notification received from a skill, can contain data for different VAP clients
@@ -65,10 +123,13 @@ pub struct NotificationData {
     pub capabilities: Vec<structures::PlainCapability>,
 }
 
-/// A message received from a skill
+/// A message received from a skill. `Close` is also received when a skill is
+/// gone without closing (it stopped observing or stopped sending heartbeats).
 pub enum SkillRegisterMessage {
     Connect(MsgConnect),
     RegisterIntents(MsgRegisterIntents),
+    /// Some scopes of intents were enabled or disabled
+    SetScopes(MsgSetScopes),
     Notification(Notification),
     Query(MsgQuery),
     Close(MsgSkillClose),
@@ -88,101 +149,130 @@ impl SkillRegister {
     /// 2. The skill stream, which will receive all the messages from the skills.
     /// 3. The skill out, which you can use to send messages to the skills.
     /// # Arguments
-    /// * `port` - The port for the skill register to listen CoAP messages on.    
+    /// * `port` - The port for the skill register to listen CoAP messages on.
+    ///
+    /// It will only listen on `127.0.0.1`, use [`SkillRegisterConfig`] for anything else.
     pub fn new(port: u16) -> Result<(Self, SkillRegisterStream, SkillRegisterOut), Error> {
-        let (in_send, in_recv) = mpsc::channel(20);
+        SkillRegisterConfig::new().port(port).build()
+    }
+
+    /// Same as [`SkillRegister::new`] but with a custom configuration.
+    pub fn with_config(config: SkillRegisterConfig) -> Result<(Self, SkillRegisterStream, SkillRegisterOut), Error> {
+        if config.skill_lease == Some(Duration::ZERO) {
+            return Err(Error::ZeroLease);
+        }
+
+        let (in_send, in_recv) = mpsc::channel(config.stream_capacity);
         let pending_requests = Arc::new(Mutex::new(HashMap::new()));
         let pending_can_you = Arc::new(Mutex::new(HashMap::new()));
-        let barrier = Arc::new(Barrier::new(2));
-
-        let (self_send, mut self_recv) = mpsc::channel(20);
-        let barrier2 = barrier.clone();
-        let _clnt_thrd = thread::spawn(move || {
-            // In Linux we need a second thread to to send to ourselves, otherwise
-            // what would be a non-blocking operation, tries to block, which
-            // returns an error.
-            Runtime::new().unwrap().block_on(async move {
-                let ip_address = format!("127.0.0.1:{}", port);
-                let mut client = CoAPClient::new(&ip_address).unwrap();
-                barrier2.wait(); // Make sure we are not sending anything before the server is ready
-
-                loop {
-                    let (name, data): (String, Vec<u8>) = self_recv.next().await.unwrap();
-                    println!("Size: {}", data.len());
-                    let resp = client
-                        .request_path(
-                            &format!("vap/skillRegistry/skills/{}", name),
-                            Method::Put,
-                            Some(data),
-                            None,
-                        )
-                        .unwrap();
-                    assert_eq!(resp.get_status(), &coap_lite::ResponseType::Valid);
-                }
-            });
-        });
+        let (socket, notifier) = ServerSocket::bind(config.socket_addr(), config.dual_stack, Security::new(&config))?;
+        let current_skills = Arc::new(SyncMutex::new(HashMap::new()));
+        let converters = Arc::new(SyncMutex::new(Converters::new()));
+        let tokens = match &config.token_file {
+            Some(path) => TokenStore::open(path)?,
+            None => TokenStore::in_memory(),
+        };
 
         Ok((
             SkillRegister {
-                ip_address: format!("127.0.0.1:{}", port),
+                socket,
                 in_send,
                 pending_requests: pending_requests.clone(),
                 pending_can_you: pending_can_you.clone(),
-                current_skills: Arc::new(SyncMutex::new(HashMap::new())),
-                barrier,
-                _clnt_thrd,
-                self_send: self_send.clone(),
+                current_skills: current_skills.clone(),
+                tokens: Arc::new(SyncMutex::new(tokens)),
+                notifier: notifier.clone(),
+                converters: converters.clone(),
+                skill_lease: config.skill_lease,
+                policy: Box::new(AllowAll),
             },
             SkillRegisterStream { stream_in: in_recv },
             SkillRegisterOut {
                 pending_requests,
-                self_send,
+                notifier,
                 pending_can_you,
                 next_request: RefCell::new(0),
+                timeout: config.request_timeout,
+                current_skills,
+                converters,
+                arbiter: Box::new(HighestConfidence),
             },
         ))
     }
 
+    /// The address the register listens on, useful when the port was 0 and
+    /// the system chose one.
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.socket.local_addr()
+    }
+
+    /// All the skills connected right now, in the order they registered
+    pub fn list_skills(&self) -> Vec<SkillRecord> {
+        registry::list_skills(&self.current_skills)
+    }
+
+    /// What we know about a skill, `None` if it is not connected
+    pub fn skill_info(&self, id: &str) -> Option<SkillRecord> {
+        registry::skill_info(&self.current_skills, id)
+    }
+
+    /// Changes which skills can connect, by default [`AllowAll`] is used.
+    pub fn set_policy<P: ConnectionPolicy + Send + Sync + 'static>(&mut self, policy: P) {
+        self.policy = Box::new(policy);
+    }
+
     /// Call this function and await it for the rest of the program, this handles
     /// sending and receiving messages from the skills. Stopping this means no more
     /// communication, and even dropped channels.
     pub async fn run(self) -> Result<(), Error> {
+        #[allow(clippy::too_many_arguments)]
         async fn perform(
             request: CoapRequest<SocketAddr>,
+            sender: Option<String>,
             mut in_send: mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
             pending_requests: &SharedPending<(
                 Vec<PlainCapability>,
                 oneshot::Sender<RequestResponse>,
             )>,
             pending_can_you: &SharedPending<f32>,
-            current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
-            mut self_send: mpsc::Sender<(String, Vec<u8>)>,
+            current_skills: SharedSkills,
+            tokens: &SharedTokens,
+            notifier: &Notifier,
+            converters: &SharedConverters,
+            policy: &(dyn ConnectionPolicy + Send + Sync),
+            skill_lease: Option<Duration>,
         ) -> Option<CoapResponse> {
             match *request.get_method() {
-                Method::Get => method_handlers::on_get(request, &mut in_send, current_skills).await,
+                Method::Get => {
+                    method_handlers::on_get(
+                        request,
+                        sender.as_deref(),
+                        &mut in_send,
+                        current_skills,
+                        tokens,
+                        notifier,
+                        converters,
+                        pending_requests,
+                    )
+                    .await
+                }
                 Method::Post => {
                     method_handlers::on_post(
                         request,
+                        sender.as_deref(),
                         &mut in_send,
-                        &mut self_send,
                         &current_skills,
+                        tokens,
+                        policy,
+                        skill_lease,
                         pending_can_you,
                         pending_requests,
                     )
                     .await
                 }
                 Method::Delete => {
-                    method_handlers::on_delete(request, &mut in_send, current_skills).await
-                }
-                Method::Put =>
-                // Puts are needed so that an observe update is produced
-                {
-                    if request.get_path().starts_with("vap/skillRegistry/skill") {
-                        respond(request.response, coap_lite::ResponseType::Valid, vec![])
-                    }
-                    else {
-                        respond(request.response, coap_lite::ResponseType::MethodNotAllowed, vec![])
-                    }
+                    method_handlers::on_delete(request, sender.as_deref(), &mut in_send, current_skills, tokens, notifier)
+                        .await
                 }
 
                 _ => {
@@ -196,27 +286,58 @@ impl SkillRegister {
             }
         }
 
-        let mut server = Server::new(&self.ip_address).unwrap();
-        server.enable_all_coap(0);
-
-        // The server is ready, we can start to send requests to itself
-        // This is added because of an issue with the client trying to acces the
-        // server too soon on Linux.
-        self.barrier.wait();
-        server
-            .run(|request| {
+        let SkillRegister {
+            socket,
+            in_send,
+            pending_requests,
+            pending_can_you,
+            current_skills,
+            tokens,
+            notifier,
+            converters,
+            skill_lease,
+            policy,
+        } = self;
+
+        let serve = socket.serve(
+            |request, sender| {
                 perform(
                     request,
-                    self.in_send.clone(),
-                    &self.pending_requests,
-                    &self.pending_can_you,
-                    self.current_skills.clone(),
-                    self.self_send.clone(),
+                    sender,
+                    in_send.clone(),
+                    &pending_requests,
+                    &pending_can_you,
+                    current_skills.clone(),
+                    &tokens,
+                    &notifier,
+                    &converters,
+                    policy.as_ref(),
+                    skill_lease,
                 )
-            })
-            .await
-            .unwrap();
-        Ok(())
+            },
+            |skill_id| registry::skill_gone(&current_skills, &notifier, &in_send, &skill_id),
+        );
+
+        // Remove skills which haven't been heard from in a while
+        let expire = async {
+            match skill_lease {
+                Some(lease) => {
+                    let mut interval = tokio::time::interval(lease / 2);
+                    loop {
+                        interval.tick().await;
+                        for skill_id in registry::expired_skills(&current_skills, lease) {
+                            registry::skill_gone(&current_skills, &notifier, &in_send, &skill_id);
+                        }
+                    }
+                }
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            res = serve => res,
+            () = expire => Ok(()),
+        }
     }
 }
 
@@ -226,6 +347,8 @@ pub struct NotificationResponse {
     pub client_id: String,
     /// Response code of the request. Use coap codes (e.g: 200 for success, 404 for not found)
     pub code: u16,
+    /// Capabilities the client couldn't receive, they are sent back to the skill
+    pub capability_errors: Vec<CapabilityError>,
 }
 
 /// Whether a notification could be handled or some problem arised
@@ -233,6 +356,8 @@ pub struct NotificationResponse {
 pub struct RequestResponse {
     /// Use coap codes (e.g: 200 for success, 404 for not found)
     pub code: u16,
+    /// Capabilities the client couldn't receive, they are sent back to the skill
+    pub capability_errors: Vec<CapabilityError>,
 }
 
 /// An object for sending messages to skills
@@ -240,72 +365,218 @@ pub struct SkillRegisterOut {
     pending_requests: SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
     pending_can_you: SharedPending<f32>,
     next_request: RefCell<RequestId>,
-    self_send: mpsc::Sender<(String, Vec<u8>)>,
+    notifier: Arc<Notifier>,
+    timeout: Duration,
+    current_skills: SharedSkills,
+    converters: SharedConverters,
+    arbiter: Box<dyn Arbiter + Send>,
+}
+
+/// Waits for the answer of a skill, if it doesn't arrive in time the request
+/// is forgotten, so that a late answer can be told apart.
+async fn wait_answer<D>(
+    receiver: oneshot::Receiver<D>,
+    pending: &SharedPending<D>,
+    request_id: RequestId,
+    deadline: Instant,
+) -> Result<D, Error> {
+    match tokio::time::timeout_at(deadline, receiver).await {
+        Ok(answer) => answer.map_err(|_| Error::ClosedChannel),
+        Err(_) => {
+            pending.lock().await.remove(&request_id);
+            Err(Error::Timeout)
+        }
+    }
+}
+
+/// Asks a skill whether it can answer a request
+async fn ask_can_you_answer(
+    notifier: &Notifier,
+    id: String,
+    request: RequestData,
+    request_id: RequestId,
+    client: ClientData,
+    pending_can_you: &SharedPending<f32>,
+    deadline: Instant,
+) -> Result<MsgNotification, Error> {
+    let msg = MsgSkillRequest {
+        client,
+        request_id,
+        request,
+    };
+    let data = rmp_serde::to_vec(&msg)?;
+
+    // The answer can arrive as soon as the notification is sent
+    let (sender, receiver) = oneshot::channel();
+    let pending = Pending {
+        skill_id: id.clone(),
+        sender,
+    };
+    pending_can_you.lock().await.insert(request_id, pending);
+    if let Err(e) = notifier.notify(&id, data) {
+        pending_can_you.lock().await.remove(&request_id);
+        return Err(e);
+    }
+    let a = wait_answer(receiver, pending_can_you, request_id, deadline).await?;
+
+    Ok(MsgNotification {
+        skill_id: id,
+        data: vec![msg_notification::Data::CanYouAnswer {
+            request_id,
+            confidence: a,
+        }],
+        auth_token: None,
+    })
 }
 
 impl SkillRegisterOut {
-    /// Returns how confident skills registered for some request are in being able to handle it
+    /// Returns how confident skills registered for some request are in being able to handle it,
+    /// skills that don't answer in the default time (see [`SkillRegisterConfig::request_timeout`])
+    /// or can't be asked are left out, use [`SkillRegisterOut::skills_answerable_stream`] to know
+    /// why.
     pub async fn skills_answerable(
         &mut self,
         ids: &[String],
         request: RequestData,
         client: ClientData,
     ) -> Vec<MsgNotification> {
-        // TODO: Respond to the notification
-        async fn send_msg(
-            self_send: &mut mpsc::Sender<(String, Vec<u8>)>,
-            id: &str,
-            request: RequestData,
-            request_id: RequestId,
-            client: ClientData,
-            pending_can_you: &SharedPending<f32>,
-        ) -> Result<MsgNotification, Error> {
-            let msg = MsgSkillRequest {
-                client,
-                request_id,
-                request,
-            };
-            let data = rmp_serde::to_vec(&msg).unwrap();
-            self_send.send((id.into(), data)).await.unwrap();
-
-            let (sender, receiver) = oneshot::channel();
-            pending_can_you.lock().await.insert(request_id, sender);
-            let a = receiver.await.unwrap();
-
-            Ok(MsgNotification {
-                skill_id: id.to_string(),
-                data: vec![msg_notification::Data::CanYouAnswer {
-                    request_id,
-                    confidence: a,
-                }],
-            })
-        }
+        let timeout = self.timeout;
+        self.skills_answerable_with_timeout(ids, request, client, timeout).await
+    }
 
-        let mut answers = Vec::new();
-        let new_id = self.get_id();
-        for id in ids {
-            match send_msg(
-                &mut self.self_send,
-                id,
-                request.clone(),
-                new_id,
-                client.clone(),
-                &self.pending_can_you,
-            )
+    /// Same as [`SkillRegisterOut::skills_answerable`] but skills have `timeout` to answer.
+    pub async fn skills_answerable_with_timeout(
+        &mut self,
+        ids: &[String],
+        request: RequestData,
+        client: ClientData,
+        timeout: Duration,
+    ) -> Vec<MsgNotification> {
+        self.skills_answerable_stream(ids, request, client, timeout)
+            .filter_map(|(_, answer)| future::ready(answer.ok()))
+            .collect()
             .await
-            {
-                Ok(resp) => {
-                    println!("We received the notification: {:?}", resp);
-                    answers.push(resp);
-                }
-                Err(e) => {
-                    // TODO: What to do with the errors?
-                    println!("Error receiving notifications: {:?}", e);
-                }
-            }
-        }
+    }
+
+    /// Asks all the skills at the same time and returns their answers as
+    /// they arrive, along with the id of the skill. Skills that couldn't be
+    /// asked (e.g: [`Error::SkillNotObserving`]) or didn't answer in time
+    /// ([`Error::Timeout`]) give an error. The stream ends once every skill
+    /// has answered or once `timeout` has passed, whatever happens first.
+    pub fn skills_answerable_stream<'a>(
+        &'a self,
+        ids: &[String],
+        request: RequestData,
+        client: ClientData,
+        timeout: Duration,
+    ) -> impl Stream<Item = (String, Result<MsgNotification, Error>)> + 'a {
+        let deadline = Instant::now() + timeout;
+        ids.iter()
+            .map(|id| {
+                let answer = ask_can_you_answer(
+                    &self.notifier,
+                    id.clone(),
+                    request.clone(),
+                    self.get_id(),
+                    registry::client_for_skill(&self.current_skills, &self.converters, id, client.clone()),
+                    &self.pending_can_you,
+                    deadline,
+                );
+                let id = id.clone();
+                async move { (id, answer.await) }
+            })
+            .collect::<FuturesUnordered<_>>()
+    }
+
+    /// All the skills connected right now, in the order they registered
+    pub fn list_skills(&self) -> Vec<SkillRecord> {
+        registry::list_skills(&self.current_skills)
+    }
+
+    /// What we know about a skill, `None` if it is not connected
+    pub fn skill_info(&self, id: &str) -> Option<SkillRecord> {
+        registry::skill_info(&self.current_skills, id)
+    }
+
+    /// How capabilities too new for a skill are turned into older versions,
+    /// by default none can be converted. They are used on the capabilities
+    /// of the clients in requests and on the answers to queries.
+    pub fn set_converters(&mut self, converters: Converters) {
+        *self.converters.lock().unwrap() = converters;
+    }
 
-        answers
+    /// Prepares capabilities for a skill, those it can't handle are left out
+    /// and their errors returned. Capabilities for skills that are not
+    /// connected are left untouched.
+    pub fn adapt_for_skill(
+        &self,
+        skill_id: &str,
+        capabilities: Vec<PlainCapability>,
+    ) -> (Vec<PlainCapability>, Vec<CapabilityError>) {
+        registry::adapt_for_skill(&self.current_skills, &self.converters, skill_id, capabilities)
+    }
+
+    /// Changes how [`SkillRegisterOut::dispatch`] chooses a skill, by default
+    /// [`HighestConfidence`] is used.
+    pub fn set_arbiter<A: Arbiter + Send + 'static>(&mut self, arbiter: A) {
+        self.arbiter = Box::new(arbiter);
+    }
+
+    /// Asks every skill that can recognize the intent of the request right
+    /// now (see [`SkillRecord::enabled_intents`]) whether it can handle it,
+    /// lets the arbiter choose one of them and sends the request to it.
+    /// Returns the id of the chosen skill along with its answer.
+    pub async fn dispatch(
+        &mut self,
+        request: RequestData,
+        client: ClientData,
+    ) -> Result<(String, Vec<PlainCapability>, oneshot::Sender<RequestResponse>), Error> {
+        let ids: Vec<String> = self
+            .current_skills
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|r| r.enabled_intents().contains(&request.intent))
+            .map(|r| r.id.clone())
+            .collect();
+        let can_answer = RequestData {
+            type_: RequestDataKind::CanAnswer,
+            ..request.clone()
+        };
+        let answers = self.skills_answerable(&ids, can_answer, client.clone()).await;
+
+        let candidates: Vec<Candidate> = {
+            let skills = self.current_skills.lock().unwrap();
+            answers
+                .into_iter()
+                .filter_map(|answer| {
+                    let confidence = answer.data.iter().find_map(|d| match d {
+                        msg_notification::Data::CanYouAnswer { confidence, .. } => Some(*confidence),
+                        _ => None,
+                    })?;
+                    let registration_order = skills.get(&answer.skill_id)?.registration_order;
+
+                    Some(Candidate {
+                        skill_id: answer.skill_id,
+                        confidence,
+                        registration_order,
+                    })
+                })
+                .collect()
+        };
+
+        let skill_id = self
+            .arbiter
+            .choose(&request, &candidates)
+            .ok_or(Error::NoSkillChosen)?;
+        let msg = MsgSkillRequest {
+            request_id: 0, // Filled by activate_skill
+            client,
+            request,
+        };
+        let (capabilities, responder) = self.activate_skill(skill_id.clone(), msg).await?;
+
+        Ok((skill_id, capabilities, responder))
     }
 
     fn get_id(&self) -> RequestId {
@@ -316,23 +587,44 @@ impl SkillRegisterOut {
         id
     }
 
-    /// Sends a request to a skill
+    /// Sends a request to a skill, fails with [`Error::Timeout`] if the skill
+    /// doesn't answer in the default time (see [`SkillRegisterConfig::request_timeout`]).
     pub async fn activate_skill(
+        &mut self,
+        name: String,
+        msg: MsgSkillRequest,
+    ) -> Result<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>), Error> {
+        let timeout = self.timeout;
+        self.activate_skill_with_timeout(name, msg, timeout).await
+    }
+
+    /// Same as [`SkillRegisterOut::activate_skill`] but the skill has `timeout` to answer.
+    pub async fn activate_skill_with_timeout(
         &mut self,
         name: String,
         mut msg: MsgSkillRequest,
+        timeout: Duration,
     ) -> Result<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>), Error> {
         // TODO: Respond to the notification
         let req_id = self.get_id();
         msg.request_id = req_id;
+        msg.client = registry::client_for_skill(&self.current_skills, &self.converters, &name, msg.client);
         let (sender, receiver) = oneshot::channel();
-        let data = rmp_serde::to_vec(&msg).unwrap();
-        self.self_send.send((name, data)).await.unwrap();
-
-        self.pending_requests.lock().await.insert(req_id, sender);
+        let data = rmp_serde::to_vec(&msg)?;
+
+        // The answer can arrive as soon as the notification is sent
+        let pending = Pending {
+            skill_id: name.clone(),
+            sender,
+        };
+        self.pending_requests.lock().await.insert(req_id, pending);
+        if let Err(e) = self.notifier.notify(&name, data) {
+            self.pending_requests.lock().await.remove(&req_id);
+            return Err(e);
+        }
 
-        let resp_data = receiver.await.unwrap();
-        Ok(resp_data)
+        let deadline = Instant::now() + timeout;
+        wait_answer(receiver, &self.pending_requests, req_id, deadline).await
     }
 }
 
@@ -344,7 +636,7 @@ pub struct SkillRegisterStream {
 impl SkillRegisterStream {
     /// Await this on a loop to get notifications from skills
     /// # Examples
-    /// ```
+    /// ```ignore
     /// loop {
     ///     let (msg, response) = skill_register_stream.recv().await.unwrap();
     /// }
@@ -352,6 +644,6 @@ impl SkillRegisterStream {
     pub async fn recv(
         &mut self,
     ) -> Result<(SkillRegisterMessage, oneshot::Sender<Response>), Error> {
-        Ok(self.stream_in.next().await.unwrap())
+        self.stream_in.next().await.ok_or(Error::ClosedChannel)
     }
 }
@@ -3,30 +3,97 @@ use std::net::SocketAddr;
 
 use crate::{respond, Response, SkillRegisterMessage};
 
-use coap_lite::{CoapRequest, CoapResponse, ResponseType};
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, ResponseType};
 use futures::{channel::{mpsc, oneshot}, SinkExt};
-use rmp_serde::from_read;
+use rmp_serde::{from_read, to_vec_named};
 use serde::de::DeserializeOwned;
+use vap_common_skill::auth::TOKEN_QUERY;
+use vap_common_skill::structures::VapError;
 
 pub async fn wait_response<F>(
     receiver: oneshot::Receiver<Response>,
     resp: Option<CoapResponse>,
     cb: F
 ) -> Option<CoapResponse> where
-F: FnOnce(&Response) {
+F: FnOnce(&mut Response) {
     match receiver.await {
-        Ok(resp_data) => {
-            cb(&resp_data);
+        Ok(mut resp_data) => {
+            cb(&mut resp_data);
             respond(resp, resp_data.status, resp_data.payload)
         }
         Err(_) => {
-            None
+            println!("The answer for a request was dropped");
+            respond_error(resp, VapError::internal())
         }
-    }  
+    }
+}
+
+/// Sends a message to the stream and waits for the answer of the application,
+/// if the stream is gone the skill receives an internal error. `cb` can look
+/// at (and change) the answer before it is sent.
+pub async fn send_and_wait<F>(
+    in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
+    msg: SkillRegisterMessage,
+    resp: Option<CoapResponse>,
+    cb: F
+) -> Option<CoapResponse> where
+F: FnOnce(&mut Response) {
+    let (sender, receiver) = oneshot::channel();
+    if in_send.send((msg, sender)).await.is_err() {
+        println!("The skill register stream was dropped, can't answer the request");
+        return respond_error(resp, VapError::internal());
+    }
+
+    wait_response(receiver, resp, cb).await
+}
+
+/// Answer with an error, the status is taken from the error code
+pub fn respond_error(r: Option<CoapResponse>, error: VapError) -> Option<CoapResponse> {
+    let (status, payload) = encode_error(error);
+    respond(r, status, payload)
+}
+
+/// Replaces the answer of the application with an error
+pub fn set_error(r: &mut Response, error: VapError) {
+    let (status, payload) = encode_error(error);
+    r.status = status;
+    r.payload = payload;
 }
 
-pub fn response_not_found(r: Option<CoapResponse>) -> Option<CoapResponse> {
-    respond(r, ResponseType::MethodNotAllowed, vec![])
+/// The status and payload for an error, an error that can't be encoded
+/// becomes an empty internal error
+fn encode_error(error: VapError) -> (ResponseType, Vec<u8>) {
+    match to_vec_named(&error) {
+        Ok(payload) => (status_from_code(error.code), payload),
+        Err(e) => {
+            println!("Failed to encode an error: {}", e);
+            (ResponseType::InternalServerError, Vec::new())
+        }
+    }
+}
+
+/// Transforms a numeric CoAP code (e.g: 404) into its response type
+fn status_from_code(code: u16) -> ResponseType {
+    let raw = (((code / 100) << 5) | (code % 100)) as u8;
+    match MessageClass::from(raw) {
+        MessageClass::Response(status) => status,
+        _ => ResponseType::InternalServerError,
+    }
+}
+
+/// The token sent in the query of a request without payload, if any
+pub fn query_token(request: &CoapRequest<SocketAddr>) -> Option<String> {
+    request
+        .message
+        .get_option(CoapOption::UriQuery)?
+        .iter()
+        .filter_map(|query| std::str::from_utf8(query).ok())
+        .find_map(|query| query.strip_prefix(TOKEN_QUERY)?.strip_prefix('='))
+        .map(str::to_string)
+}
+
+pub fn response_not_found(r: Option<CoapResponse>, path: &str) -> Option<CoapResponse> {
+    respond_error(r, VapError::not_found(path))
 }
 
 pub fn read_payload<T: DeserializeOwned>(payload: &[u8], r: Option<CoapResponse>) -> Result<(T, Option<CoapResponse>), Option<CoapResponse>> {
@@ -35,48 +102,24 @@ pub fn read_payload<T: DeserializeOwned>(payload: &[u8], r: Option<CoapResponse>
             Ok((a,r))
         }
         Err(e) => {
-            Err(r.map(|mut r|{
-                println!("Found an error while reading payload: {}", &e);
-                let status = match e {
-                    rmp_serde::decode::Error::TypeMismatch(_) => {
-                        coap_lite::ResponseType::RequestEntityIncomplete
-                    }
+            println!("Found an error while reading payload: {}", &e);
+            let error = match e {
+                rmp_serde::decode::Error::TypeMismatch(_) => {
+                    VapError::missing_field(None)
+                }
 
-                    _ => {
-                        coap_lite::ResponseType::BadRequest
-                    }
-                };
+                rmp_serde::decode::Error::Syntax(msg) if msg.starts_with("missing field") => {
+                    // Serde's message looks like: missing field `name`
+                    let field = msg.split('`').nth(1).map(str::to_string);
+                    VapError::missing_field(field)
+                }
 
-                r.set_status(status);
-                r
-            }))
-        }
-    }
-}
+                _ => {
+                    VapError::malformed_content()
+                }
+            };
 
-pub async fn handle_msg<T: DeserializeOwned, F, F2>(
-    request: CoapRequest<SocketAddr>,
-    in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    key_check: F2,
-    cb: F,
-) -> Option<CoapResponse> where
-    F: FnOnce(T) -> SkillRegisterMessage,
-    F2: FnOnce(&T) -> bool{
-
-    match read_payload(&request.message.payload, request.response) {
-        Ok::<(T,_),_>((p, resp)) => {
-            if  key_check(&p){
-                let (sender, receiver) = oneshot::channel();
-                in_send.send((cb(p), sender)).await.unwrap();
-                wait_response(receiver, resp, |_|{}).await
-            }
-            else {
-                println!("Bad request because key_check");
-                respond(resp, ResponseType::BadRequest, vec![])
-            }
-        }
-        Err(r) => {
-            r
+            Err(respond_error(r, error))
         }
     }
-}
\ No newline at end of file
+}
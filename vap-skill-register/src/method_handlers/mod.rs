@@ -1,102 +1,208 @@
 // Handle the incoming CoAP requests
 
-use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex as SyncMutex};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
 
-use crate::{respond, Notification, NotificationData,  RequestId, RequestResponse, Response, SkillRegisterMessage, SharedPending};
-use crate::vars::VAP_VERSION;
+use crate::{respond, ConnectAttempt, ConnectionPolicy, Verdict, Notification, NotificationData,  RequestId, RequestResponse, Response, SkillRegisterMessage, SharedPending};
+use crate::registry::{self, SharedConverters, SharedSkills, SharedTokens};
+use crate::server::Notifier;
+use crate::validation::validate_nlu_data;
+use crate::vars::SUPPORTED_VERSIONS;
 use self::io_helpers::*;
 
-use coap_lite::{CoapRequest, CoapResponse, ResponseType};
+use coap_lite::{CoapRequest, CoapResponse, ObserveOption, ResponseType};
 use futures::future::{join, join_all};
-use futures::{channel::{mpsc, oneshot}, SinkExt, lock::Mutex};
-use rmp_serde::to_vec_named;
+use futures::channel::{mpsc, oneshot};
+use rmp_serde::{from_slice, to_vec_named};
 use vap_common_skill::structures::*;
+use vap_common_skill::version::{Version, VersionRange};
 
 mod io_helpers;
 
+const BASE_SKILLS_PATH: &str = "vap/skillRegistry/skills/";
+
+#[allow(clippy::too_many_arguments)]
 pub async fn on_get(
     request: CoapRequest<SocketAddr>,
+    sender: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    current_skills: Arc<std::sync::Mutex<HashMap<String, ()>>>
+    current_skills: SharedSkills,
+    tokens: &SharedTokens,
+    notifier: &Notifier,
+    converters: &SharedConverters,
+    pending_requests: &SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>,
 ) -> Option<CoapResponse> {
-    if request.get_path().starts_with("vap/skillRegistry/skills/") {
-        respond(request.response, ResponseType::Content, vec![])
+    let path = request.get_path();
+    if let Some(id) = path.strip_prefix(BASE_SKILLS_PATH) {
+        // Skills observe their own resource, requests are sent as notifications.
+        // Only the skill itself can (de)register, proven by its token, since
+        // observing uses a socket of its own.
+        let token = query_token(&request);
+        match request.get_observe_flag() {
+            Some(Ok(ObserveOption::Register)) => {
+                let check = skill_authenticated(tokens, sender, id, token.as_deref())
+                    .and_then(|()| skill_seen(&current_skills, id));
+                match (check, request.source) {
+                    (Ok(()), Some(source)) => {
+                        let token = request.message.get_token().clone();
+                        let sequence = notifier.register(id, source, token);
+                        request.response.map(|mut r| {
+                            r.set_status(ResponseType::Content);
+                            r.message.set_observe_value(sequence);
+                            r
+                        })
+                    }
+                    (Err(e), _) => respond_error(request.response, e),
+                    (Ok(()), None) => respond_error(request.response, VapError::internal()),
+                }
+            }
+            Some(Ok(ObserveOption::Deregister)) => {
+                match skill_authenticated(tokens, sender, id, token.as_deref()).and_then(|()| skill_seen(&current_skills, id)) {
+                    Ok(()) => {
+                        // Without observing the skill can't receive anything
+                        registry::skill_gone(&current_skills, notifier, in_send, id);
+                        respond(request.response, ResponseType::Content, vec![])
+                    }
+                    Err(e) => respond_error(request.response, e),
+                }
+            }
+            Some(Err(_)) => respond_error(request.response, VapError::malformed_content()),
+            // A plain GET is a heartbeat
+            None => match skill_authenticated(tokens, sender, id, token.as_deref()).and_then(|()| skill_seen(&current_skills, id)) {
+                Ok(()) => respond(request.response, ResponseType::Content, vec![]),
+                Err(e) => respond_error(request.response, e),
+            },
+        }
     }
 
     else {
-        match request.get_path().as_str() {
+        match path.as_str() {
             "vap/skillRegistry/query" => {
-                handle_msg(
-                    request,
-                    in_send,
-                    |p: &MsgQuery|current_skills.lock().unwrap().contains_key(&p.skill_id),
-                    SkillRegisterMessage::Query
-                ).await
+                match read_payload(&request.message.payload, request.response) {
+                    Ok::<(MsgQuery,_),_>((p, resp)) => {
+                        let check = skill_authenticated(tokens, sender, &p.skill_id, p.auth_token.as_deref())
+                            .and_then(|()| skill_seen(&current_skills, &p.skill_id));
+                        match check {
+                            Ok(()) => {
+                                let skill_id = p.skill_id.clone();
+                                send_and_wait(in_send, SkillRegisterMessage::Query(p), resp, |r| {
+                                    // The skill only gets the capabilities it can handle
+                                    if is_ok(r.status) {
+                                        if let Ok(answer) = from_slice::<MsgQueryResponse>(&r.payload) {
+                                            let data = registry::query_answer_for_skill(&current_skills, converters, &skill_id, answer.data);
+                                            match to_vec_named(&MsgQueryResponse { data }) {
+                                                Ok(payload) => r.payload = payload,
+                                                Err(e) => {
+                                                    println!("Failed to encode the answer to a query: {}", e);
+                                                    set_error(r, VapError::internal());
+                                                }
+                                            }
+                                        }
+                                    }
+                                }).await
+                            }
+                            Err(e) => respond_error(resp, e),
+                        }
+                    }
+                    Err(r) => r,
+                }
             }
 
             ".well-known/core" => {
                 respond(request.response, ResponseType::Content, b"</vap>;rt=\"vap-skill-registry\"".to_vec())
             }
 
-            _ => {
-                if request.get_path().starts_with("vap/request/") {
-                    // TODO: Make sure only the same skill is asking for it.
-                    respond(request.response, ResponseType::Valid, vec![])
+            path => {
+                if let Some(request_id) = path.strip_prefix("vap/request/") {
+                    // Only the skill the request was sent to can ask for it
+                    let owner = match request_id.parse::<RequestId>() {
+                        Ok(request_id) => pending_requests.lock().await.get(&request_id).map(|p| p.skill_id.clone()),
+                        Err(_) => None,
+                    };
+                    match owner {
+                        Some(owner)
+                            if sender.is_none_or(|s| s == owner)
+                                && skill_is_caller(&current_skills, &owner, request.source).is_ok() =>
+                        {
+                            respond(request.response, ResponseType::Valid, vec![])
+                        }
+                        _ => respond_error(request.response, VapError::unknown_request(request_id)),
+                    }
                 } else {
-                    response_not_found(request.response)
+                    response_not_found(request.response, path)
                 }
             }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn on_post(
     request: CoapRequest<SocketAddr>,
+    sender: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    self_send: &mut mpsc::Sender<(String, Vec<u8>)>,
-    current_skills: &Arc<std::sync::Mutex<HashMap<String, ()>>>,
-    pending_can_you: &Arc<Mutex<HashMap<u64, oneshot::Sender<f32>>>>,
+    current_skills: &SharedSkills,
+    tokens: &SharedTokens,
+    policy: &(dyn ConnectionPolicy + Send + Sync),
+    skill_lease: Option<Duration>,
+    pending_can_you: &SharedPending<f32>,
     pending_requests: &SharedPending<(Vec<PlainCapability>, oneshot::Sender<RequestResponse>)>
 ) -> Option<CoapResponse> {
     match request.get_path().as_str() {
         "vap/skillRegistry/connect" => {
+            // Our server always knows where requests come from
+            let address = match request.source {
+                Some(address) => address,
+                None => return respond_error(request.response, VapError::internal()),
+            };
             match read_payload(&request.message.payload, request.response) {
                 Ok::<(MsgConnect,_),_>((p, resp)) => {
-                    // Until there's a way for skill "connections" to be checked we'll
-                    // disable checking whether an skill already exists
-                    //let is_in_dict = current_skills.lock().unwrap().contains_key(&p.id);
-                    let is_in_dict = false;
-                    if !is_in_dict && p.vap_version == VAP_VERSION {
-                        let (sender, receiver) = oneshot::channel();
-                        let skill_id = p.id.clone();
-                        in_send.send((SkillRegisterMessage::Connect(p), sender)).await.unwrap();
-                        
-                        wait_response(receiver, resp, |r| {
-                            // If it is regarded as "OK"
-                            if [
-                                ResponseType::Created, ResponseType::Deleted,
-                                ResponseType::Valid,
-                                ResponseType::Changed,
-                                ResponseType::Content,
-                                ResponseType::Continue
-                                ].contains(&r.status) {
-                                
-                                // We need to register the skill inside the CoAP server
-                                self_send.try_send((skill_id.clone(), vec![])).unwrap();
-                                current_skills.lock().unwrap().insert(skill_id.clone(),());
+                    // Skills that connected before need to prove who they are,
+                    // and a skill with a key can only connect as itself
+                    let (is_known, has_token) = {
+                        let tokens = tokens.lock().unwrap();
+                        (tokens.is_known(&p.id), tokens.verify(&p.id, p.auth_token.as_deref()))
+                    };
+                    let is_sender = sender.is_none_or(|s| s == p.id);
+                    let authorized = is_sender && (!is_known || has_token);
+                    // A skill that proves who it is restarted before its lease
+                    // ran out and replaces its old record, anyone else trying
+                    // to use the id of a live skill is a duplicate
+                    let is_duplicate = !has_token && current_skills.lock().unwrap().contains_key(&p.id);
+                    let version = negotiate_version(&p);
+                    if !is_duplicate && authorized && version.is_some() {
+                        let attempt = ConnectAttempt {
+                            skill_id: p.id.clone(),
+                            name: p.name.clone(),
+                            source: address,
+                        };
+                        if !is_allowed(policy, &attempt).await {
+                            println!("{} was denied by the connection policy", p.id);
+                            return respond_error(resp, VapError::connection_denied());
+                        }
+
+                        let connect = p.clone();
+                        send_and_wait(in_send, SkillRegisterMessage::Connect(p), resp, |r| {
+                            if is_ok(r.status) {
+                                match complete_connect(r, tokens, &connect.id, is_known, version, skill_lease) {
+                                    Ok(()) => registry::add_skill(current_skills, &connect, address),
+                                    Err(e) => set_error(r, e),
+                                }
                             }
                         }).await
                     }
+                    else if version.is_none() {
+                        println!("Received a non-compatible version, bad request");
+                        respond_error(resp, VapError::incompatible_version(p.vap_version))
+                    }
+                    else if is_duplicate && is_sender {
+                        println!("Tried to register a skill already connected, if this a genuine request wait a little");
+                        respond_error(resp, VapError::wrong_skill_id(p.id))
+                    }
                     else {
-                        if p.vap_version != VAP_VERSION {
-                            println!("Received a non-compatible version, bad request");
-                        }
-                        else if is_in_dict {
-                            println!("Tried to register a skill already connected, if this a genuine request wait a little");
-                        }
-                        respond(resp, ResponseType::BadRequest, vec![])
+                        println!("{} tried to connect without its token", p.id);
+                        respond_error(resp, VapError::connection_denied())
                     }
                 }
                 Err(r) => {
@@ -106,18 +212,68 @@ pub async fn on_post(
         }
 
         "vap/skillRegistry/registerIntents" => {
-            handle_msg(
-                request,
-                in_send,
-                |p: &MsgRegisterIntents|current_skills.lock().unwrap().contains_key(&p.skill_id),
-                SkillRegisterMessage::RegisterIntents
-            ).await
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgRegisterIntents,_),_>((p, resp)) => {
+                    let check = skill_authenticated(tokens, sender, &p.skill_id, p.auth_token.as_deref())
+                        .and_then(|()| skill_seen(current_skills, &p.skill_id))
+                        .and_then(|()| validate_nlu_data(&p.nlu_data));
+                    match check {
+                        Ok(()) => {
+                            let skill_id = p.skill_id.clone();
+                            let nlu_data = p.nlu_data.clone();
+                            send_and_wait(in_send, SkillRegisterMessage::RegisterIntents(p), resp, |r| {
+                                if is_ok(r.status) {
+                                    if let Some(record) = current_skills.lock().unwrap().get_mut(&skill_id) {
+                                        record.set_nlu_data(&nlu_data);
+                                    }
+                                }
+                            }).await
+                        }
+                        Err(e) => respond_error(resp, e)
+                    }
+                }
+                Err(r) => {
+                    r
+                }
+            }
+        }
+
+        "vap/skillRegistry/scopes" => {
+            match read_payload(&request.message.payload, request.response) {
+                Ok::<(MsgSetScopes,_),_>((p, resp)) => {
+                    let check = skill_authenticated(tokens, sender, &p.skill_id, p.auth_token.as_deref())
+                        .and_then(|()| skill_seen(current_skills, &p.skill_id))
+                        .and_then(|()| scopes_known(current_skills, &p));
+                    match check {
+                        Ok(()) => {
+                            let changed = p.clone();
+                            send_and_wait(in_send, SkillRegisterMessage::SetScopes(p), resp, |r| {
+                                if is_ok(r.status) {
+                                    if let Some(record) = current_skills.lock().unwrap().get_mut(&changed.skill_id) {
+                                        record.set_scopes(&changed.enable, &changed.disable);
+                                    }
+                                }
+                            }).await
+                        }
+                        Err(e) => respond_error(resp, e)
+                    }
+                }
+                Err(r) => {
+                    r
+                }
+            }
         }
 
         "vap/skillRegistry/notification" => {
-            
+            let source = request.source;
             match read_payload(&request.message.payload, request.response) {
                 Ok::<(MsgNotification,_),_>((msg, resp)) => {
+                    let check = skill_authenticated(tokens, sender, &msg.skill_id, msg.auth_token.as_deref())
+                        .and_then(|()| skill_seen(current_skills, &msg.skill_id));
+                    if let Err(e) = check {
+                        return respond_error(resp, e);
+                    }
+
                     let mut standalone = vec![];
                     let mut resolutions = vec![];
 
@@ -127,6 +283,11 @@ pub async fn on_post(
                     }
 
                     let skill_id = msg.skill_id;
+                    // Only the skill itself can answer its requests
+                    let owner = match skill_is_caller(current_skills, &skill_id, source) {
+                        Ok(()) => Some(skill_id.as_str()),
+                        Err(_) => None,
+                    };
 
                     for d in msg.data {
                         match d {
@@ -138,14 +299,17 @@ pub async fn on_post(
                                     })
                                 }
 
-                                let resol= match pending_can_you.lock().await.remove(&request_id) {
+                                let resol= match take_owned(pending_can_you, request_id, owner).await {
                                     Some(pending_sender) => {
-                                        pending_sender.send(confidence).unwrap();
-                                        can_you_answer_done(coap_lite::ResponseType::Valid, request_id)
-                                        
+                                        match pending_sender.send(confidence) {
+                                            Ok(()) => can_you_answer_done(coap_lite::ResponseType::Valid, request_id),
+                                            // Nobody is waiting for this answer anymore
+                                            Err(_) => can_you_answer_done(coap_lite::ResponseType::BadOption, request_id)
+                                        }
                                     }
+                                    // Unknown, expired or someone else's request
                                     None => {
-                                        can_you_answer_done(coap_lite::ResponseType::BadRequest, request_id)
+                                        can_you_answer_done(coap_lite::ResponseType::BadOption, request_id)
                                     }
                                 };
 
@@ -155,19 +319,23 @@ pub async fn on_post(
                                 fn requested_done(response: coap_lite::ResponseType, id: RequestId) -> RequestResolution {
                                     RequestResolution::Done(msg_notification_response::Data::Requested {
                                         code: response as u16,
-                                        request_id: id
+                                        request_id: id,
+                                        errors: vec![],
                                     })
                                 }
 
-                                let resol = match pending_requests.lock().await.remove(&request_id) {
+                                let resol = match take_owned(pending_requests, request_id, owner).await {
                                     Some(pending_sender) => {
-
                                         let (sender, receiver) = oneshot::channel();
-                                        pending_sender.send((capabilities.clone(), sender)).unwrap();
-                                        RequestResolution::InProcess((request_id, receiver))
+                                        match pending_sender.send((capabilities, sender)) {
+                                            Ok(()) => RequestResolution::InProcess((request_id, receiver)),
+                                            // Nobody is waiting for this answer anymore
+                                            Err(_) => requested_done(coap_lite::ResponseType::BadOption, request_id)
+                                        }
                                     }
+                                    // Unknown, expired or someone else's request
                                     None => {
-                                        requested_done(coap_lite::ResponseType::BadRequest, request_id)
+                                        requested_done(coap_lite::ResponseType::BadOption, request_id)
                                     }
                                 };
 
@@ -195,39 +363,39 @@ pub async fn on_post(
                     let futs = join_all(futures);                               
 
                     if !standalone.is_empty() {
-                        let send_standalone = async {
-                            let (sender, receiver) = oneshot::channel();
-                            in_send.send((SkillRegisterMessage::Notification(Notification {
+                        let send_standalone = send_and_wait(
+                            in_send,
+                            SkillRegisterMessage::Notification(Notification {
                                 skill_id: skill_id.clone(),
                                 data: standalone,
-                            }), sender)).await.unwrap();
-                            wait_response(receiver, resp, |_|{}).await
-                        };
+                            }),
+                            resp,
+                            |_|{}
+                        );
 
                         // TODO: Any result that is not standalone is ignored right now (though it is processed)
                         join(send_standalone, futs).await.0
 
                     }
                     else {
-                        const DEFAULT_RESP: RequestResponse = RequestResponse{code: ResponseType::Content as u16};
+                        const DEFAULT_RESP: RequestResponse = RequestResponse{code: ResponseType::Content as u16, capability_errors: vec![]};
                         let res = futs.await.into_iter()
                             .map(|r|r.unwrap_or(DEFAULT_RESP))
                             .zip(request_ids)
                             .map(|(n, request_id)|msg_notification_response::Data::Requested {
                                 code: n.code,
-                                request_id
+                                request_id,
+                                errors: n.capability_errors,
                             });
                         other_res.extend(res);
                             
-                        resp.map(|mut r| {
-                            let payload = to_vec_named(&MsgNotificationResponse {
-                                data: other_res
-                            }).unwrap();
-                            
-                            r.set_status(coap_lite::ResponseType::Valid);
-                            r.message.payload = payload;
-                            r
-                        })
+                        match to_vec_named(&MsgNotificationResponse {data: other_res}) {
+                            Ok(payload) => respond(resp, coap_lite::ResponseType::Valid, payload),
+                            Err(e) => {
+                                println!("Failed to encode the notification response: {}", e);
+                                respond_error(resp, VapError::internal())
+                            }
+                        }
                     }
                 }
                 Err(r) => {
@@ -236,29 +404,39 @@ pub async fn on_post(
             }
         }
 
-        _ => response_not_found(request.response)
-    }                    
+        path => response_not_found(request.response, path)
+    }
 }
 
 pub async fn on_delete(
     request: CoapRequest<SocketAddr>,
+    sender: Option<&str>,
     in_send: &mut mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
-    current_skills: Arc<SyncMutex<HashMap<String, ()>>>,
+    current_skills: SharedSkills,
+    tokens: &SharedTokens,
+    notifier: &Notifier,
 ) -> Option<CoapResponse> {
     let path = request.get_path();
-    const BASE_SKILLS_PATH: &str = "vap/skillRegistry/skills/";
-    if path.starts_with("vap/skillRegistry/skills/") {
-        let id = &path[BASE_SKILLS_PATH.len()..];
-
+    if let Some(id) = path.strip_prefix(BASE_SKILLS_PATH) {
+        let source = request.source;
         match read_payload(&request.message.payload, request.response) {
             Ok::<(MsgSkillClose, _), _>((p, resp)) => {
-                if current_skills.lock().unwrap().contains_key(id) {
-                    let (sender, receiver) = oneshot::channel();
-                    in_send.send((SkillRegisterMessage::Close(p), sender)).await.unwrap();
-                    wait_response(receiver, resp, |_|{}).await
-                }
-                else {
-                    respond(resp, ResponseType::BadRequest, vec![])
+                // Only the skill itself can close its connection
+                let check = skill_authenticated(tokens, sender, id, p.auth_token.as_deref())
+                    .and_then(|()| skill_seen(&current_skills, id))
+                    .and_then(|()| skill_is_caller(&current_skills, id, source))
+                    .and_then(|()| if p.skill_id == id { Ok(()) } else { Err(VapError::unauthorized()) });
+                match check {
+                    Ok(()) => {
+                        send_and_wait(in_send, SkillRegisterMessage::Close(p), resp, |r| {
+                            if is_ok(r.status) {
+                                registry::remove_skill(&current_skills, notifier, id);
+                            }
+                        }).await
+                    }
+                    Err(e) => {
+                        respond_error(resp, e)
+                    }
                 }
             }
             Err(r) => {
@@ -267,6 +445,117 @@ pub async fn on_delete(
         }
     }
     else {
-        response_not_found(request.response)
+        response_not_found(request.response, &path)
+    }
+}
+
+/// Checks that the skill is registered (and notes that we heard from it),
+/// answering with the spec's error otherwise
+fn skill_seen(current_skills: &SharedSkills, id: &str) -> Result<(), VapError> {
+    match current_skills.lock().unwrap().get_mut(id) {
+        Some(record) => {
+            record.last_seen = SystemTime::now();
+            Ok(())
+        }
+        None => Err(VapError::wrong_skill_id(id))
+    }
+}
+
+/// Checks that the request comes from the address (and port) the skill
+/// connected from
+fn skill_is_caller(current_skills: &SharedSkills, id: &str, source: Option<SocketAddr>) -> Result<(), VapError> {
+    match current_skills.lock().unwrap().get(id) {
+        Some(record) if Some(record.address) == source => Ok(()),
+        Some(_) => Err(VapError::unauthorized()),
+        None => Err(VapError::wrong_skill_id(id))
     }
+}
+
+/// Checks that every scope in the message was registered by the skill
+fn scopes_known(skills: &SharedSkills, msg: &MsgSetScopes) -> Result<(), VapError> {
+    let skills = skills.lock().unwrap();
+    let record = skills.get(&msg.skill_id).ok_or_else(|| VapError::wrong_skill_id(msg.skill_id.clone()))?;
+    match record.unknown_scope(&msg.enable).or_else(|| record.unknown_scope(&msg.disable)) {
+        Some(scope) => Err(VapError::not_found(scope.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the message carries the token given to the skill and, if it
+/// was protected (`sender`), that the key was the one of the skill
+fn skill_authenticated(tokens: &SharedTokens, sender: Option<&str>, id: &str, token: Option<&str>) -> Result<(), VapError> {
+    if sender.is_none_or(|s| s == id) && tokens.lock().unwrap().verify(id, token) {
+        Ok(())
+    }
+    else {
+        Err(VapError::unauthorized())
+    }
+}
+
+/// The newest version both we and the skill support. Skills that don't
+/// negotiate only support the version they send.
+fn negotiate_version(msg: &MsgConnect) -> Option<Version> {
+    let skill_versions = match msg.vap_versions {
+        Some(versions) => versions,
+        None => VersionRange::exactly(msg.vap_version.parse().ok()?),
+    };
+
+    SUPPORTED_VERSIONS.negotiate(&skill_versions)
+}
+
+/// Adds the negotiated version and our lease to the answer of the
+/// application, along with a new token the first time a skill connects
+fn complete_connect(
+    response: &mut Response,
+    tokens: &SharedTokens,
+    id: &str,
+    is_known: bool,
+    version: Option<Version>,
+    lease: Option<Duration>,
+) -> Result<(), VapError> {
+    let mut msg: MsgConnectResponse = from_slice(&response.payload).map_err(|e| {
+        println!("The application answered a connect with something unexpected: {}", e);
+        VapError::internal()
+    })?;
+    msg.vap_version = version;
+    msg.lease = lease.map(|l| l.as_millis().try_into().unwrap_or(u64::MAX));
+    if !is_known {
+        msg.auth_token = Some(tokens.lock().unwrap().issue(id).map_err(|e| {
+            println!("Couldn't issue a token for {}: {}", id, e);
+            VapError::internal()
+        })?);
+    }
+    response.payload = to_vec_named(&msg).map_err(|_| VapError::internal())?;
+
+    Ok(())
+}
+
+/// Asks the connection policy, waiting for its answer if it needs one
+async fn is_allowed(policy: &(dyn ConnectionPolicy + Send + Sync), attempt: &ConnectAttempt) -> bool {
+    match policy.check(attempt) {
+        Verdict::Allow => true,
+        Verdict::Deny => false,
+        Verdict::Pending(answer) => answer.await.unwrap_or(false),
+    }
+}
+
+/// Takes a pending request, but only if it was sent to `owner`
+async fn take_owned<D>(pending: &SharedPending<D>, request_id: RequestId, owner: Option<&str>) -> Option<oneshot::Sender<D>> {
+    let mut pending = pending.lock().await;
+    match pending.get(&request_id) {
+        Some(p) if Some(p.skill_id.as_str()) == owner => pending.remove(&request_id).map(|p| p.sender),
+        _ => None
+    }
+}
+
+/// Whether the answer of the application is regarded as "OK"
+fn is_ok(status: ResponseType) -> bool {
+    [
+        ResponseType::Created,
+        ResponseType::Deleted,
+        ResponseType::Valid,
+        ResponseType::Changed,
+        ResponseType::Content,
+        ResponseType::Continue
+    ].contains(&status)
 }
\ No newline at end of file
@@ -0,0 +1,228 @@
+// Decide which skills are allowed to connect
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex as SyncMutex;
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+
+/// A skill asking to connect
+#[derive(Debug, Clone)]
+pub struct ConnectAttempt {
+    pub skill_id: String,
+    /// The human readable name of the skill
+    pub name: String,
+    /// Where the connect message came from
+    pub source: SocketAddr,
+}
+
+/// What a [`ConnectionPolicy`] thinks about a connection attempt
+#[derive(Debug)]
+pub enum Verdict {
+    Allow,
+    Deny,
+    /// Someone else decides later (e.g: the user), a dropped sender means
+    /// the skill is denied.
+    Pending(oneshot::Receiver<bool>),
+}
+
+/// Decides whether a skill can connect, it is only asked when everything else
+/// is fine (right version, right token, not connected already). Denied skills
+/// receive a "connectionDenied" error. Implement this to provide your own
+/// policy.
+pub trait ConnectionPolicy {
+    fn check(&self, attempt: &ConnectAttempt) -> Verdict;
+}
+
+/// Every skill can connect, the default.
+#[derive(Debug, Clone, Default)]
+pub struct AllowAll;
+
+impl ConnectionPolicy for AllowAll {
+    fn check(&self, _attempt: &ConnectAttempt) -> Verdict {
+        Verdict::Allow
+    }
+}
+
+/// Only the skills in the list can connect.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    pub skills: HashSet<String>,
+}
+
+impl AllowList {
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(skills: I) -> Self {
+        Self {
+            skills: skills.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl ConnectionPolicy for AllowList {
+    fn check(&self, attempt: &ConnectAttempt) -> Verdict {
+        if self.skills.contains(&attempt.skill_id) {
+            Verdict::Allow
+        } else {
+            Verdict::Deny
+        }
+    }
+}
+
+/// The skills in the list are blocked, anything else is left to `inner`.
+pub struct DenyList<P: ConnectionPolicy> {
+    pub skills: HashSet<String>,
+    pub inner: P,
+}
+
+impl DenyList<AllowAll> {
+    /// Every skill but those in the list can connect
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(skills: I) -> Self {
+        Self {
+            skills: skills.into_iter().map(Into::into).collect(),
+            inner: AllowAll,
+        }
+    }
+}
+
+impl<P: ConnectionPolicy> ConnectionPolicy for DenyList<P> {
+    fn check(&self, attempt: &ConnectAttempt) -> Verdict {
+        if self.skills.contains(&attempt.skill_id) {
+            Verdict::Deny
+        } else {
+            self.inner.check(attempt)
+        }
+    }
+}
+
+/// A connection waiting for the user to accept it. Dropping it without
+/// answering denies the connection.
+#[derive(Debug)]
+pub struct Approval {
+    pub attempt: ConnectAttempt,
+    answer: oneshot::Sender<bool>,
+}
+
+impl Approval {
+    pub fn accept(self) {
+        let _ = self.answer.send(true);
+    }
+
+    pub fn deny(self) {
+        let _ = self.answer.send(false);
+    }
+}
+
+/// Asks the user about every skill: `ask` receives an [`Approval`] which can
+/// be answered at any moment (it can be sent to another task). The skill
+/// waits for the answer, so don't take longer than its request timeout.
+pub struct AskUser<F: Fn(Approval)> {
+    ask: F,
+}
+
+impl<F: Fn(Approval)> AskUser<F> {
+    pub fn new(ask: F) -> Self {
+        Self { ask }
+    }
+}
+
+impl<F: Fn(Approval)> ConnectionPolicy for AskUser<F> {
+    fn check(&self, attempt: &ConnectAttempt) -> Verdict {
+        let (answer, receiver) = oneshot::channel();
+        (self.ask)(Approval {
+            attempt: attempt.clone(),
+            answer,
+        });
+
+        Verdict::Pending(receiver)
+    }
+}
+
+/// Each source address can try to connect at most `max_attempts` times every
+/// `period`, the attempts within the limit are left to `inner`. Denied
+/// attempts count too, so insisting doesn't help.
+pub struct RateLimit<P: ConnectionPolicy> {
+    pub max_attempts: usize,
+    pub period: Duration,
+    pub inner: P,
+    attempts: SyncMutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl<P: ConnectionPolicy> RateLimit<P> {
+    pub fn new(max_attempts: usize, period: Duration, inner: P) -> Self {
+        Self {
+            max_attempts,
+            period,
+            inner,
+            attempts: SyncMutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<P: ConnectionPolicy> ConnectionPolicy for RateLimit<P> {
+    fn check(&self, attempt: &ConnectAttempt) -> Verdict {
+        let now = Instant::now();
+        let within_limit = {
+            let mut attempts = self.attempts.lock().unwrap();
+            // Forget what is too old to matter
+            attempts.retain(|_, times| {
+                while times.front().is_some_and(|t| now.duration_since(*t) >= self.period) {
+                    times.pop_front();
+                }
+                !times.is_empty()
+            });
+
+            let times = attempts.entry(attempt.source.ip()).or_default();
+            times.push_back(now);
+            times.len() <= self.max_attempts
+        };
+
+        if within_limit {
+            self.inner.check(attempt)
+        } else {
+            Verdict::Deny
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::attempt;
+
+    fn allowed(verdict: Verdict) -> bool {
+        match verdict {
+            Verdict::Allow => true,
+            Verdict::Deny => false,
+            Verdict::Pending(mut r) => r.try_recv().ok().flatten().unwrap_or(false),
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let a = attempt("com.example.a", "127.0.0.1:1000");
+        let b = attempt("com.example.b", "127.0.0.1:1001");
+
+        let allow = AllowList::new(["com.example.a"]);
+        assert!(allowed(allow.check(&a)));
+        assert!(!allowed(allow.check(&b)));
+
+        let deny = DenyList::new(["com.example.a"]);
+        assert!(!allowed(deny.check(&a)));
+        assert!(allowed(deny.check(&b)));
+
+        let ask = AskUser::new(|approval: Approval| {
+            if approval.attempt.skill_id == "com.example.a" {
+                approval.accept()
+            }
+        });
+        assert!(allowed(ask.check(&a)));
+        assert!(!allowed(ask.check(&b)));
+
+        // Both come from the same address
+        let limit = RateLimit::new(1, Duration::from_secs(60), AllowAll);
+        assert!(allowed(limit.check(&a)));
+        assert!(!allowed(limit.check(&b)));
+        assert!(allowed(limit.check(&attempt("com.example.c", "127.0.0.2:1000"))));
+    }
+}
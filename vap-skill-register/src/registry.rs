@@ -0,0 +1,402 @@
+// What the register knows about each connected skill
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, SystemTime};
+
+use futures::channel::{mpsc, oneshot};
+use vap_common_skill::auth::TokenStore;
+use vap_common_skill::capability::{CapabilityError, CapabilityRange, Converters};
+use vap_common_skill::structures::msg_query_response::{QueryData, QueryDataCapability};
+use vap_common_skill::structures::msg_register_intents::{NluData, MAIN_SCOPE};
+use vap_common_skill::structures::msg_skill_request::{ClientData, ClientDataCapability};
+use vap_common_skill::structures::{Language, MsgConnect, MsgSkillClose, PlainCapability};
+
+use crate::server::Notifier;
+use crate::{Response, SkillRegisterMessage};
+
+/// Skill id -> What we know about it
+pub(crate) type SharedSkills = Arc<SyncMutex<HashMap<String, SkillRecord>>>;
+
+/// Every skill that ever connected and its token
+pub(crate) type SharedTokens = Arc<SyncMutex<TokenStore>>;
+
+/// How capabilities too new for a skill are turned into older versions
+pub(crate) type SharedConverters = Arc<SyncMutex<Converters>>;
+
+/// A skill connected to the register
+#[derive(Debug, Clone)]
+pub struct SkillRecord {
+    pub id: String,
+    /// Where the skill connected from, only this address can act as the skill
+    pub address: SocketAddr,
+    /// Human-readable name
+    pub name: String,
+    pub vap_version: String,
+    /// Versions of the capabilities the skill can handle, empty if it didn't
+    /// say
+    pub capabilities: Vec<CapabilityRange>,
+    pub registered_at: SystemTime,
+    /// Skills registered earlier have lower numbers
+    pub registration_order: u64,
+    /// Languages the skill registered intents for
+    pub languages: Vec<Language>,
+    /// Names of the intents registered by the skill (in any language)
+    pub intents: Vec<String>,
+    /// Scope name -> Names of its intents (in any language)
+    pub scopes: BTreeMap<String, BTreeSet<String>>,
+    /// Scopes whose intents can be recognized right now
+    pub enabled_scopes: BTreeSet<String>,
+    /// Last time the skill sent us anything
+    pub last_seen: SystemTime,
+}
+
+impl SkillRecord {
+    pub(crate) fn new(msg: &MsgConnect, address: SocketAddr, registration_order: u64) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id: msg.id.clone(),
+            address,
+            name: msg.name.clone(),
+            vap_version: msg.vap_version.clone(),
+            capabilities: msg.capabilities.clone(),
+            registered_at: now,
+            registration_order,
+            languages: vec![],
+            intents: vec![],
+            scopes: BTreeMap::new(),
+            enabled_scopes: BTreeSet::new(),
+            last_seen: now,
+        }
+    }
+
+    /// The skill connected again, it keeps its place in the registration order
+    pub(crate) fn reconnected(&mut self, msg: &MsgConnect, address: SocketAddr) {
+        *self = Self::new(msg, address, self.registration_order);
+    }
+
+    pub(crate) fn set_nlu_data(&mut self, nlu_data: &[NluData]) {
+        self.languages = nlu_data.iter().map(|d| d.language.clone()).collect();
+        self.intents = nlu_data
+            .iter()
+            .flat_map(|d| d.intents.iter().map(|i| i.name.clone()))
+            .collect();
+        self.intents.sort();
+        self.intents.dedup();
+
+        self.scopes.clear();
+        for intent in nlu_data.iter().flat_map(|d| &d.intents) {
+            self.scopes
+                .entry(intent.scope.clone())
+                .or_default()
+                .insert(intent.name.clone());
+        }
+        // Scopes that are gone can't be enabled, new ones start disabled
+        let scopes = &self.scopes;
+        self.enabled_scopes.retain(|s| scopes.contains_key(s));
+        self.enabled_scopes.insert(MAIN_SCOPE.to_string());
+    }
+
+    /// The first of `scopes` that the skill never registered
+    pub(crate) fn unknown_scope<'a>(&self, scopes: &'a [String]) -> Option<&'a String> {
+        scopes.iter().find(|s| !self.scopes.contains_key(*s))
+    }
+
+    pub(crate) fn set_scopes(&mut self, enable: &[String], disable: &[String]) {
+        for scope in disable {
+            self.enabled_scopes.remove(scope);
+        }
+        self.enabled_scopes.extend(enable.iter().cloned());
+    }
+
+    /// The intents that can be recognized right now, those in enabled scopes
+    pub fn enabled_intents(&self) -> BTreeSet<String> {
+        self.enabled_scopes
+            .iter()
+            .filter_map(|s| self.scopes.get(s))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Adds the skill to the registry (or updates it if it was already there)
+pub(crate) fn add_skill(skills: &SharedSkills, msg: &MsgConnect, address: SocketAddr) {
+    let mut skills = skills.lock().unwrap();
+    let order = skills
+        .values()
+        .map(|r| r.registration_order + 1)
+        .max()
+        .unwrap_or(0);
+
+    skills
+        .entry(msg.id.clone())
+        .and_modify(|r| r.reconnected(msg, address))
+        .or_insert_with(|| SkillRecord::new(msg, address, order));
+}
+
+/// All the skills, in the order they registered
+pub(crate) fn list_skills(skills: &SharedSkills) -> Vec<SkillRecord> {
+    let mut list: Vec<_> = skills.lock().unwrap().values().cloned().collect();
+    list.sort_by_key(|r| r.registration_order);
+    list
+}
+
+pub(crate) fn skill_info(skills: &SharedSkills, id: &str) -> Option<SkillRecord> {
+    skills.lock().unwrap().get(id).cloned()
+}
+
+/// Skills that haven't sent anything in `lease`
+pub(crate) fn expired_skills(skills: &SharedSkills, lease: Duration) -> Vec<String> {
+    let now = SystemTime::now();
+    skills
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|r| now.duration_since(r.last_seen).is_ok_and(|d| d > lease))
+        .map(|r| r.id.clone())
+        .collect()
+}
+
+/// Prepares capabilities for a skill, those it can't handle are left out and
+/// their errors returned. Skills that are not connected get them untouched.
+pub(crate) fn adapt_for_skill(
+    skills: &SharedSkills,
+    converters: &SharedConverters,
+    id: &str,
+    capabilities: Vec<PlainCapability>,
+) -> (Vec<PlainCapability>, Vec<CapabilityError>) {
+    let supported = skills
+        .lock()
+        .unwrap()
+        .get(id)
+        .map(|r| r.capabilities.clone())
+        .unwrap_or_default();
+
+    converters.lock().unwrap().adapt_all(capabilities, &supported)
+}
+
+/// The client of a request as the skill should see it: only the
+/// capabilities it declared, at versions it can handle.
+pub(crate) fn client_for_skill(skills: &SharedSkills, converters: &SharedConverters, id: &str, client: ClientData) -> ClientData {
+    let capabilities = client
+        .capabilities
+        .into_iter()
+        .map(|c| PlainCapability {
+            name: c.name,
+            version: c.version,
+            cap_data: HashMap::new(),
+        })
+        .collect();
+    let (capabilities, _) = adapt_for_skill(skills, converters, id, capabilities);
+
+    ClientData {
+        system_id: client.system_id,
+        capabilities: capabilities
+            .into_iter()
+            .map(|c| ClientDataCapability {
+                name: c.name,
+                version: c.version,
+            })
+            .collect(),
+    }
+}
+
+/// Adapts the data of a query answer for the skill that asked. Failed
+/// capabilities (any code but 205) carry no data and are kept as they are.
+pub(crate) fn query_answer_for_skill(
+    skills: &SharedSkills,
+    converters: &SharedConverters,
+    id: &str,
+    data: Vec<QueryData>,
+) -> Vec<QueryData> {
+    data.into_iter()
+        .map(|mut data| {
+            let (found, failed): (Vec<_>, Vec<_>) = data.capabilities.into_iter().partition(|c| c.code == 205);
+            let found = found
+                .into_iter()
+                .map(|c| PlainCapability {
+                    name: c.name,
+                    version: c.version,
+                    cap_data: c.data,
+                })
+                .collect();
+            let (found, errors) = adapt_for_skill(skills, converters, id, found);
+            for e in errors {
+                println!("Left out of the answer to {}: {}", id, e);
+            }
+
+            data.capabilities = found
+                .into_iter()
+                .map(|c| QueryDataCapability {
+                    name: c.name,
+                    code: 205,
+                    version: c.version,
+                    data: c.cap_data,
+                })
+                .chain(failed)
+                .collect();
+            data
+        })
+        .collect()
+}
+
+/// Forgets a skill that closed the connection
+pub(crate) fn remove_skill(skills: &SharedSkills, notifier: &Notifier, id: &str) -> bool {
+    notifier.deregister(id);
+    skills.lock().unwrap().remove(id).is_some()
+}
+
+/// Forgets a skill that is gone without closing, the application receives a
+/// `Close` as if the skill had sent it.
+pub(crate) fn skill_gone(
+    skills: &SharedSkills,
+    notifier: &Notifier,
+    in_send: &mpsc::Sender<(SkillRegisterMessage, oneshot::Sender<Response>)>,
+    id: &str,
+) {
+    if remove_skill(skills, notifier, id) {
+        println!("The skill {} is gone, removing it", id);
+        let msg = SkillRegisterMessage::Close(MsgSkillClose {
+            skill_id: id.to_string(),
+            auth_token: None,
+        });
+
+        // Nobody is interested in the answer
+        let (sender, _) = oneshot::channel();
+        if let Err(e) = in_send.clone().try_send((msg, sender)) {
+            println!("Couldn't tell that {} is gone: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, intent, language};
+
+    fn connect(id: &str, name: &str) -> MsgConnect {
+        MsgConnect {
+            id: id.into(),
+            name: name.into(),
+            vap_version: "Alpha".into(),
+            vap_versions: None,
+            capabilities: vec![CapabilityRange::new("text", 1, 2)],
+            auth_token: None,
+        }
+    }
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn nlu_data(tag: &str, intents: &[(&str, &str)]) -> NluData {
+        let intents = intents.iter().map(|(name, scope)| intent(name, scope, &[], vec![])).collect();
+        test_support::nlu_data(language(tag, None), intents, vec![])
+    }
+
+    fn registry(ids: &[&str]) -> SharedSkills {
+        let skills = SharedSkills::default();
+        for (port, id) in (1000..).zip(ids) {
+            add_skill(&skills, &connect(id, "Test"), address(port));
+        }
+        skills
+    }
+
+    #[test]
+    fn skills_are_listed_in_registration_order() {
+        let skills = registry(&["com.example.b", "com.example.c", "com.example.a"]);
+        let ids: Vec<_> = list_skills(&skills).into_iter().map(|r| r.id).collect();
+        assert_eq!(ids, ["com.example.b", "com.example.c", "com.example.a"]);
+
+        // Reconnecting keeps the place, new skills go last
+        add_skill(&skills, &connect("com.example.b", "Renamed"), address(2000));
+        add_skill(&skills, &connect("com.example.d", "Test"), address(2001));
+        let list = list_skills(&skills);
+        let ids: Vec<_> = list.iter().map(|r| r.id.as_str()).collect();
+        assert_eq!(ids, ["com.example.b", "com.example.c", "com.example.a", "com.example.d"]);
+        assert_eq!(list[0].name, "Renamed");
+        assert_eq!(list[0].address, address(2000));
+    }
+
+    #[test]
+    fn skill_info_describes_the_skill() {
+        let skills = registry(&["com.example.a"]);
+        assert!(skill_info(&skills, "com.example.unknown").is_none());
+
+        let record = skill_info(&skills, "com.example.a").unwrap();
+        assert_eq!(record.name, "Test");
+        assert_eq!(record.vap_version, "Alpha");
+        assert_eq!(record.address, address(1000));
+        assert_eq!(record.capabilities, [CapabilityRange::new("text", 1, 2)]);
+        assert_eq!(record.registered_at, record.last_seen);
+        assert!(record.intents.is_empty());
+        assert!(record.enabled_intents().is_empty());
+    }
+
+    #[test]
+    fn records_keep_intents_and_scopes() {
+        let mut record = SkillRecord::new(&connect("com.example.a", "Test"), address(1000), 0);
+        record.set_nlu_data(&[
+            nlu_data("en", &[("weather", MAIN_SCOPE), ("yes", "confirm")]),
+            nlu_data("es", &[("weather", MAIN_SCOPE), ("no", "confirm")]),
+        ]);
+
+        assert_eq!(record.languages.len(), 2);
+        assert_eq!(record.intents, ["no", "weather", "yes"]);
+        assert_eq!(record.enabled_intents(), BTreeSet::from(["weather".to_string()]));
+
+        let confirm = ["confirm".to_string()];
+        assert_eq!(record.unknown_scope(&confirm), None);
+        assert_eq!(record.unknown_scope(&["other".to_string()]).map(String::as_str), Some("other"));
+        record.set_scopes(&confirm, &[MAIN_SCOPE.to_string()]);
+        assert_eq!(record.enabled_intents().into_iter().collect::<Vec<_>>(), ["no", "yes"]);
+
+        // Registering again forgets scopes that are gone
+        record.set_nlu_data(&[nlu_data("en", &[("weather", MAIN_SCOPE)])]);
+        assert_eq!(record.enabled_scopes, BTreeSet::from([MAIN_SCOPE.to_string()]));
+    }
+
+    #[test]
+    fn capabilities_are_adapted_for_the_skill() {
+        let skills = registry(&["com.example.a"]);
+        let converters = SharedConverters::default();
+        converters.lock().unwrap().add("text", 3, |c| c);
+
+        let descriptor = |name: &str, version| ClientDataCapability { name: name.into(), version };
+        let client = ClientData {
+            system_id: "com.example.speaker".into(),
+            capabilities: vec![descriptor("text", 3), descriptor("sound", 1)],
+        };
+        let seen = client_for_skill(&skills, &converters, "com.example.a", client.clone());
+        let seen: Vec<_> = seen.capabilities.iter().map(|c| (c.name.as_str(), c.version)).collect();
+        assert_eq!(seen, [("text", 2)]);
+        // Skills that are not connected declared nothing
+        let seen = client_for_skill(&skills, &converters, "com.example.unknown", client);
+        assert_eq!(seen.capabilities.len(), 2);
+
+        let answer = |name: &str, code, version| QueryDataCapability {
+            name: name.into(),
+            code,
+            version,
+            data: HashMap::new(),
+        };
+        let data = vec![QueryData {
+            client_id: "com.example.speaker".into(),
+            capabilities: vec![answer("text", 205, 3), answer("sound", 205, 1), answer("sound", 404, 1)],
+        }];
+        let data = query_answer_for_skill(&skills, &converters, "com.example.a", data);
+        let answers: Vec<_> = data[0].capabilities.iter().map(|c| (c.name.as_str(), c.code, c.version)).collect();
+        assert_eq!(answers, [("text", 205, 2), ("sound", 404, 1)]);
+    }
+
+    #[test]
+    fn skills_expire_after_the_lease() {
+        let skills = registry(&["com.example.a", "com.example.b"]);
+        skills.lock().unwrap().get_mut("com.example.a").unwrap().last_seen -= Duration::from_secs(120);
+
+        assert_eq!(expired_skills(&skills, Duration::from_secs(60)), ["com.example.a"]);
+        assert!(expired_skills(&skills, Duration::from_secs(600)).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+// Protection of the messages of skills that share a key with the register.
+// Without the `oscore` feature everything goes in the clear.
+
+#[cfg(feature = "oscore")]
+pub use self::oscore::*;
+
+#[cfg(not(feature = "oscore"))]
+pub use self::plain::*;
+
+#[cfg(feature = "oscore")]
+mod oscore {
+    use std::collections::HashMap;
+    use std::sync::Mutex as SyncMutex;
+
+    use coap_lite::{CoapOption, Packet};
+    use vap_common_skill::oscore::{self, RequestBinding, SecurityContext};
+
+    use crate::{Error, SkillRegisterConfig};
+
+    /// What the answer to a request is bound to, `None` for plain requests
+    pub type Binding = Option<(Vec<u8>, RequestBinding)>;
+
+    struct Peer {
+        /// The only skill that can use this key
+        skill_id: String,
+        context: SecurityContext,
+        /// Notifications are answers to the observe request
+        observation: Option<RequestBinding>,
+    }
+
+    /// Skills with a key, by their sender id
+    pub struct Security {
+        peers: SyncMutex<HashMap<Vec<u8>, Peer>>,
+    }
+
+    impl Security {
+        pub fn new(config: &SkillRegisterConfig) -> Self {
+            let peers = config
+                .keys
+                .iter()
+                .map(|(skill_id, secret)| {
+                    let peer = Peer {
+                        skill_id: skill_id.clone(),
+                        context: SecurityContext::for_register(skill_id, secret),
+                        observation: None,
+                    };
+                    (oscore::sender_id(skill_id), peer)
+                })
+                .collect();
+
+            Self {
+                peers: SyncMutex::new(peers),
+            }
+        }
+
+        /// Decrypts a request in place. Once any skill has a key every skill
+        /// needs one: plain requests are refused, except for discovery.
+        pub fn open(&self, packet: &mut Packet) -> Result<Binding, Error> {
+            let mut peers = self.peers.lock().unwrap();
+            let kid = match oscore::kid(packet) {
+                Err(oscore::Error::NotProtected) if peers.is_empty() || is_discovery(packet) => return Ok(None),
+                kid => kid?.ok_or(oscore::Error::UnknownSender)?,
+            };
+            let peer = peers.get_mut(&kid).ok_or(oscore::Error::UnknownSender)?;
+            let binding = peer.context.unprotect(packet, None)?;
+
+            if matches!(packet.get_observe_value(), Some(Ok(0))) {
+                peer.observation = Some(binding.clone());
+            }
+
+            Ok(Some((kid, binding)))
+        }
+
+        /// The skill whose key protected a request, it can only act as
+        /// itself. `None` for plain requests.
+        pub fn sender(&self, binding: &Binding) -> Option<String> {
+            let (kid, _) = binding.as_ref()?;
+            self.peers.lock().unwrap().get(kid).map(|p| p.skill_id.clone())
+        }
+
+        /// Encrypts the answer to a request opened by [`Security::open`]
+        pub fn seal_response(&self, packet: &mut Packet, binding: Binding) -> Result<(), Error> {
+            if let Some((kid, binding)) = binding {
+                let mut peers = self.peers.lock().unwrap();
+                let peer = peers.get_mut(&kid).ok_or(oscore::Error::UnknownSender)?;
+                peer.context.protect(packet, Some(&binding))?;
+            }
+
+            Ok(())
+        }
+
+        /// Turns `packet` into the answer to a request refused with `error`:
+        /// skills whose request might be an old one are asked to send it
+        /// again with an Echo, anything else is refused in the clear.
+        pub fn challenge(&self, packet: &mut Packet, error: &Error) -> Result<(), Error> {
+            if let Error::Oscore(oscore::Error::Unverified(binding)) = error {
+                let mut peers = self.peers.lock().unwrap();
+                let peer = peers.get_mut(&binding.kid).ok_or(oscore::Error::UnknownSender)?;
+                peer.context.challenge(packet, binding)?;
+            }
+
+            Ok(())
+        }
+
+        /// Encrypts a notification for a skill. Like with requests, they
+        /// only go in the clear when no skill has a key.
+        pub fn seal_notification(&self, skill_id: &str, packet: &mut Packet) -> Result<(), Error> {
+            let mut peers = self.peers.lock().unwrap();
+            if peers.is_empty() {
+                return Ok(());
+            }
+            let peer = peers
+                .values_mut()
+                .find(|p| p.skill_id == skill_id)
+                .ok_or(oscore::Error::UnknownSender)?;
+            let observation = peer
+                .observation
+                .clone()
+                .ok_or_else(|| Error::SkillNotObserving(skill_id.to_string()))?;
+            peer.context.protect(packet, Some(&observation))?;
+
+            Ok(())
+        }
+    }
+
+    fn is_discovery(packet: &Packet) -> bool {
+        let path: Vec<_> = packet
+            .get_option(CoapOption::UriPath)
+            .map(|p| p.iter().map(|s| s.as_slice()).collect())
+            .unwrap_or_default();
+
+        path == [b".well-known".as_slice(), b"core".as_slice()]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::net::SocketAddr;
+
+        use coap_lite::{CoapRequest, MessageClass, RequestType as Method, ResponseType};
+
+        use super::*;
+
+        const SKILL_ID: &str = "com.example.test";
+        const SECRET: &[u8] = b"a secret shared over loopback";
+
+        fn request(path: &str) -> CoapRequest<SocketAddr> {
+            let mut request = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path(path);
+            request
+        }
+
+        #[test]
+        fn skills_with_a_key() {
+            let security = Security::new(&SkillRegisterConfig::new().psk(SKILL_ID, SECRET.to_vec()));
+            let mut skill = SecurityContext::for_skill(SKILL_ID, SECRET);
+
+            // Plain messages are refused, except for discovery
+            let mut discovery = request(".well-known/core");
+            assert!(matches!(security.open(&mut discovery.message), Ok(None)));
+            let mut query = request("vap/skillRegistry/query");
+            assert!(security.open(&mut query.message.clone()).is_err());
+
+            // The first request is answered with an Echo to send back
+            let mut first = query.clone();
+            let sent = skill.protect(&mut first.message, None).unwrap();
+            let error = security.open(&mut first.message).unwrap_err();
+            let mut challenge = Packet::new();
+            security.challenge(&mut challenge, &error).unwrap();
+            skill.unprotect(&mut challenge, Some(&sent)).unwrap();
+            assert_eq!(challenge.header.code, MessageClass::Response(ResponseType::Unauthorized));
+            let echo = challenge.get_first_option(CoapOption::Unknown(oscore::ECHO)).unwrap();
+            query.message.add_option(CoapOption::Unknown(oscore::ECHO), echo.clone());
+
+            let sent = skill.protect(&mut query.message, None).unwrap();
+            assert_eq!(query.get_path(), "");
+            let binding = security.open(&mut query.message).unwrap();
+            assert_eq!(security.sender(&binding).as_deref(), Some(SKILL_ID));
+            assert_eq!(query.get_path(), "vap/skillRegistry/query");
+            assert_eq!(query.message.header.code, MessageClass::Request(Method::Get));
+
+            let mut response = Packet::new();
+            response.header.code = MessageClass::Response(ResponseType::Content);
+            response.payload = b"answer".to_vec();
+            security.seal_response(&mut response, binding).unwrap();
+            assert_ne!(response.payload, b"answer");
+            skill.unprotect(&mut response, Some(&sent)).unwrap();
+            assert_eq!(response.payload, b"answer");
+            assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+
+            // Somebody without the secret can't act as the skill
+            let mut impostor = SecurityContext::for_skill(SKILL_ID, b"a guessed secret");
+            let mut forged = request("vap/skillRegistry/query");
+            impostor.protect(&mut forged.message, None).unwrap();
+            assert!(security.open(&mut forged.message).is_err());
+
+            // Nothing goes in the clear to skills without a key
+            let mut notification = Packet::new();
+            notification.payload = b"request".to_vec();
+            assert!(security.seal_notification("com.example.other", &mut notification).is_err());
+        }
+    }
+}
+
+#[cfg(not(feature = "oscore"))]
+mod plain {
+    use coap_lite::Packet;
+
+    use crate::{Error, SkillRegisterConfig};
+
+    /// Plain requests are bound to nothing
+    pub type Binding = ();
+
+    pub struct Security;
+
+    impl Security {
+        pub fn new(_config: &SkillRegisterConfig) -> Self {
+            Self
+        }
+
+        pub fn open(&self, _packet: &mut Packet) -> Result<Binding, Error> {
+            Ok(())
+        }
+
+        pub fn sender(&self, _binding: &Binding) -> Option<String> {
+            None
+        }
+
+        pub fn seal_response(&self, _packet: &mut Packet, _binding: Binding) -> Result<(), Error> {
+            Ok(())
+        }
+
+        pub fn challenge(&self, _packet: &mut Packet, _error: &Error) -> Result<(), Error> {
+            Ok(())
+        }
+
+        pub fn seal_notification(&self, _skill_id: &str, _packet: &mut Packet) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+}
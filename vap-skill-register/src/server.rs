@@ -0,0 +1,346 @@
+// A small CoAP server built on coap-lite. Owning the socket lets the register
+// keep its own list of observers and publish notifications to them directly.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use coap_lite::{CoapRequest, CoapResponse, MessageClass, MessageType, Packet, ResponseType};
+use futures::channel::mpsc;
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::security::Security;
+use crate::Error;
+
+/// Biggest datagram we are willing to receive
+const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Multicast address for "All CoAP nodes" in IPv4
+const ALL_COAP_IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+
+/// Multicast address for "All CoAP nodes" in IPv6 (link-local scope)
+const ALL_COAP_IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfd);
+
+/// Observe sequence numbers are 24 bits long
+const OBSERVE_SEQ_MASK: u32 = 0xFF_FFFF;
+
+/// How long a message id identifies the same request (EXCHANGE_LIFETIME in
+/// RFC 7252), retransmissions arrive within this time
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// A request is identified by who sent it and its message id
+type ExchangeKey = (SocketAddr, u16);
+
+/// Requests received recently, so that retransmissions (and duplicates)
+/// are answered with the same response instead of being handled again.
+#[derive(Default)]
+struct Exchanges {
+    /// `None` while the request is being handled, otherwise the bytes of
+    /// the answer (empty if nothing was sent)
+    answers: HashMap<ExchangeKey, Option<Vec<u8>>>,
+    received: VecDeque<(Instant, ExchangeKey)>,
+}
+
+impl Exchanges {
+    /// Remembers a request, if it was already received returns what to do
+    /// with it: `Some(None)` when it is still being handled (it is ignored)
+    /// and `Some(Some(bytes))` with the answer given to it.
+    fn receive(&mut self, key: ExchangeKey) -> Option<Option<Vec<u8>>> {
+        let now = Instant::now();
+        while let Some((received, old)) = self.received.front() {
+            if now.duration_since(*received) < EXCHANGE_LIFETIME {
+                break;
+            }
+            self.answers.remove(old);
+            self.received.pop_front();
+        }
+
+        if let Some(answer) = self.answers.get(&key) {
+            return Some(answer.clone());
+        }
+        self.answers.insert(key, None);
+        self.received.push_back((now, key));
+
+        None
+    }
+
+    /// A request is answered, retransmissions will get the same answer
+    fn answer(&mut self, key: ExchangeKey, bytes: Vec<u8>) {
+        if let Some(answer) = self.answers.get_mut(&key) {
+            *answer = Some(bytes);
+        }
+    }
+
+    /// A request couldn't be handled, a retransmission will be tried again
+    fn forget(&mut self, key: ExchangeKey) {
+        self.answers.remove(&key);
+    }
+}
+
+/// Somebody observing a resource
+struct Observer {
+    address: SocketAddr,
+    token: Vec<u8>,
+    sequence: u32,
+    /// Id of the last notification, used to know what a reset refers to
+    last_message_id: Option<u16>,
+}
+
+/// A datagram waiting to be sent and where to
+type Outgoing = (SocketAddr, Vec<u8>);
+
+/// Keeps account of who observes each skill resource and sends them the
+/// notifications through the same socket the server uses. Notifications are
+/// queued and sent by [`ServerSocket::serve`], which waits for the socket to
+/// be ready instead of dropping them when its buffer is full.
+pub struct Notifier {
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    observers: SyncMutex<HashMap<String, Observer>>,
+    message_id: AtomicU16,
+    security: Security,
+}
+
+impl Notifier {
+    fn new(outgoing: mpsc::UnboundedSender<Outgoing>, security: Security) -> Self {
+        // Start from a random-ish id so that we don't repeat ids after a restart
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or_default();
+
+        Self {
+            outgoing,
+            observers: SyncMutex::new(HashMap::new()),
+            message_id: AtomicU16::new(seed),
+            security,
+        }
+    }
+
+    /// Registers an observer for a skill (replacing the last one) and
+    /// returns the observe sequence number to be used in the answer.
+    pub fn register(&self, skill_id: &str, address: SocketAddr, token: Vec<u8>) -> u32 {
+        let observer = Observer {
+            address,
+            token,
+            sequence: 0,
+            last_message_id: None,
+        };
+        self.observers
+            .lock()
+            .unwrap()
+            .insert(skill_id.to_string(), observer);
+
+        0
+    }
+
+    /// Stops sending notifications for a skill
+    pub fn deregister(&self, skill_id: &str) {
+        self.observers.lock().unwrap().remove(skill_id);
+    }
+
+    /// A reset was received, if it was an answer to a notification the
+    /// observer is gone and the id of its skill is returned.
+    pub fn reset(&self, source: SocketAddr, message_id: u16) -> Option<String> {
+        let mut observers = self.observers.lock().unwrap();
+        let skill_id = observers
+            .iter()
+            .find(|(_, o)| o.address == source && o.last_message_id == Some(message_id))
+            .map(|(id, _)| id.clone())?;
+        observers.remove(&skill_id);
+
+        Some(skill_id)
+    }
+
+    /// Sends a notification with `payload` to whoever observes the skill
+    pub fn notify(&self, skill_id: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let (address, bytes) = {
+            let mut observers = self.observers.lock().unwrap();
+            let observer = observers
+                .get_mut(skill_id)
+                .ok_or_else(|| Error::SkillNotObserving(skill_id.to_string()))?;
+            observer.sequence = (observer.sequence + 1) & OBSERVE_SEQ_MASK;
+
+            let mut packet = Packet::new();
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.code = MessageClass::Response(ResponseType::Content);
+            packet.header.message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
+            observer.last_message_id = Some(packet.header.message_id);
+            packet.set_token(observer.token.clone());
+            packet.set_observe_value(observer.sequence);
+            packet.payload = payload;
+            self.security.seal_notification(skill_id, &mut packet)?;
+
+            (observer.address, to_bytes(&packet)?)
+        };
+
+        self.outgoing
+            .unbounded_send((address, bytes))
+            .map_err(|_| Error::NotServing)
+    }
+}
+
+fn to_bytes(packet: &Packet) -> Result<Vec<u8>, Error> {
+    packet.to_bytes().map_err(|_| Error::PacketTooBig)
+}
+
+/// The socket of the server, it is created beforehand so that errors are
+/// known as soon as possible and so that the notifier can use it.
+pub struct ServerSocket {
+    socket: StdUdpSocket,
+    notifier: Arc<Notifier>,
+    outgoing: mpsc::UnboundedReceiver<Outgoing>,
+}
+
+impl ServerSocket {
+    /// Binds to `address`, if it is an IPv6 one `dual_stack` decides whether
+    /// IPv4 traffic is accepted as well. Messages go through `security`.
+    pub fn bind(address: SocketAddr, dual_stack: bool, security: Security) -> Result<(Self, Arc<Notifier>), Error> {
+        let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+        if address.is_ipv6() {
+            socket.set_only_v6(!dual_stack)?;
+        }
+        socket.bind(&address.into())?;
+        socket.set_nonblocking(true)?;
+        let socket: StdUdpSocket = socket.into();
+
+        let joined = match address {
+            SocketAddr::V4(a) => {
+                let interface = if a.ip().is_loopback() { Ipv4Addr::UNSPECIFIED } else { *a.ip() };
+                socket.join_multicast_v4(&ALL_COAP_IPV4, &interface)
+            }
+            SocketAddr::V6(_) => socket.join_multicast_v6(&ALL_COAP_IPV6, 0),
+        };
+        if let Err(e) = joined {
+            println!("Couldn't join the CoAP multicast group, discovery won't work: {}", e);
+        }
+
+        let (out_send, outgoing) = mpsc::unbounded();
+        let notifier = Arc::new(Notifier::new(out_send, security));
+        Ok((Self { socket, notifier: notifier.clone(), outgoing }, notifier))
+    }
+
+    /// The address the server really listens on (e.g: when bound to port 0)
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Receives requests and answers them with `handler`, along with the
+    /// skill whose key protected the request if any. Requests are handled
+    /// concurrently and retransmitted ones get the answer of the first one.
+    /// `on_gone` is called with the id of a skill that rejected a
+    /// notification.
+    pub async fn serve<F, Fut, G>(self, mut handler: F, mut on_gone: G) -> Result<(), Error>
+    where
+        F: FnMut(CoapRequest<SocketAddr>, Option<String>) -> Fut,
+        Fut: Future<Output = Option<CoapResponse>>,
+        G: FnMut(String),
+    {
+        let socket = UdpSocket::from_std(self.socket)?;
+        let mut outgoing = self.outgoing;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        let mut pending = FuturesUnordered::new();
+        let mut exchanges = Exchanges::default();
+
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (size, source) = match received {
+                        Ok(r) => r,
+                        Err(e) => {
+                            // Some systems report errors of past sends here
+                            println!("Error while receiving: {}", e);
+                            continue;
+                        }
+                    };
+
+                    match Packet::from_bytes(&buf[..size]) {
+                        Ok(mut packet) => {
+                            // Empty messages are acknowledgements, resets and pings
+                            if packet.header.code != MessageClass::Empty {
+                                let key = (source, packet.header.message_id);
+                                match exchanges.receive(key) {
+                                    Some(Some(bytes)) if !bytes.is_empty() => {
+                                        if let Err(e) = socket.send_to(&bytes, source).await {
+                                            println!("Failed to answer {}: {}", source, e);
+                                        }
+                                    }
+                                    Some(_) => {}
+                                    None => match self.notifier.security.open(&mut packet) {
+                                        Ok(binding) => {
+                                            let request = CoapRequest::from_packet(packet, source);
+                                            let sender = self.notifier.security.sender(&binding);
+                                            let fut = handler(request, sender);
+                                            pending.push(async move { (fut.await, key, binding) });
+                                        }
+                                        Err(e) => {
+                                            println!("Refused a message from {}: {}", source, e);
+                                            exchanges.forget(key);
+                                            refuse(&socket, &self.notifier.security, packet, source, &e).await;
+                                        }
+                                    },
+                                }
+                            }
+                            else if packet.header.get_type() == MessageType::Reset {
+                                if let Some(skill_id) = self.notifier.reset(source, packet.header.message_id) {
+                                    on_gone(skill_id);
+                                }
+                            }
+                        }
+                        Err(_) => {
+                            println!("Received a malformed packet from {}", source);
+                        }
+                    }
+                }
+
+                Some((address, bytes)) = outgoing.next() => {
+                    if let Err(e) = socket.send_to(&bytes, address).await {
+                        println!("Failed to notify {}: {}", address, e);
+                    }
+                }
+
+                Some((response, key, binding)) = pending.next(), if !pending.is_empty() => {
+                    let (source, _) = key;
+                    match response {
+                        Some(mut response) => {
+                            let sealed = self.notifier.security.seal_response(&mut response.message, binding);
+                            match sealed.and_then(|()| to_bytes(&response.message)) {
+                                Ok(bytes) => {
+                                    if let Err(e) = socket.send_to(&bytes, source).await {
+                                        println!("Failed to answer {}: {}", source, e);
+                                    }
+                                    exchanges.answer(key, bytes);
+                                }
+                                Err(e) => {
+                                    println!("Failed to answer {}: {}", source, e);
+                                    exchanges.forget(key);
+                                }
+                            }
+                        }
+                        None => exchanges.answer(key, vec![]),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers a message that couldn't be verified, in the clear since we
+/// can't know who sent it unless it only has to prove that it is fresh.
+async fn refuse(socket: &UdpSocket, security: &Security, packet: Packet, source: SocketAddr, error: &Error) {
+    let request = CoapRequest::from_packet(packet, source);
+    if let Some(mut response) = request.response {
+        response.set_status(ResponseType::Unauthorized);
+        if let Err(e) = security.challenge(&mut response.message, error) {
+            println!("Couldn't ask {} to prove its message is fresh: {}", source, e);
+            return;
+        }
+        if let Ok(bytes) = to_bytes(&response.message) {
+            let _ = socket.send_to(&bytes, source).await;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Fixtures shared by the unit tests of the register
+
+use vap_common_skill::structures::msg_register_intents::{
+    NluData, NluDataEntity, NluDataEntityKind, NluDataIntent, NluDataIntentUtterance, NluDataSlot,
+};
+use vap_common_skill::structures::msg_skill_request::{RequestData, RequestDataKind};
+use vap_common_skill::structures::Language;
+
+use crate::{Candidate, ConnectAttempt};
+
+/// A skill asking to connect from `source` (e.g: "127.0.0.1:1000")
+pub fn attempt(skill_id: &str, source: &str) -> ConnectAttempt {
+    ConnectAttempt {
+        skill_id: skill_id.to_string(),
+        name: "Test".to_string(),
+        source: source.parse().unwrap(),
+    }
+}
+
+pub fn candidate(skill_id: &str, confidence: f32, registration_order: u64) -> Candidate {
+    Candidate {
+        skill_id: skill_id.into(),
+        confidence,
+        registration_order,
+    }
+}
+
+/// An English request for `intent` without slots
+pub fn request(intent: &str) -> RequestData {
+    RequestData {
+        type_: RequestDataKind::Intent,
+        intent: intent.into(),
+        locale: "en-US".into(),
+        slots: vec![],
+    }
+}
+
+pub fn language(language: &str, country: Option<&str>) -> Language {
+    Language {
+        language: language.into(),
+        country: country.map(Into::into),
+        extra: None,
+    }
+}
+
+pub fn nlu_data(language: Language, intents: Vec<NluDataIntent>, entities: Vec<NluDataEntity>) -> NluData {
+    NluData {
+        language,
+        intents,
+        entities,
+    }
+}
+
+pub fn intent(name: &str, scope: &str, utterances: &[&str], slots: Vec<NluDataSlot>) -> NluDataIntent {
+    NluDataIntent {
+        name: name.into(),
+        utterances: utterances
+            .iter()
+            .map(|text| NluDataIntentUtterance { text: text.to_string() })
+            .collect(),
+        slots,
+        scope: scope.into(),
+    }
+}
+
+/// A required slot
+pub fn slot(name: &str, entity: &str, prompt: Option<&str>, reprompt: Option<&str>) -> NluDataSlot {
+    NluDataSlot {
+        name: name.into(),
+        entity: entity.into(),
+        required: true,
+        prompt: prompt.map(Into::into),
+        reprompt: reprompt.map(Into::into),
+    }
+}
+
+/// A loose entity without data
+pub fn entity(name: &str, kind: NluDataEntityKind) -> NluDataEntity {
+    NluDataEntity {
+        name: name.into(),
+        strict: false,
+        data: vec![],
+        kind,
+    }
+}
@@ -0,0 +1,286 @@
+// Check that the intents registered by skills make sense
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use vap_common_skill::structures::msg_register_intents::{
+    NluData, NluDataEntity, NluDataEntityKind, NluDataIntent, BUILTIN_ENTITIES,
+};
+use vap_common_skill::structures::{Language, VapError};
+
+/// Checks everything a skill sends in registerIntents, the error names the
+/// first problem found. Objects are paths like `en-US/intent/slot`.
+pub(crate) fn validate_nlu_data(nlu_data: &[NluData]) -> Result<(), VapError> {
+    let mut languages = HashSet::new();
+    // Intent name -> How the first language that has it defines it
+    let mut shapes = HashMap::new();
+    for data in nlu_data {
+        let language = language_tag(&data.language);
+        // A second set for the same language would repeat its intents
+        if !languages.insert(language.clone()) {
+            return Err(VapError::duplicated(language));
+        }
+
+        let entities = validate_entities(&data.entities, &language)?;
+        let mut intents = HashSet::new();
+        for intent in &data.intents {
+            let path = format!("{}/{}", language, intent.name);
+            // The same intent can be in several scopes (e.g: a "yes" follow-up)
+            if !intents.insert((intent.scope.as_str(), intent.name.as_str())) {
+                return Err(VapError::duplicated(path));
+            }
+
+            validate_slots(intent, &path)?;
+            for slot in &intent.slots {
+                let entity = slot.entity.as_str();
+                if !entities.contains_key(entity) && !BUILTIN_ENTITIES.contains(&entity) {
+                    return Err(VapError::missing_entity(format!("{}/{}", language, slot.entity)));
+                }
+            }
+            for utterance in &intent.utterances {
+                for placeholder in placeholders(&utterance.text) {
+                    if !intent.slots.iter().any(|s| s.name == placeholder) {
+                        return Err(VapError::missing_slot(format!("{}/{}", path, placeholder)));
+                    }
+                }
+            }
+        }
+
+        // Every language must define an intent the same way
+        for (name, shape) in intent_shapes(&data.intents) {
+            match shapes.get(name) {
+                Some(first) if *first != shape => {
+                    return Err(VapError::mismatched_intent(format!("{}/{}", language, name)));
+                }
+                Some(_) => {}
+                None => {
+                    shapes.insert(name, shape);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Scope -> Names and entities of the slots
+type IntentShape<'a> = BTreeMap<&'a str, BTreeSet<(&'a str, &'a str)>>;
+
+/// What must match between the languages for each intent name
+fn intent_shapes(intents: &[NluDataIntent]) -> HashMap<&str, IntentShape<'_>> {
+    let mut shapes: HashMap<_, IntentShape> = HashMap::new();
+    for intent in intents {
+        let slots = intent.slots.iter().map(|s| (s.name.as_str(), s.entity.as_str())).collect();
+        shapes.entry(intent.name.as_str()).or_default().insert(intent.scope.as_str(), slots);
+    }
+
+    shapes
+}
+
+/// Entity names can't repeat, ranges must make sense and references must
+/// point to a list of the same language. Returns the entities by name.
+fn validate_entities<'a>(
+    entities: &'a [NluDataEntity],
+    language: &str,
+) -> Result<HashMap<&'a str, &'a NluDataEntity>, VapError> {
+    let mut by_name = HashMap::new();
+    for entity in entities {
+        if by_name.insert(entity.name.as_str(), entity).is_some() {
+            return Err(VapError::duplicated(format!("{}/{}", language, entity.name)));
+        }
+    }
+
+    for entity in entities {
+        let object = format!("{}/{}", language, entity.name);
+        match &entity.kind {
+            NluDataEntityKind::List => {}
+            NluDataEntityKind::Regex { pattern } => {
+                if pattern.is_empty() {
+                    return Err(VapError::missing_field(Some(format!("{}/pattern", object))));
+                }
+            }
+            NluDataEntityKind::Range { min, max, step } => {
+                let finite = min.is_finite() && max.is_finite() && step.is_none_or(f64::is_finite);
+                if !finite || min > max || step.is_some_and(|s| s <= 0.0) {
+                    return Err(VapError::invalid_entity(object));
+                }
+            }
+            NluDataEntityKind::Reference { entity: target } => match by_name.get(target.as_str()) {
+                // Only plain lists can be shared, so references never chain
+                Some(t) if t.kind == NluDataEntityKind::List => {}
+                Some(_) => return Err(VapError::invalid_entity(object)),
+                None => return Err(VapError::missing_entity(format!("{}/{}", language, target))),
+            },
+        }
+    }
+
+    Ok(by_name)
+}
+
+/// Slots need a name and an entity, names can't repeat and a reprompt is
+/// only used after a prompt.
+fn validate_slots(intent: &NluDataIntent, path: &str) -> Result<(), VapError> {
+    let mut names = HashSet::new();
+    for slot in &intent.slots {
+        let object = format!("{}/{}", path, slot.name);
+        if slot.name.is_empty() {
+            return Err(VapError::missing_field(Some(format!("{}/name", object))));
+        }
+        if !names.insert(slot.name.as_str()) {
+            return Err(VapError::duplicated(object));
+        }
+        if slot.entity.is_empty() {
+            return Err(VapError::missing_field(Some(format!("{}/entity", object))));
+        }
+        if slot.reprompt.is_some() && slot.prompt.is_none() {
+            return Err(VapError::missing_field(Some(format!("{}/prompt", object))));
+        }
+    }
+
+    Ok(())
+}
+
+/// The slots used in an utterance, written as `$slot`
+fn placeholders(utterance: &str) -> impl Iterator<Item = &str> {
+    utterance.split('$').skip(1).filter_map(|rest| {
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        Some(&rest[..end]).filter(|name| !name.is_empty())
+    })
+}
+
+/// Like `en-US`, as written by skills
+fn language_tag(language: &Language) -> String {
+    [Some(&language.language), language.extra.as_ref(), language.country.as_ref()]
+        .iter()
+        .flatten()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use vap_common_skill::structures::error_types;
+    use vap_common_skill::structures::msg_register_intents::{NluDataSlot, DATE_ENTITY, MAIN_SCOPE};
+
+    use super::*;
+    use crate::test_support::{entity, language, nlu_data, slot};
+
+    fn intent(utterance: &str, slots: Vec<NluDataSlot>) -> NluDataIntent {
+        crate::test_support::intent("set_alarm", MAIN_SCOPE, &[utterance], slots)
+    }
+
+    fn time(prompt: Option<&str>, reprompt: Option<&str>) -> NluDataSlot {
+        slot("time", "time", prompt, reprompt)
+    }
+
+    fn english(country: &str, intents: Vec<NluDataIntent>) -> NluData {
+        nlu_data(language("en", Some(country)), intents, vec![entity("time", NluDataEntityKind::List)])
+    }
+
+    fn error(nlu: &[NluData]) -> (String, String) {
+        let e = validate_nlu_data(nlu).unwrap_err();
+        (e.type_, e.object.unwrap())
+    }
+
+    #[test]
+    fn slots() {
+        let ok = intent("wake me up at $time", vec![time(Some("When?"), Some("At what time?"))]);
+        assert!(validate_slots(&ok, "en-US/set_alarm").is_ok());
+        assert!(validate_slots(&intent("", vec![time(None, None)]), "en-US/set_alarm").is_ok());
+
+        let e = validate_slots(&intent("", vec![time(None, Some("At what time?"))]), "en-US/set_alarm").unwrap_err();
+        assert_eq!(e.type_, error_types::MISSING_FIELD);
+        assert_eq!(e.object.as_deref(), Some("en-US/set_alarm/time/prompt"));
+
+        let e = validate_slots(&intent("", vec![time(None, None), time(None, None)]), "en-US/set_alarm").unwrap_err();
+        assert_eq!(e.type_, error_types::DUPLICATED);
+
+        let e = validate_slots(&intent("", vec![slot("", "time", None, None)]), "en-US/set_alarm").unwrap_err();
+        assert_eq!(e.type_, error_types::MISSING_FIELD);
+        assert_eq!(e.object.as_deref(), Some("en-US/set_alarm//name"));
+    }
+
+    #[test]
+    fn intents() {
+        let alarm = || intent("wake me up at $time, please", vec![time(None, None)]);
+        assert!(validate_nlu_data(&[english("US", vec![alarm()]), english("GB", vec![alarm()])]).is_ok());
+
+        let missing_slot = intent("wake me up at $time on $day", vec![time(None, None)]);
+        assert_eq!(
+            error(&[english("US", vec![missing_slot])]),
+            (error_types::MISSING_SLOT.into(), "en-US/set_alarm/day".into())
+        );
+
+        let missing_entity = slot("time", "clock", None, None);
+        assert_eq!(
+            error(&[english("US", vec![intent("$time", vec![missing_entity])])]),
+            (error_types::MISSING_ENTITY.into(), "en-US/clock".into())
+        );
+
+        assert_eq!(
+            error(&[english("US", vec![alarm(), alarm()])]),
+            (error_types::DUPLICATED.into(), "en-US/set_alarm".into())
+        );
+        let builtin = slot("time", DATE_ENTITY, None, None);
+        assert!(validate_nlu_data(&[english("US", vec![intent("$time", vec![builtin])])]).is_ok());
+
+        let other_scope = NluDataIntent { scope: "follow_up".into(), ..alarm() };
+        assert!(validate_nlu_data(&[english("US", vec![alarm(), other_scope])]).is_ok());
+        assert_eq!(
+            error(&[english("US", vec![alarm()]), english("US", vec![])]),
+            (error_types::DUPLICATED.into(), "en-US".into())
+        );
+
+        // The same intent must look the same in every language
+        let en_gb = |intents| english("GB", intents);
+        assert!(validate_nlu_data(&[english("US", vec![alarm()]), en_gb(vec![])]).is_ok());
+        let date = intent("wake me up at $time", vec![slot("time", DATE_ENTITY, None, None)]);
+        assert_eq!(
+            error(&[english("US", vec![alarm()]), en_gb(vec![date])]),
+            (error_types::MISMATCHED_INTENT.into(), "en-GB/set_alarm".into())
+        );
+        let follow_up = NluDataIntent { scope: "follow_up".into(), ..alarm() };
+        assert_eq!(
+            error(&[english("US", vec![alarm()]), en_gb(vec![follow_up.clone()])]),
+            (error_types::MISMATCHED_INTENT.into(), "en-GB/set_alarm".into())
+        );
+        assert!(validate_nlu_data(&[english("US", vec![alarm(), follow_up.clone()]), en_gb(vec![follow_up, alarm()])]).is_ok());
+    }
+
+    #[test]
+    fn entities() {
+        let range = |min, max, step| NluDataEntityKind::Range { min, max, step };
+        let reference = |to: &str| NluDataEntityKind::Reference { entity: to.into() };
+        let check = |entities: Vec<NluDataEntity>| validate_entities(&entities, "en-US").map(|_| ()).map_err(|e| (e.type_, e.object.unwrap()));
+
+        assert!(check(vec![
+            entity("cities", NluDataEntityKind::List),
+            entity("hometown", reference("cities")),
+            entity("code", NluDataEntityKind::Regex { pattern: "[A-Z]{3}".into() }),
+            entity("volume", range(0.0, 100.0, Some(5.0))),
+        ])
+        .is_ok());
+
+        let invalid = |name: &str| Err((error_types::INVALID_ENTITY.into(), format!("en-US/{}", name)));
+        assert_eq!(check(vec![entity("volume", range(10.0, 0.0, None))]), invalid("volume"));
+        assert_eq!(check(vec![entity("volume", range(0.0, 10.0, Some(0.0)))]), invalid("volume"));
+        assert_eq!(
+            check(vec![entity("a", reference("b")), entity("b", reference("a"))]),
+            invalid("a")
+        );
+        assert_eq!(
+            check(vec![entity("hometown", reference("cities"))]),
+            Err((error_types::MISSING_ENTITY.into(), "en-US/cities".into()))
+        );
+        assert_eq!(
+            check(vec![entity("code", NluDataEntityKind::Regex { pattern: "".into() })]),
+            Err((error_types::MISSING_FIELD.into(), "en-US/code/pattern".into()))
+        );
+        assert_eq!(
+            check(vec![entity("a", NluDataEntityKind::List), entity("a", NluDataEntityKind::List)]),
+            Err((error_types::DUPLICATED.into(), "en-US/a".into()))
+        );
+    }
+}
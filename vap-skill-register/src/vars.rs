@@ -1,6 +1,11 @@
 // Some vars to be exported (though some are used internally as well)
 
-/// VAP version implemented by this crate
+use vap_common_skill::version::{self, VersionRange};
+
+/// VAP version implemented by this crate, as sent by skills that don't
+/// negotiate
 pub const VAP_VERSION: &str = "Alpha";
+/// Every VAP version this crate can talk with skills
+pub const SUPPORTED_VERSIONS: VersionRange = version::SUPPORTED;
 /// The name used to refer to the skill register itself
 pub const SYSTEM_SELF_ID: &str = "vap.SYSTEM";
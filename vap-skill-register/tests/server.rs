@@ -0,0 +1,873 @@
+// Talks to a running register through UDP like a skill would, to check what
+// goes on the wire: observing, notifications and retransmissions.
+
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::StreamExt;
+use coap_lite::{
+    CoapOption, CoapRequest, MessageClass, MessageType, ObserveOption, Packet, RequestType as Method,
+};
+use serde::Serialize;
+use vap_common_skill::auth::TOKEN_QUERY;
+use vap_skill_register::{
+    structures::{
+        error_types, msg_notification,
+        msg_notification_response,
+        msg_register_intents::{NluData, NluDataIntent, MAIN_SCOPE},
+        msg_skill_request::{ClientData, RequestData, RequestDataKind},
+        Language, MsgConnect, MsgConnectResponse, MsgNotification, MsgNotificationResponse,
+        MsgRegisterIntents, MsgSkillRequest, VapError,
+    },
+    Error, RequestResponse, Response, ResponseType, SkillRegisterConfig, SkillRegisterMessage,
+    SkillRegisterOut, VAP_VERSION,
+};
+
+const SKILL_ID: &str = "com.example.test_skill";
+const OBSERVE_TOKEN: &[u8] = b"obs";
+
+fn packet(method: Method, path: &str, message_id: u16, payload: Vec<u8>) -> Vec<u8> {
+    let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+    request.set_method(method);
+    request.set_path(path);
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = message_id;
+    request.message.payload = payload;
+    request.message.to_bytes().unwrap()
+}
+
+fn observe(skill_id: &str, token: Option<&str>, flag: ObserveOption, message_id: u16) -> Vec<u8> {
+    let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+    request.set_method(Method::Get);
+    request.set_path(&format!("vap/skillRegistry/skills/{}", skill_id));
+    if let Some(token) = token {
+        let query = format!("{}={}", TOKEN_QUERY, token);
+        request.message.add_option(CoapOption::UriQuery, query.into_bytes());
+    }
+    request.set_observe_flag(flag);
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = message_id;
+    request.message.set_token(OBSERVE_TOKEN.to_vec());
+    request.message.to_bytes().unwrap()
+}
+
+fn heartbeat(token: Option<&str>, message_id: u16) -> Vec<u8> {
+    let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+    request.set_method(Method::Get);
+    request.set_path(&format!("vap/skillRegistry/skills/{}", SKILL_ID));
+    if let Some(token) = token {
+        let query = format!("{}={}", TOKEN_QUERY, token);
+        request.message.add_option(CoapOption::UriQuery, query.into_bytes());
+    }
+    request.message.header.set_type(MessageType::Confirmable);
+    request.message.header.message_id = message_id;
+    request.message.to_bytes().unwrap()
+}
+
+fn receive(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = [0; 1500];
+    let size = socket.recv(&mut buf).unwrap();
+    buf[..size].to_vec()
+}
+
+fn exchange(socket: &UdpSocket, bytes: &[u8]) -> Packet {
+    socket.send(bytes).unwrap();
+    Packet::from_bytes(&receive(socket)).unwrap()
+}
+
+fn encode<T: Serialize>(msg: &T) -> Vec<u8> {
+    rmp_serde::to_vec_named(msg).unwrap()
+}
+
+fn connect_payload(skill_id: &str) -> Vec<u8> {
+    encode(&MsgConnect {
+        id: skill_id.into(),
+        name: "Test skill".into(),
+        vap_version: VAP_VERSION.into(),
+        vap_versions: None,
+        capabilities: vec![],
+        auth_token: None,
+    })
+}
+
+fn request() -> RequestData {
+    RequestData {
+        type_: RequestDataKind::CanAnswer,
+        intent: "hello".into(),
+        locale: "en-US".into(),
+        slots: vec![],
+    }
+}
+
+fn client() -> ClientData {
+    ClientData {
+        system_id: "test_client".into(),
+        capabilities: vec![],
+    }
+}
+
+/// Starts a register on a free port which accepts every skill, the kinds
+/// of the messages it receives are sent through the channel.
+fn start_register(
+    rt: &tokio::runtime::Runtime,
+) -> (SocketAddr, SkillRegisterOut, std_mpsc::Receiver<&'static str>) {
+    start_register_with(rt, SkillRegisterConfig::new())
+}
+
+fn start_register_with(
+    rt: &tokio::runtime::Runtime,
+    config: SkillRegisterConfig,
+) -> (SocketAddr, SkillRegisterOut, std_mpsc::Receiver<&'static str>) {
+    let (register, mut stream, out) = config.port(0).build().unwrap();
+    let address = register.local_addr().unwrap();
+    let (kinds, received) = std_mpsc::channel();
+    rt.spawn(async move { register.run().await.unwrap() });
+    rt.spawn(async move {
+        while let Ok((msg, responder)) = stream.recv().await {
+            let kind = match msg {
+                SkillRegisterMessage::Connect(_) => "connect",
+                SkillRegisterMessage::Close(_) => "close",
+                _ => "other",
+            };
+            let payload = encode(&MsgConnectResponse {
+                langs: vec![Language {
+                    language: "en".into(),
+                    country: None,
+                    extra: None,
+                }],
+                vap_version: None,
+                lease: None,
+                auth_token: None,
+            });
+            let _ = responder.send(Response {
+                status: ResponseType::Created,
+                payload,
+            });
+            let _ = kinds.send(kind);
+        }
+    });
+
+    (address, out, received)
+}
+
+fn skill_socket(register: SocketAddr) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.connect(register).unwrap();
+    socket
+}
+
+/// Connects and observes as `skill_id`, returns the token of the skill
+fn connect_and_observe(socket: &UdpSocket, skill_id: &str) -> Option<String> {
+    let connected = exchange(socket, &packet(Method::Post, "vap/skillRegistry/connect", 1, connect_payload(skill_id)));
+    assert_eq!(connected.header.code, MessageClass::Response(ResponseType::Created));
+    let token = rmp_serde::from_read::<_, MsgConnectResponse>(Cursor::new(&connected.payload))
+        .unwrap()
+        .auth_token;
+    let observing = exchange(socket, &observe(skill_id, token.as_deref(), ObserveOption::Register, 2));
+    assert_eq!(observing.header.code, MessageClass::Response(ResponseType::Content));
+
+    token
+}
+
+/// Sends a notification for the skill and returns what the register
+/// answered for each piece of data
+fn notify(
+    socket: &UdpSocket,
+    skill_id: &str,
+    message_id: u16,
+    token: Option<String>,
+    data: msg_notification::Data,
+) -> Vec<msg_notification_response::Data> {
+    let msg = encode(&MsgNotification {
+        skill_id: skill_id.into(),
+        data: vec![data],
+        auth_token: token,
+    });
+    let answer = exchange(socket, &packet(Method::Post, "vap/skillRegistry/notification", message_id, msg));
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Valid));
+
+    rmp_serde::from_read::<_, MsgNotificationResponse>(Cursor::new(&answer.payload))
+        .unwrap()
+        .data
+}
+
+/// Registers one English intent in the main scope
+fn register_intent(
+    socket: &UdpSocket,
+    skill_id: &str,
+    message_id: u16,
+    token: Option<String>,
+    intent: &str,
+) {
+    let msg = encode(&MsgRegisterIntents {
+        skill_id: skill_id.into(),
+        nlu_data: vec![NluData {
+            language: Language {
+                language: "en".into(),
+                country: Some("US".into()),
+                extra: None,
+            },
+            intents: vec![NluDataIntent {
+                name: intent.into(),
+                utterances: vec![],
+                slots: vec![],
+                scope: MAIN_SCOPE.into(),
+            }],
+            entities: vec![],
+        }],
+        auth_token: token,
+    });
+    let path = "vap/skillRegistry/registerIntents";
+    let answer = exchange(socket, &packet(Method::Post, path, message_id, msg));
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Created));
+}
+
+fn received_request(socket: &UdpSocket) -> MsgSkillRequest {
+    let notification = Packet::from_bytes(&receive(socket)).unwrap();
+    rmp_serde::from_read(Cursor::new(&notification.payload)).unwrap()
+}
+
+#[test]
+fn retransmissions_get_the_same_answer() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, _out, kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+
+    let connect = packet(Method::Post, "vap/skillRegistry/connect", 7, connect_payload(SKILL_ID));
+    socket.send(&connect).unwrap();
+    let first = receive(&socket);
+    // As if our first request or the answer to it had been lost
+    socket.send(&connect).unwrap();
+    let second = receive(&socket);
+
+    let answer = Packet::from_bytes(&first).unwrap();
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Created));
+    assert_eq!(answer.header.get_type(), MessageType::Acknowledgement);
+    assert_eq!(answer.header.message_id, 7);
+    assert_eq!(first, second);
+
+    // The register only saw one connection, a new one without the token is refused
+    assert_eq!(kinds.recv_timeout(Duration::from_secs(5)), Ok("connect"));
+    assert!(kinds.try_recv().is_err());
+    let again = exchange(&socket, &packet(Method::Post, "vap/skillRegistry/connect", 8, connect_payload(SKILL_ID)));
+    assert_ne!(again.header.code, MessageClass::Response(ResponseType::Created));
+}
+
+#[test]
+fn floods_of_notifications_arrive() {
+    const REQUESTS: usize = 500;
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+    connect_and_observe(&socket, SKILL_ID);
+    socket2::SockRef::from(&socket).set_recv_buffer_size(1 << 20).unwrap();
+
+    let reader = thread::spawn(move || {
+        let mut request_ids = HashSet::new();
+        let mut buf = [0; 1500];
+        while request_ids.len() < REQUESTS {
+            let Ok(size) = socket.recv(&mut buf) else { break };
+            let notification = Packet::from_bytes(&buf[..size]).unwrap();
+            let asked: MsgSkillRequest = rmp_serde::from_slice(&notification.payload).unwrap();
+            request_ids.insert(asked.request_id);
+        }
+        request_ids.len()
+    });
+
+    // Nobody answers, but every request must have been sent
+    let ids = vec![SKILL_ID.to_string(); REQUESTS];
+    let answers: Vec<_> = rt.block_on(
+        out.skills_answerable_stream(&ids, request(), client(), Duration::from_millis(500))
+            .map(|(_, answer)| answer)
+            .collect(),
+    );
+    assert!(answers.iter().all(|a| matches!(a, Err(Error::Timeout))));
+    assert_eq!(reader.join().unwrap(), REQUESTS);
+}
+
+#[test]
+fn notifications_need_a_connected_skill() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, _out, _kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+    let token = connect_and_observe(&socket, SKILL_ID);
+    let stopped = exchange(&socket, &observe(SKILL_ID, token.as_deref(), ObserveOption::Deregister, 3));
+    assert_eq!(stopped.header.code, MessageClass::Response(ResponseType::Content));
+
+    // Its token is still right, but the skill is gone
+    let msg = encode(&MsgNotification {
+        skill_id: SKILL_ID.into(),
+        data: vec![msg_notification::Data::CanYouAnswer {
+            request_id: 1,
+            confidence: 0.5,
+        }],
+        auth_token: token,
+    });
+    let answer = exchange(&socket, &packet(Method::Post, "vap/skillRegistry/notification", 4, msg));
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::BadRequest));
+    let error: VapError = rmp_serde::from_slice(&answer.payload).unwrap();
+    assert_eq!(error.type_, error_types::WRONG_SKILL_ID);
+}
+
+#[test]
+fn observers_receive_notifications() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, mut out, kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+
+    let connected = exchange(&socket, &packet(Method::Post, "vap/skillRegistry/connect", 1, connect_payload(SKILL_ID)));
+    assert_eq!(connected.header.code, MessageClass::Response(ResponseType::Created));
+    let token = rmp_serde::from_read::<_, MsgConnectResponse>(Cursor::new(&connected.payload))
+        .unwrap()
+        .auth_token;
+
+    // Only the skill itself can observe its resource
+    let observing = exchange(&socket, &observe(SKILL_ID, Some("wrong"), ObserveOption::Register, 2));
+    assert_eq!(observing.header.code, MessageClass::Response(ResponseType::Unauthorized));
+    let observing = exchange(&socket, &observe(SKILL_ID, token.as_deref(), ObserveOption::Register, 5));
+    assert_eq!(observing.header.code, MessageClass::Response(ResponseType::Content));
+    assert_eq!(observing.get_token(), OBSERVE_TOKEN);
+    assert_eq!(observing.get_observe_value().map(Result::unwrap), Some(0));
+
+    let ids = [SKILL_ID.to_string()];
+    let asking = rt.spawn(async move {
+        let answers = out.skills_answerable(&ids, request(), client()).await;
+        (out, answers)
+    });
+
+    let notification = Packet::from_bytes(&receive(&socket)).unwrap();
+    assert_eq!(notification.get_token(), OBSERVE_TOKEN);
+    assert_eq!(notification.get_observe_value().map(Result::unwrap), Some(1));
+    let asked: MsgSkillRequest = rmp_serde::from_read(Cursor::new(&notification.payload)).unwrap();
+    assert_eq!(asked.request.intent, "hello");
+
+    let answer = encode(&MsgNotification {
+        skill_id: SKILL_ID.into(),
+        data: vec![msg_notification::Data::CanYouAnswer {
+            request_id: asked.request_id,
+            confidence: 0.5,
+        }],
+        auth_token: token.clone(),
+    });
+    let answered = exchange(&socket, &packet(Method::Post, "vap/skillRegistry/notification", 3, answer));
+    assert_eq!(answered.header.code, MessageClass::Response(ResponseType::Valid));
+
+    let (out, answers) = rt.block_on(asking).unwrap();
+    assert_eq!(answers.len(), 1);
+    assert_eq!(answers[0].skill_id, SKILL_ID);
+
+    // Requests that are not pending can't be asked for
+    let unknown = exchange(&socket, &packet(Method::Get, &format!("vap/request/{}", asked.request_id), 6, vec![]));
+    assert_eq!(unknown.header.code, MessageClass::Response(ResponseType::BadOption));
+    let error: VapError = rmp_serde::from_slice(&unknown.payload).unwrap();
+    assert_eq!(error.type_, error_types::UNKNOWN_REQUEST);
+
+    // Nobody else can make the skill stop observing, once it does it is gone
+    let deregistered = exchange(&socket, &observe(SKILL_ID, None, ObserveOption::Deregister, 7));
+    assert_eq!(deregistered.header.code, MessageClass::Response(ResponseType::Unauthorized));
+    let deregistered = exchange(&socket, &observe(SKILL_ID, token.as_deref(), ObserveOption::Deregister, 4));
+    assert_eq!(deregistered.header.code, MessageClass::Response(ResponseType::Content));
+    assert_eq!(kinds.recv_timeout(Duration::from_secs(5)), Ok("connect"));
+    assert_eq!(kinds.recv_timeout(Duration::from_secs(5)), Ok("close"));
+    assert!(out.skill_info(SKILL_ID).is_none());
+}
+
+#[test]
+fn late_confidences_are_rejected() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+    let token = connect_and_observe(&socket, SKILL_ID);
+
+    let ids = [SKILL_ID.to_string(), "com.example.not_connected".to_string()];
+    let asking = rt.spawn(async move {
+        let timeout = Duration::from_millis(200);
+        let mut answers: Vec<_> = out.skills_answerable_stream(&ids, request(), client(), timeout).collect().await;
+        answers.sort_by(|a, b| a.0.cmp(&b.0));
+        answers
+    });
+
+    // The skill is asked but doesn't answer in time
+    let asked = received_request(&socket);
+    let answers = rt.block_on(asking).unwrap();
+    assert_eq!(answers.len(), 2);
+    assert!(matches!(&answers[0], (id, Err(Error::SkillNotObserving(_))) if id == "com.example.not_connected"));
+    assert!(matches!(&answers[1], (id, Err(Error::Timeout)) if id == SKILL_ID));
+
+    let data = msg_notification::Data::CanYouAnswer {
+        request_id: asked.request_id,
+        confidence: 1.0,
+    };
+    match &notify(&socket, SKILL_ID, 3, token, data)[..] {
+        [msg_notification_response::Data::CanYouAnswer { request_id, code }] => {
+            assert_eq!(*request_id, asked.request_id);
+            assert_eq!(*code, ResponseType::BadOption as u16);
+        }
+        other => panic!("Unexpected answer: {:?}", other),
+    }
+}
+
+#[test]
+fn late_requested_answers_are_rejected() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, mut out, _kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+    let token = connect_and_observe(&socket, SKILL_ID);
+
+    let activating = rt.spawn(async move {
+        let msg = MsgSkillRequest {
+            request_id: 0,
+            client: client(),
+            request: RequestData {
+                type_: RequestDataKind::Intent,
+                ..request()
+            },
+        };
+        out.activate_skill_with_timeout(SKILL_ID.into(), msg, Duration::from_millis(200))
+            .await
+            .map(|_| ())
+    });
+
+    let asked = received_request(&socket);
+    assert!(matches!(rt.block_on(activating).unwrap(), Err(Error::Timeout)));
+
+    let data = msg_notification::Data::Requested {
+        request_id: asked.request_id,
+        capabilities: vec![],
+    };
+    match &notify(&socket, SKILL_ID, 3, token, data)[..] {
+        [msg_notification_response::Data::Requested { request_id, code, .. }] => {
+            assert_eq!(*request_id, asked.request_id);
+            assert_eq!(*code, ResponseType::BadOption as u16);
+        }
+        other => panic!("Unexpected answer: {:?}", other),
+    }
+}
+
+#[test]
+fn skills_are_asked_at_once() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let skills: Vec<_> = (0..3)
+        .map(|n| {
+            let id = format!("com.example.skill{}", n);
+            let socket = skill_socket(address);
+            let token = connect_and_observe(&socket, &id);
+            (id, socket, token)
+        })
+        .collect();
+
+    let ids: Vec<_> = skills.iter().map(|(id, _, _)| id.clone()).collect();
+    let asking = rt.spawn(async move {
+        let timeout = Duration::from_secs(5);
+        out.skills_answerable_stream(&ids, request(), client(), timeout)
+            .map(|(id, answer)| (id, answer.unwrap()))
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    // Every skill is asked before any of them answers, each one with its
+    // own request id
+    let asked: Vec<_> = skills.iter().map(|(_, socket, _)| received_request(socket).request_id).collect();
+    let mut unique = asked.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    assert_eq!(unique.len(), skills.len());
+
+    // Answers are matched by request id, whatever order they come in
+    for (n, ((id, socket, token), request_id)) in skills.iter().zip(&asked).enumerate().rev() {
+        let data = msg_notification::Data::CanYouAnswer {
+            request_id: *request_id,
+            confidence: n as f32 / 10.0,
+        };
+        notify(socket, id, 3, token.clone(), data);
+    }
+
+    let answers = rt.block_on(asking).unwrap();
+    assert_eq!(answers.len(), skills.len());
+    for (id, answer) in answers {
+        let n: f32 = id.trim_start_matches("com.example.skill").parse().unwrap();
+        assert_eq!(answer.skill_id, id);
+        assert!(matches!(
+            answer.data[..],
+            [msg_notification::Data::CanYouAnswer { confidence, .. }] if confidence == n / 10.0
+        ));
+    }
+}
+
+#[test]
+fn dispatch_only_asks_skills_with_the_intent() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, mut out, _kinds) = start_register(&rt);
+    let hello = skill_socket(address);
+    let hello_token = connect_and_observe(&hello, "com.example.hello");
+    register_intent(&hello, "com.example.hello", 3, hello_token.clone(), "hello");
+    let bye = skill_socket(address);
+    let bye_token = connect_and_observe(&bye, "com.example.bye");
+    register_intent(&bye, "com.example.bye", 3, bye_token, "bye");
+
+    let dispatching = rt.spawn(async move {
+        let request = RequestData {
+            type_: RequestDataKind::Intent,
+            ..request()
+        };
+        let (skill_id, _, responder) = out.dispatch(request, client()).await.unwrap();
+        let response = RequestResponse {
+            code: 205,
+            capability_errors: vec![],
+        };
+        responder.send(response).unwrap();
+        skill_id
+    });
+
+    let asked = received_request(&hello);
+    assert_eq!(asked.request.type_, RequestDataKind::CanAnswer);
+    let data = msg_notification::Data::CanYouAnswer {
+        request_id: asked.request_id,
+        confidence: 0.9,
+    };
+    notify(&hello, "com.example.hello", 4, hello_token.clone(), data);
+
+    let sent = received_request(&hello);
+    assert_eq!(sent.request.type_, RequestDataKind::Intent);
+    let data = msg_notification::Data::Requested {
+        request_id: sent.request_id,
+        capabilities: vec![],
+    };
+    notify(&hello, "com.example.hello", 5, hello_token, data);
+    assert_eq!(rt.block_on(dispatching).unwrap(), "com.example.hello");
+
+    // The other skill doesn't know the intent, so it wasn't even asked
+    bye.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    assert!(bye.recv(&mut [0; 1500]).is_err());
+}
+
+#[test]
+fn concurrent_questions_get_their_own_answers() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let socket = skill_socket(address);
+    let token = connect_and_observe(&socket, SKILL_ID);
+
+    let asking = rt.spawn(async move {
+        let ids = [SKILL_ID.to_string()];
+        let timeout = Duration::from_secs(5);
+        let first = out.skills_answerable_stream(&ids, request(), client(), timeout).collect::<Vec<_>>();
+        let hello_again = RequestData {
+            intent: "hello_again".into(),
+            ..request()
+        };
+        let second = out.skills_answerable_stream(&ids, hello_again, client(), timeout).collect::<Vec<_>>();
+        futures::join!(first, second)
+    });
+
+    let asked = [received_request(&socket), received_request(&socket)];
+    assert_ne!(asked[0].request_id, asked[1].request_id);
+    for (message_id, request) in (3..).zip(&asked) {
+        let confidence = if request.request.intent == "hello" { 0.25 } else { 0.75 };
+        let data = msg_notification::Data::CanYouAnswer {
+            request_id: request.request_id,
+            confidence,
+        };
+        notify(&socket, SKILL_ID, message_id, token.clone(), data);
+    }
+
+    let confidence = |answers: &[(String, Result<MsgNotification, Error>)]| match answers {
+        [(_, Ok(answer))] => match answer.data[..] {
+            [msg_notification::Data::CanYouAnswer { confidence, .. }] => confidence,
+            _ => panic!("Unexpected answer: {:?}", answer),
+        },
+        other => panic!("Unexpected answers: {:?}", other),
+    };
+    let (first, second) = rt.block_on(asking).unwrap();
+    assert_eq!(confidence(&first), 0.25);
+    assert_eq!(confidence(&second), 0.75);
+}
+
+#[test]
+fn skills_without_heartbeats_expire() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let config = SkillRegisterConfig::new().skill_lease(Some(Duration::from_millis(300)));
+    let (address, out, kinds) = start_register_with(&rt, config);
+    let socket = skill_socket(address);
+    let token = connect_and_observe(&socket, SKILL_ID);
+
+    // Only the skill itself can keep it alive
+    let answer = exchange(&socket, &heartbeat(None, 9));
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Unauthorized));
+
+    // Heartbeats keep the skill alive past the lease
+    for message_id in 10..18 {
+        thread::sleep(Duration::from_millis(100));
+        let answer = exchange(&socket, &heartbeat(token.as_deref(), message_id));
+        assert_eq!(answer.header.code, MessageClass::Response(ResponseType::Content));
+    }
+    assert!(out.skill_info(SKILL_ID).is_some());
+
+    assert_eq!(kinds.recv_timeout(Duration::from_secs(5)), Ok("connect"));
+    assert_eq!(kinds.recv_timeout(Duration::from_secs(5)), Ok("close"));
+    assert!(out.skill_info(SKILL_ID).is_none());
+    let answer = exchange(&socket, &heartbeat(token.as_deref(), 20));
+    assert_eq!(answer.header.code, MessageClass::Response(ResponseType::BadRequest));
+}
+
+#[test]
+fn connecting_skills_learn_the_lease() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let config = SkillRegisterConfig::new().skill_lease(Some(Duration::from_secs(45)));
+    let (address, _out, _kinds) = start_register_with(&rt, config);
+    let connected = exchange(&skill_socket(address), &packet(Method::Post, "vap/skillRegistry/connect", 1, connect_payload(SKILL_ID)));
+    assert_eq!(connected.header.code, MessageClass::Response(ResponseType::Created));
+    let connected: MsgConnectResponse = rmp_serde::from_read(Cursor::new(&connected.payload)).unwrap();
+    assert_eq!(connected.lease, Some(45_000));
+}
+
+#[test]
+fn zero_leases_are_rejected() {
+    let built = SkillRegisterConfig::new()
+        .port(0)
+        .skill_lease(Some(Duration::ZERO))
+        .build();
+    assert!(matches!(built, Err(Error::ZeroLease)));
+}
+
+#[test]
+fn restarted_skills_replace_their_old_connection() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let token = connect_and_observe(&skill_socket(address), SKILL_ID);
+
+    let restarted = skill_socket(address);
+    let connect = encode(&MsgConnect {
+        auth_token: token,
+        ..rmp_serde::from_slice(&connect_payload(SKILL_ID)).unwrap()
+    });
+    let connected = exchange(&restarted, &packet(Method::Post, "vap/skillRegistry/connect", 2, connect));
+    assert_eq!(connected.header.code, MessageClass::Response(ResponseType::Created));
+    assert_eq!(out.skill_info(SKILL_ID).unwrap().address, restarted.local_addr().unwrap());
+}
+
+#[test]
+fn live_skills_cant_be_connected_again_without_their_token() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (address, out, _kinds) = start_register(&rt);
+    let skill = skill_socket(address);
+    let token = connect_and_observe(&skill, SKILL_ID);
+
+    // While the skill is connected anyone else is a duplicate
+    let other = skill_socket(address);
+    let path = "vap/skillRegistry/connect";
+    let duplicated = exchange(&other, &packet(Method::Post, path, 1, connect_payload(SKILL_ID)));
+    assert_eq!(duplicated.header.code, MessageClass::Response(ResponseType::BadRequest));
+    let error: VapError = rmp_serde::from_slice(&duplicated.payload).unwrap();
+    assert_eq!(error.type_, error_types::WRONG_SKILL_ID);
+    assert_eq!(error.object.as_deref(), Some(SKILL_ID));
+    let connect = encode(&MsgConnect {
+        auth_token: Some("wrong".into()),
+        ..rmp_serde::from_slice(&connect_payload(SKILL_ID)).unwrap()
+    });
+    let duplicated = exchange(&other, &packet(Method::Post, path, 2, connect));
+    assert_eq!(duplicated.header.code, MessageClass::Response(ResponseType::BadRequest));
+    assert_eq!(out.skill_info(SKILL_ID).unwrap().address, skill.local_addr().unwrap());
+
+    // Once it is gone its id needs its token
+    let stopped = exchange(&skill, &observe(SKILL_ID, token.as_deref(), ObserveOption::Deregister, 3));
+    assert_eq!(stopped.header.code, MessageClass::Response(ResponseType::Content));
+    let denied = exchange(&other, &packet(Method::Post, path, 3, connect_payload(SKILL_ID)));
+    assert_eq!(denied.header.code, MessageClass::Response(ResponseType::Unauthorized));
+    let error: VapError = rmp_serde::from_slice(&denied.payload).unwrap();
+    assert_eq!(error.type_, error_types::CONNECTION_DENIED);
+}
+
+/// The skill framework and raw skills talking to the register through OSCORE
+#[cfg(feature = "oscore")]
+mod oscore {
+    use std::fs;
+
+    use futures::executor::block_on;
+    use vap_common_skill::oscore::{SecurityContext, ECHO};
+    use vap_skill_framework::{Request, Skill};
+
+    use super::*;
+
+    const SECRET: &[u8] = b"a secret shared over loopback";
+    const OTHER_ID: &str = "com.example.other_skill";
+
+    /// Starts a register that answers like an application would, so that the
+    /// framework takes every answer
+    fn start_register_for_framework(
+        rt: &tokio::runtime::Runtime,
+        config: SkillRegisterConfig,
+    ) -> (SocketAddr, SkillRegisterOut, std_mpsc::Receiver<&'static str>) {
+        let (register, mut stream, out) = config.port(0).build().unwrap();
+        let address = register.local_addr().unwrap();
+        let (kinds, received) = std_mpsc::channel();
+        rt.spawn(async move { register.run().await.unwrap() });
+        rt.spawn(async move {
+            while let Ok((msg, responder)) = stream.recv().await {
+                let (kind, status, payload) = match msg {
+                    SkillRegisterMessage::Connect(_) => {
+                        let connected = MsgConnectResponse {
+                            langs: vec![Language {
+                                language: "en".into(),
+                                country: Some("US".into()),
+                                extra: None,
+                            }],
+                            vap_version: None,
+                            lease: None,
+                            auth_token: None,
+                        };
+                        ("connect", ResponseType::Created, encode(&connected))
+                    }
+                    SkillRegisterMessage::RegisterIntents(_) => ("intents", ResponseType::Created, vec![]),
+                    SkillRegisterMessage::Notification(_) => {
+                        let answer = MsgNotificationResponse { data: vec![] };
+                        ("notification", ResponseType::Content, encode(&answer))
+                    }
+                    SkillRegisterMessage::Close(_) => ("close", ResponseType::Deleted, vec![]),
+                    _ => ("other", ResponseType::Changed, vec![]),
+                };
+                let _ = responder.send(Response { status, payload });
+                let _ = kinds.send(kind);
+            }
+        });
+
+        (address, out, received)
+    }
+
+    /// Sends a request protected with `context`, echoing the challenge of
+    /// the register if it asks for one. Returns the bytes of the request
+    /// that was taken and its (decrypted) answer.
+    fn protected_exchange(
+        socket: &UdpSocket,
+        context: &mut SecurityContext,
+        path: &str,
+        mut message_id: u16,
+        payload: Vec<u8>,
+    ) -> (Vec<u8>, Packet) {
+        let mut echo = None;
+        loop {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Post);
+            request.set_path(path);
+            request.message.header.set_type(MessageType::Confirmable);
+            request.message.header.message_id = message_id;
+            request.message.set_token(message_id.to_be_bytes().to_vec());
+            request.message.payload = payload.clone();
+            if let Some(echo) = echo.take() {
+                request.message.add_option(CoapOption::Unknown(ECHO), echo);
+            }
+            let binding = context.protect(&mut request.message, None).unwrap();
+            let bytes = request.message.to_bytes().unwrap();
+
+            let mut answer = exchange(socket, &bytes);
+            context.unprotect(&mut answer, Some(&binding)).unwrap();
+            match answer.get_first_option(CoapOption::Unknown(ECHO)) {
+                Some(challenge) => echo = Some(challenge.clone()),
+                None => return (bytes, answer),
+            }
+            message_id += 1;
+        }
+    }
+
+    #[test]
+    fn framework_skills_talk_through_oscore() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let config = SkillRegisterConfig::new().psk(SKILL_ID, SECRET.to_vec());
+        let (address, mut out, kinds) = start_register_for_framework(&rt, config);
+        let folder = std::env::temp_dir().join(format!("vap-oscore-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("en-US.toml"), "[intents.main.hello]\nutterances = [\"hello\"]\n").unwrap();
+
+        // The first request of the skill is challenged, the framework echoes it
+        let (mut skill, mut skill_in) = Skill::builder("Test skill", SKILL_ID, &folder)
+            .address(address.to_string())
+            .token_file(folder.join("token"))
+            .psk(SECRET)
+            .build()
+            .unwrap();
+        assert_eq!(kinds.recv().unwrap(), "connect");
+        assert_eq!(kinds.recv().unwrap(), "intents");
+        skill.notify("test_client".into(), vec![]).unwrap();
+        assert_eq!(kinds.recv().unwrap(), "notification");
+
+        // Requests reach the skill protected as well
+        let dispatching = rt.spawn(async move {
+            let request = RequestData {
+                type_: RequestDataKind::Intent,
+                ..request()
+            };
+            let (skill_id, _, responder) = out.dispatch(request, client()).await.unwrap();
+            let response = RequestResponse {
+                code: 205,
+                capability_errors: vec![],
+            };
+            responder.send(response).unwrap();
+            skill_id
+        });
+        let asked = block_on(skill_in.next()).unwrap();
+        assert!(matches!(&asked.request, Request::CanAnswer(intent, _) if intent == "hello"));
+        let data = msg_notification::Data::CanYouAnswer {
+            request_id: asked.request_id,
+            confidence: 0.9,
+        };
+        skill.notify_multiple(vec![data]).unwrap();
+        let sent = block_on(skill_in.next()).unwrap();
+        assert!(matches!(&sent.request, Request::Intent(intent, _) if intent == "hello"));
+        skill.answer(&sent, vec![]).unwrap();
+        assert_eq!(rt.block_on(dispatching).unwrap(), SKILL_ID);
+
+        drop(skill);
+        assert_eq!(kinds.recv().unwrap(), "close");
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn replayed_requests_are_refused() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let config = SkillRegisterConfig::new().psk(SKILL_ID, SECRET.to_vec());
+        let (address, _out, kinds) = start_register_with(&rt, config);
+        let socket = skill_socket(address);
+        let mut context = SecurityContext::for_skill(SKILL_ID, SECRET);
+
+        let path = "vap/skillRegistry/connect";
+        let (connect, connected) = protected_exchange(&socket, &mut context, path, 1, connect_payload(SKILL_ID));
+        assert_eq!(connected.header.code, MessageClass::Response(ResponseType::Created));
+        assert_eq!(kinds.recv().unwrap(), "connect");
+
+        // Somebody that recorded it sends it again as a new message
+        let mut replayed = Packet::from_bytes(&connect).unwrap();
+        replayed.header.message_id += 100;
+        let refused = exchange(&socket, &replayed.to_bytes().unwrap());
+        assert_eq!(refused.header.code, MessageClass::Response(ResponseType::Unauthorized));
+        assert!(refused.get_option(CoapOption::Oscore).is_none());
+        assert!(kinds.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn keys_only_work_for_their_skill() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let config = SkillRegisterConfig::new()
+            .psk(SKILL_ID, SECRET.to_vec())
+            .psk(OTHER_ID, b"the secret of another skill".to_vec());
+        let (address, _out, kinds) = start_register_with(&rt, config);
+        let socket = skill_socket(address);
+        let mut other = SecurityContext::for_skill(OTHER_ID, b"the secret of another skill");
+
+        let path = "vap/skillRegistry/connect";
+        let (_, denied) = protected_exchange(&socket, &mut other, path, 1, connect_payload(SKILL_ID));
+        assert_eq!(denied.header.code, MessageClass::Response(ResponseType::Unauthorized));
+        let error: VapError = rmp_serde::from_slice(&denied.payload).unwrap();
+        assert_eq!(error.type_, error_types::CONNECTION_DENIED);
+        assert!(kinds.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // Plain requests are refused too
+        let plain = exchange(&socket, &packet(Method::Post, path, 10, connect_payload(SKILL_ID)));
+        assert_eq!(plain.header.code, MessageClass::Response(ResponseType::Unauthorized));
+    }
+}